@@ -0,0 +1,114 @@
+use serde::Deserialize;
+
+use crate::utils::anyhow;
+
+use super::duration_in_millis::DurationInMillis;
+
+pub(crate) const LETSENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub(crate) enum AcmeChallenge {
+    // answered on `/.well-known/acme-challenge/` by the plain http listeners, needs port 80
+    #[default]
+    #[serde(alias = "http-01", alias = "http01", alias = "HTTP-01")]
+    Http01,
+
+    // answered in the tls handshake of this listener, needs port 443
+    #[serde(alias = "tls-alpn-01", alias = "tlsalpn01", alias = "TLS-ALPN-01")]
+    TlsAlpn01,
+}
+
+// certificates issued and renewed by an acme ca, selected by sni like `certs`
+#[derive(Deserialize, Clone, Default, Debug)]
+pub(crate) struct AcmeConfig {
+    // directory url of the ca, defaults to let's encrypt
+    #[serde(default, alias = "Directory", alias = "url", alias = "Url")]
+    pub directory: String,
+
+    // extra pem roots to trust for the directory, such as the pebble ca
+    #[serde(default, alias = "CA", alias = "Ca")]
+    pub ca: String,
+
+    #[serde(default, alias = "Contacts", alias = "emails", alias = "Emails")]
+    pub contacts: Vec<String>,
+
+    // the terms of service of the ca must be agreed to explicitly to create an account
+    #[serde(default, alias = "AgreeTos", alias = "agree_terms_of_service")]
+    pub agree_tos: bool,
+
+    // names of one certificate
+    #[serde(default, alias = "Domains")]
+    pub domains: Vec<String>,
+
+    #[serde(default, alias = "Challenge")]
+    pub challenge: AcmeChallenge,
+
+    // where the account key and the certificates are kept, relative to the workdir
+    #[serde(default, alias = "Dir", alias = "storage", alias = "Storage")]
+    pub dir: String,
+
+    // renew when the certificate expires within this duration
+    #[serde(default, alias = "RenewBefore")]
+    pub renew_before: DurationInMillis,
+
+    #[serde(default, alias = "CheckInterval")]
+    pub check_interval: DurationInMillis,
+
+    // paths of the issued certificate chain and its key
+    #[serde(skip)]
+    pub(crate) cert: String,
+
+    #[serde(skip)]
+    pub(crate) key: String,
+}
+
+impl AcmeConfig {
+    pub(crate) fn autofix(&mut self) -> anyhow::Result<()> {
+        if self.domains.is_empty() {
+            return anyhow::error("acme config has no domains");
+        }
+        if !self.agree_tos {
+            return anyhow::error(
+                "acme config must set `agree_tos = true` to agree to the terms of service of the ca",
+            );
+        }
+        if self.directory.is_empty() {
+            self.directory = LETSENCRYPT_DIRECTORY.to_string();
+        }
+        if self.dir.is_empty() {
+            self.dir = "acme".to_string();
+        }
+        if self.renew_before.is_zero() {
+            self.renew_before = DurationInMillis::new(30 * 24 * 60 * 60 * 1000);
+        }
+        if self.check_interval.is_zero() {
+            self.check_interval = DurationInMillis::new(12 * 60 * 60 * 1000);
+        }
+
+        let basename = self.domains[0].replace('*', "_");
+        self.cert = format!("{}/{}.crt", self.dir, basename);
+        self.key = format!("{}/{}.key", self.dir, basename);
+        Ok(())
+    }
+
+    pub(crate) fn account_key(&self) -> String {
+        format!("{}/account.key", self.dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agree_tos() {
+        let mut cfg = AcmeConfig {
+            domains: vec!["example.com".to_string()],
+            ..Default::default()
+        };
+        assert!(cfg.autofix().is_err());
+        cfg.agree_tos = true;
+        cfg.autofix().unwrap();
+        assert_eq!(cfg.cert, "acme/example.com.crt");
+    }
+}
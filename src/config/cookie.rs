@@ -0,0 +1,42 @@
+use serde::Deserialize;
+
+use crate::{cookie::CookieKeys, utils::anyhow};
+
+#[derive(Deserialize, Clone, Default, Debug)]
+pub struct CookieConfig {
+    // at least 32 random bytes, the keys of signed and encrypted cookies are derived from it.
+    // changing it invalidates every such cookie
+    #[serde(default, alias = "Secret")]
+    pub secret: String,
+
+    #[serde(skip)]
+    pub(crate) keys: Option<CookieKeys>,
+}
+
+impl CookieConfig {
+    pub fn autofix(&mut self, root: Option<&Self>) -> anyhow::Result<()> {
+        match root {
+            Some(root) => {
+                if self.secret.is_empty() {
+                    self.secret = root.secret.clone();
+                }
+            }
+            None => {}
+        }
+        self.keys = None;
+        if self.secret.is_empty() {
+            return Ok(());
+        }
+        if self.secret.len() < 32 {
+            return anyhow::error("cookie secret is shorter than 32 bytes");
+        }
+        self.keys = Some(CookieKeys::derive(self.secret.as_bytes()));
+        Ok(())
+    }
+
+    /// `None` if no secret is set.
+    #[inline]
+    pub(crate) fn keys(&self) -> Option<&CookieKeys> {
+        self.keys.as_ref()
+    }
+}
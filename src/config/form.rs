@@ -0,0 +1,97 @@
+use serde::Deserialize;
+
+use crate::utils::anyhow;
+
+use super::bytes_size::BytesSize;
+
+// limits of form bodies, see `form::Multipart`
+#[derive(Deserialize, Clone, Default, Debug)]
+pub struct FormConfig {
+    // the fields of an `application/x-www-form-urlencoded` body
+    #[serde(default, alias = "MaxFields")]
+    pub max_fields: usize,
+
+    #[serde(default, alias = "MaxParts")]
+    pub max_parts: usize,
+
+    // the headers of one part together
+    #[serde(default, alias = "MaxPartHeaderSize")]
+    pub max_part_header_size: BytesSize,
+
+    // a part without a filename, always kept in memory
+    #[serde(default, alias = "MaxFieldSize")]
+    pub max_field_size: BytesSize,
+
+    // a file part, zero for no limit but `max_body_size`
+    #[serde(default, alias = "MaxFileSize")]
+    pub max_file_size: BytesSize,
+
+    // file parts larger than this are written to a temporary file
+    #[serde(default, alias = "SpoolSize")]
+    pub spool_size: BytesSize,
+
+    // where the temporary files go, the system temporary directory if empty
+    #[serde(default, alias = "TempDir")]
+    pub temp_dir: String,
+}
+
+impl FormConfig {
+    pub fn autofix(&mut self, root: Option<&Self>) -> anyhow::Result<()> {
+        match root {
+            Some(root) => {
+                if self.max_fields < 1 {
+                    self.max_fields = root.max_fields;
+                }
+                if self.max_parts < 1 {
+                    self.max_parts = root.max_parts;
+                }
+                if self.max_part_header_size.0 < 1 {
+                    self.max_part_header_size = root.max_part_header_size;
+                }
+                if self.max_field_size.0 < 1 {
+                    self.max_field_size = root.max_field_size;
+                }
+                if self.max_file_size.0 < 1 {
+                    self.max_file_size = root.max_file_size;
+                }
+                if self.spool_size.0 < 1 {
+                    self.spool_size = root.spool_size;
+                }
+                if self.temp_dir.is_empty() {
+                    self.temp_dir = root.temp_dir.clone();
+                }
+            }
+            None => {}
+        }
+
+        if self.max_fields < 1 {
+            self.max_fields = 1000;
+        }
+        if self.max_parts < 1 {
+            self.max_parts = 128;
+        }
+        if self.max_part_header_size.0 < 1 {
+            self.max_part_header_size = BytesSize(8 * 1024); // 8KB
+        }
+        if self.max_field_size.0 < 1 {
+            self.max_field_size = BytesSize(1024 * 1024); // 1MB
+        }
+        if self.spool_size.0 < 1 {
+            self.spool_size = BytesSize(256 * 1024); // 256KB
+        }
+        if !self.temp_dir.is_empty() && !std::path::Path::new(&self.temp_dir).is_dir() {
+            return anyhow::error(&format!(
+                "form temp dir `{}` is not a directory",
+                self.temp_dir
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn temp_dir(&self) -> std::path::PathBuf {
+        if self.temp_dir.is_empty() {
+            return std::env::temp_dir();
+        }
+        std::path::PathBuf::from(&self.temp_dir)
+    }
+}
@@ -1,7 +1,12 @@
+use std::collections::HashMap;
+
 use crate::utils::anyhow;
 use serde::Deserialize;
 
-use super::{bytes_size::BytesSize, duration_in_millis::DurationInMillis};
+use super::{
+    bytes_size::BytesSize, cookie::CookieConfig, duration_in_millis::DurationInMillis,
+    form::FormConfig,
+};
 
 #[derive(Deserialize, Clone, Default, Debug)]
 pub struct WebsocketConfig {
@@ -18,14 +23,81 @@ pub struct WebsocketConfig {
     pub compression: Option<i32>,
 }
 
+// the body of an error response, inline text or a file read when the config is loaded
+#[derive(Deserialize, Clone, Default, Debug)]
+pub struct ErrorPage {
+    #[serde(default, alias = "Text")]
+    pub text: String,
+
+    #[serde(default, alias = "File")]
+    pub file: String,
+
+    // `text/plain; charset=utf-8` if empty
+    #[serde(default, alias = "ContentType")]
+    pub content_type: String,
+
+    #[serde(skip)]
+    pub(crate) body: Vec<u8>,
+}
+
+impl ErrorPage {
+    fn autofix(&mut self, code: &str) -> anyhow::Result<()> {
+        match code.parse::<u16>() {
+            Ok(400..=599) => {}
+            _ => return anyhow::error(&format!("bad error page status `{}`", code)),
+        }
+        if !self.text.is_empty() && !self.file.is_empty() {
+            return anyhow::error(&format!("error page `{}` has both text and file", code));
+        }
+        self.body = match self.file.as_str() {
+            "" => self.text.as_bytes().to_vec(),
+            file => match std::fs::read(file) {
+                Ok(v) => v,
+                Err(e) => {
+                    return anyhow::error(&format!(
+                        "read error page `{}` from `{}` failed, {}",
+                        code, file, e
+                    ))
+                }
+            },
+        };
+        if self.content_type.is_empty() {
+            self.content_type = "text/plain; charset=utf-8".to_string();
+        }
+        Ok(())
+    }
+}
+
 #[derive(Deserialize, Clone, Default, Debug)]
 pub struct HttpConfig {
+    // reuse connections for further requests, on by default
     #[serde(default, alias = "KeepAlive")]
     pub keep_alive: Option<bool>,
 
+    // requests served over one connection, zero for no limit
+    #[serde(default, alias = "MaxRequests", alias = "max_requests_per_conn")]
+    pub max_requests: usize,
+
+    // waiting for the next request of a keep-alive connection
     #[serde(default, alias = "IdleTimeout")]
     pub idle_timeout: DurationInMillis,
 
+    // from the first byte of a request to the end of its headers
+    #[serde(default, alias = "HeaderTimeout")]
+    pub header_timeout: DurationInMillis,
+
+    // reading a request body, extended by the time it takes at `min_body_rate`
+    #[serde(default, alias = "BodyTimeout")]
+    pub body_timeout: DurationInMillis,
+
+    // bytes per second, zero for no limit
+    #[serde(default, alias = "MinBodyRate")]
+    pub min_body_rate: BytesSize,
+
+    // writing a response
+    #[serde(default, alias = "WriteTimeout")]
+    pub write_timeout: DurationInMillis,
+
     #[serde(default, alias = "MaxUrlSize")]
     pub max_url_size: BytesSize,
 
@@ -43,6 +115,26 @@ pub struct HttpConfig {
 
     #[serde(default, alias = "Websocket", alias = "ws")]
     pub websocket: Option<WebsocketConfig>,
+
+    // accept bare LF line ends, whitespace before a header colon and folded header lines,
+    // and let `transfer-encoding` override `content-length`. only for clients that can not
+    // be fixed, a proxy in front parsing the other way can be used to smuggle requests
+    #[serde(default, alias = "Lenient")]
+    pub lenient: Option<bool>,
+
+    // bodies of the responses to bad requests, timeouts and limits, by status such as `400`
+    #[serde(default, alias = "ErrorPages")]
+    pub error_pages: HashMap<String, ErrorPage>,
+
+    #[serde(default, alias = "Cookies")]
+    pub cookies: CookieConfig,
+
+    #[serde(default, alias = "Forms")]
+    pub forms: FormConfig,
+
+    // cleartext http2, by prior knowledge or `Upgrade: h2c`
+    #[serde(default, alias = "H2c", alias = "H2C")]
+    pub h2c: Option<bool>,
 }
 
 impl HttpConfig {
@@ -52,9 +144,24 @@ impl HttpConfig {
                 if self.keep_alive.is_none() {
                     self.keep_alive = root.keep_alive;
                 }
+                if self.max_requests < 1 {
+                    self.max_requests = root.max_requests;
+                }
                 if self.idle_timeout.is_zero() {
                     self.idle_timeout = root.idle_timeout;
                 }
+                if self.header_timeout.is_zero() {
+                    self.header_timeout = root.header_timeout;
+                }
+                if self.body_timeout.is_zero() {
+                    self.body_timeout = root.body_timeout;
+                }
+                if self.min_body_rate.0 < 1 {
+                    self.min_body_rate = root.min_body_rate;
+                }
+                if self.write_timeout.is_zero() {
+                    self.write_timeout = root.write_timeout;
+                }
                 if self.max_url_size.0 < 1 {
                     self.max_url_size = root.max_url_size;
                 }
@@ -64,15 +171,38 @@ impl HttpConfig {
                 if self.compression.is_none() {
                     self.compression = root.compression;
                 }
+                if self.h2c.is_none() {
+                    self.h2c = root.h2c;
+                }
+                if self.lenient.is_none() {
+                    self.lenient = root.lenient;
+                }
+                for (code, page) in root.error_pages.iter() {
+                    if !self.error_pages.contains_key(code) {
+                        self.error_pages.insert(code.clone(), page.clone());
+                    }
+                }
             }
             None => {}
         }
 
         // self.compression = std::cmp::min(11, self.compression);
 
-        if !self.idle_timeout.is_zero() && self.idle_timeout.as_millis() < 10000 {
+        if self.idle_timeout.is_zero() {
+            self.idle_timeout = DurationInMillis::new(60 * 1000);
+        }
+        if self.idle_timeout.as_millis() < 10000 {
             self.idle_timeout = DurationInMillis(std::time::Duration::from_millis(10000));
         }
+        if self.header_timeout.is_zero() {
+            self.header_timeout = DurationInMillis::new(30 * 1000);
+        }
+        if self.body_timeout.is_zero() {
+            self.body_timeout = DurationInMillis::new(60 * 1000);
+        }
+        if self.write_timeout.is_zero() {
+            self.write_timeout = DurationInMillis::new(60 * 1000);
+        }
 
         if self.max_url_size.u64() < 1 {
             self.max_url_size = BytesSize(8 * 1024); // 8KB
@@ -86,7 +216,62 @@ impl HttpConfig {
         if self.max_body_size.u64() < 1 {
             self.max_body_size = BytesSize(1024 * 1024 * 10); // 10MB
         }
+        for (code, page) in self.error_pages.iter_mut() {
+            page.autofix(code)?;
+        }
+        self.cookies.autofix(root.map(|v| &v.cookies))?;
+        self.forms.autofix(root.map(|v| &v.forms))?;
 
         Ok(())
     }
+
+    #[inline]
+    pub(crate) fn keep_alive(&self) -> bool {
+        self.keep_alive.unwrap_or(true)
+    }
+
+    #[inline]
+    pub(crate) fn lenient(&self) -> bool {
+        self.lenient.unwrap_or(false)
+    }
+
+    pub(crate) fn error_page(&self, code: u16) -> Option<&ErrorPage> {
+        self.error_pages.get(code.to_string().as_str())
+    }
+
+    /// the time allowed to read a body of `size` bytes.
+    pub fn body_timeout_of(&self, size: usize) -> std::time::Duration {
+        match self.min_body_rate.0 {
+            0 => self.body_timeout.0,
+            rate => {
+                let ms = (size as u128) * 1000 / (rate as u128);
+                self.body_timeout.0 + std::time::Duration::from_millis(ms as u64)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body_timeout_of() {
+        let mut cfg = HttpConfig::default();
+        cfg.autofix(None).unwrap();
+        assert_eq!(
+            cfg.body_timeout_of(1 << 20),
+            std::time::Duration::from_secs(60)
+        );
+
+        cfg.min_body_rate = BytesSize(1024);
+        assert_eq!(
+            cfg.body_timeout_of(10 * 1024),
+            std::time::Duration::from_secs(70)
+        );
+        assert_eq!(
+            cfg.body_timeout_of(512),
+            std::time::Duration::from_millis(60500)
+        );
+    }
 }
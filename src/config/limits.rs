@@ -0,0 +1,72 @@
+use serde::Deserialize;
+
+use crate::utils::anyhow;
+
+use super::duration_in_millis::DurationInMillis;
+
+// what a listener does while a connection limit is reached
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LimitPolicy {
+    // stop accepting until a connection is closed, new ones wait in the backlog
+    #[serde(alias = "pause")]
+    Pause,
+    // accept and answer `503` with `retry-after`
+    #[serde(alias = "reject")]
+    Reject,
+}
+
+#[derive(Deserialize, Clone, Default, Debug)]
+pub struct LimitsConfig {
+    // concurrent connections of the service, zero for no limit
+    #[serde(default, alias = "MaxConns", alias = "max_connections")]
+    pub max_conns: usize,
+
+    // concurrent connections of a client ip, always rejected, zero for no limit
+    #[serde(default, alias = "MaxConnsPerIp", alias = "max_connections_per_ip")]
+    pub max_conns_per_ip: usize,
+
+    #[serde(default, alias = "Policy")]
+    pub policy: Option<LimitPolicy>,
+
+    #[serde(default, alias = "RetryAfter")]
+    pub retry_after: DurationInMillis,
+}
+
+impl LimitsConfig {
+    pub fn autofix(&mut self, root: Option<&Self>) -> anyhow::Result<()> {
+        match root {
+            Some(root) => {
+                if self.max_conns < 1 {
+                    self.max_conns = root.max_conns;
+                }
+                if self.max_conns_per_ip < 1 {
+                    self.max_conns_per_ip = root.max_conns_per_ip;
+                }
+                if self.policy.is_none() {
+                    self.policy = root.policy;
+                }
+                if self.retry_after.is_zero() {
+                    self.retry_after = root.retry_after;
+                }
+            }
+            None => {}
+        }
+        if self.policy.is_none() {
+            self.policy = Some(LimitPolicy::Pause);
+        }
+        if self.retry_after.is_zero() {
+            self.retry_after = DurationInMillis::new(1000);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn pauses(&self) -> bool {
+        self.policy == Some(LimitPolicy::Pause)
+    }
+
+    /// the value of the `retry-after` header, in whole seconds.
+    pub(crate) fn retry_after_secs(&self) -> u64 {
+        std::cmp::max(1, self.retry_after.as_millis().div_ceil(1000) as u64)
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
     str::FromStr,
     vec,
 };
@@ -20,16 +21,23 @@ use self::{
     tcp::TcpConfig,
 };
 
+pub mod acme;
 pub mod bytes_size;
+pub mod cookie;
 pub mod duration_in_millis;
+pub mod form;
 pub mod http;
+pub mod limits;
 pub mod logging;
 mod matchs;
+pub mod proxy_protocol;
+pub mod quic;
 pub mod runtime;
 pub mod service;
 pub mod split_uint;
 pub mod tcp;
 pub mod tls;
+pub mod unix;
 
 #[derive(Deserialize, Clone, Default, Debug)]
 pub struct Config {
@@ -65,11 +73,26 @@ pub struct Config {
 
     #[serde(skip)]
     service_logging_appender_map: Slab<Vec<usize>>,
+
+    // the absolute path of the loaded file, reloads read it again
+    #[serde(skip)]
+    pub(crate) src: String,
+
+    // the working directory before `workdir` was applied, a relative `workdir` is based on it
+    #[serde(skip)]
+    pub(crate) basedir: String,
 }
 
 impl Config {
-    fn include(&mut self, pattern: &str) -> anyhow::Result<()> {
-        for entry in anyhow::result(glob::glob(pattern))? {
+    // a relative pattern is based on `workdir`
+    fn include(&mut self, workdir: &Path, pattern: &str) -> anyhow::Result<()> {
+        let pattern = if Path::new(pattern).is_absolute() {
+            pattern.to_string()
+        } else {
+            let base = glob::Pattern::escape(&workdir.to_string_lossy());
+            format!("{}/{}", base.trim_end_matches('/'), pattern)
+        };
+        for entry in anyhow::result(glob::glob(&pattern))? {
             match entry {
                 Ok(entry) => {
                     if !entry.is_file() || entry.as_path().file_name().is_none() {
@@ -118,18 +141,30 @@ impl Config {
         Ok(())
     }
 
+    /// the config file `fp`, a relative one is based on the working directory. it stays the
+    /// same, see `workdir`.
     pub fn load(fp: &str) -> anyhow::Result<Self> {
-        let txt = anyhow::result(std::fs::read_to_string(fp))?;
+        let basedir = anyhow::result(std::env::current_dir())?;
+        Self::read(fp, &basedir)
+    }
+
+    /// `load` with the paths based on `basedir` instead of the working directory, as for a
+    /// reload after the process changed to `workdir`.
+    pub(crate) fn read(fp: &str, basedir: &Path) -> anyhow::Result<Self> {
+        let path = basedir.join(fp);
+        let txt = anyhow::result(std::fs::read_to_string(&path))?;
         let mut config = anyhow::result(toml::from_str::<Self>(txt.as_str()))?;
-        if !config.workdir.is_empty() {
-            anyhow::result(std::env::set_current_dir(&config.workdir))?;
-        }
+        config.src = anyhow::result(std::fs::canonicalize(&path))?
+            .to_string_lossy()
+            .to_string();
+        config.basedir = basedir.to_string_lossy().to_string();
 
+        let workdir = config.workdir();
         if !config.include.is_empty() {
-            config.include(config.include.clone().as_str())?;
+            config.include(&workdir, config.include.clone().as_str())?;
         }
         for pattern in config.includes.clone() {
-            config.include(pattern.as_str())?;
+            config.include(&workdir, pattern.as_str())?;
         }
 
         if config.services.len() < 1 {
@@ -146,6 +181,12 @@ impl Config {
         Ok(config)
     }
 
+    /// `workdir` based on `basedir`, the relative paths of the config are based on it. the
+    /// process changes to it once at startup.
+    pub(crate) fn workdir(&self) -> PathBuf {
+        Path::new(&self.basedir).join(&self.workdir)
+    }
+
     pub fn autofix(&mut self) -> anyhow::Result<()> {
         self.runtime.autofix()?;
 
@@ -174,6 +215,24 @@ impl Config {
         Ok(())
     }
 
+    /// the global logger is set up once, a reloaded config keeps the logging slots of `prev`.
+    /// a service new to this config logs through the root appenders, returns their names.
+    pub(crate) fn inherit_logging(&mut self, prev: &Config) -> anyhow::Result<Vec<String>> {
+        let mut fallbacks = vec![];
+        for (name, service) in self.services.iter_mut() {
+            service.idx = match prev.services.get(name) {
+                Some(v) => v.idx,
+                None => {
+                    fallbacks.push(name.clone());
+                    0
+                }
+            };
+            service.logging.autofix(name, service.idx)?;
+        }
+        self.service_logging_appender_map = prev.service_logging_appender_map.clone();
+        Ok(fallbacks)
+    }
+
     pub fn logging(&mut self) -> anyhow::Result<ShutdownGuard> {
         let mut appenders = vec![];
         let mut renderer_names = HashSet::<String>::new();
@@ -265,4 +324,32 @@ mod tests {
             println!("{} {}", path, filename);
         }
     }
+
+    #[test]
+    fn test_read() {
+        let base = std::env::temp_dir().join(format!("httpd.config.{}", std::process::id()));
+        std::fs::create_dir_all(base.join("[x]/conf.d")).unwrap();
+        std::fs::create_dir_all(base.join("etc")).unwrap();
+        std::fs::write(
+            base.join("etc/httpd.toml"),
+            "workdir = \"[x]\"\ninclude = \"conf.d/*.toml\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            base.join("[x]/conf.d/api.toml"),
+            "[tcp]\naddr = \"127.0.0.1:18081\"\n",
+        )
+        .unwrap();
+
+        let cwd = std::env::current_dir().unwrap();
+        let config = super::Config::read("etc/httpd.toml", &base).unwrap();
+        assert_eq!(std::env::current_dir().unwrap(), cwd);
+        assert!(config.services.contains_key("api"));
+        assert_eq!(config.workdir(), base.join("[x]"));
+        assert_eq!(
+            std::path::PathBuf::from(&config.src),
+            std::fs::canonicalize(base.join("etc/httpd.toml")).unwrap()
+        );
+        std::fs::remove_dir_all(&base).unwrap();
+    }
 }
@@ -0,0 +1,42 @@
+use serde::Deserialize;
+
+use crate::utils::{anyhow, cidr::Cidr};
+
+use super::duration_in_millis::DurationInMillis;
+
+// connections start with a PROXY protocol v1 or v2 header, sent by a load balancer in front
+#[derive(Deserialize, Clone, Default, Debug)]
+pub struct ProxyProtocolConfig {
+    // addresses of the load balancers, such as `10.0.0.0/8`, others are closed. every peer is
+    // trusted if empty, unix socket peers always are
+    #[serde(
+        default,
+        alias = "Trusted",
+        alias = "trusted_cidrs",
+        alias = "TrustedCidrs"
+    )]
+    pub trusted: Vec<String>,
+
+    #[serde(default, alias = "Timeout")]
+    pub timeout: DurationInMillis,
+
+    #[serde(skip)]
+    pub(crate) cidrs: Vec<Cidr>,
+}
+
+impl ProxyProtocolConfig {
+    pub fn autofix(&mut self) -> anyhow::Result<()> {
+        if self.timeout.is_zero() {
+            self.timeout = DurationInMillis::new(5 * 1000);
+        }
+        self.cidrs = vec![];
+        for v in self.trusted.iter() {
+            self.cidrs.push(Cidr::parse(v)?);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn trusts(&self, ip: &std::net::IpAddr) -> bool {
+        self.cidrs.is_empty() || self.cidrs.iter().any(|v| v.contains(ip))
+    }
+}
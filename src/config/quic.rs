@@ -0,0 +1,50 @@
+use serde::Deserialize;
+
+use crate::utils::anyhow;
+
+use super::duration_in_millis::DurationInMillis;
+
+// served over udp with the certificates of `tcp.tls`, requires the `http3` feature
+#[derive(Deserialize, Clone, Default, Debug)]
+pub struct QuicConfig {
+    #[serde(default, alias = "address", alias = "Address", alias = "Addr")]
+    pub addr: String,
+
+    #[serde(default, alias = "IdleTimeout")]
+    pub idle_timeout: DurationInMillis,
+
+    #[serde(default, alias = "AltSvcMaxAge")]
+    pub alt_svc_max_age: DurationInMillis,
+
+    // value of the `alt-svc` header sent on tcp responses
+    #[serde(skip)]
+    pub(crate) alt_svc: String,
+}
+
+impl QuicConfig {
+    pub fn autofix(&mut self, tcp_addr: &str) -> anyhow::Result<()> {
+        if self.addr.is_empty() {
+            if tcp_addr.starts_with(super::tcp::UNIX_PREFIX) {
+                return anyhow::error(
+                    "quic needs an address of its own, tcp listens on a unix socket",
+                );
+            }
+            self.addr = tcp_addr.to_string();
+        }
+        if self.idle_timeout.is_zero() {
+            self.idle_timeout = DurationInMillis::new(30 * 1000);
+        }
+        if self.alt_svc_max_age.is_zero() {
+            self.alt_svc_max_age = DurationInMillis::new(24 * 60 * 60 * 1000);
+        }
+
+        let port = match self.addr.rsplit_once(':') {
+            Some((_, port)) => anyhow::result(port.parse::<u16>())?,
+            None => {
+                return anyhow::error(&format!("quic address without port, `{}`", self.addr));
+            }
+        };
+        self.alt_svc = format!("h3=\":{}\"; ma={}", port, self.alt_svc_max_age.as_secs());
+        Ok(())
+    }
+}
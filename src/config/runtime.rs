@@ -2,17 +2,59 @@ use serde::Deserialize;
 
 use crate::utils::anyhow;
 
+use super::duration_in_millis::DurationInMillis;
+
 #[derive(Deserialize, Clone, Default, Debug)]
 pub struct RuntimeConfig {
     #[serde(default, alias = "WorkerThreads")]
     pub worker_threads: u32,
 
+    // a current_thread runtime per cpu, each with its own listener of every service
     #[serde(default, alias = "PerCore")]
     pub per_core: Option<bool>,
+
+    // the runtimes of per_core mode, one per available cpu if 0
+    #[serde(default, alias = "Cores")]
+    pub cores: u32,
+
+    // pin the runtimes of per_core mode to a cpu each, linux only
+    #[serde(default, alias = "PinCpus", alias = "pin_cores", alias = "PinCores")]
+    pub pin_cpus: bool,
+
+    // concurrent connections of every service together, zero for no limit. the policy of
+    // the service accepting decides what happens at the limit
+    #[serde(default, alias = "MaxConns", alias = "max_connections")]
+    pub max_conns: usize,
+
+    // how long in-flight requests may take to finish after ctrl-c/SIGTERM
+    #[serde(
+        default,
+        alias = "ShutdownTimeout",
+        alias = "grace_period",
+        alias = "GracePeriod"
+    )]
+    pub shutdown_timeout: DurationInMillis,
 }
 
 impl RuntimeConfig {
     pub fn autofix(&mut self) -> anyhow::Result<()> {
+        if self.shutdown_timeout.is_zero() {
+            self.shutdown_timeout = DurationInMillis::new(30 * 1000);
+        }
+        if self.cores == 0 {
+            self.cores = match std::thread::available_parallelism() {
+                Ok(v) => v.get() as u32,
+                Err(_) => 1,
+            };
+        }
         Ok(())
     }
+
+    /// how many runtimes serve the services, each binds the listen addresses once.
+    pub fn runtimes(&self) -> usize {
+        match self.per_core {
+            Some(true) => self.cores.max(1) as usize,
+            _ => 1,
+        }
+    }
 }
@@ -4,7 +4,9 @@ use serde::Deserialize;
 
 use crate::utils::anyhow;
 
-use super::{http::HttpConfig, logging::LoggingConfig, matchs::Match, tcp::TcpConfig};
+use super::{
+    http::HttpConfig, logging::LoggingConfig, matchs::Match, quic::QuicConfig, tcp::TcpConfig,
+};
 
 #[derive(Deserialize, Clone, Debug, Default)]
 pub struct Rewrite {}
@@ -117,6 +119,10 @@ pub enum Service {
         #[serde(default, alias = "Rules")]
         rules: Vec<Rule>,
     },
+    // `POST /reload` reloads the config file, `GET /metrics` shows the connection counters.
+    // keep it on a loopback or otherwise trusted address
+    #[serde(alias = "admin")]
+    Admin {},
 }
 
 impl Service {
@@ -147,6 +153,7 @@ impl Service {
             Service::FileSystem { root, .. } => format!("Fs{{{}}}", root),
             Service::Forward { .. } => format!("Forward"),
             Service::Upstream { .. } => format!("Upstream"),
+            Service::Admin { .. } => format!("Admin"),
         }
     }
 }
@@ -180,6 +187,9 @@ pub struct ServiceConfig {
     #[serde(default, alias = "Http")]
     pub http: HttpConfig,
 
+    #[serde(default, alias = "Quic", alias = "http3", alias = "Http3")]
+    pub quic: Option<QuicConfig>,
+
     #[serde(skip)]
     pub src: String,
 }
@@ -194,6 +204,10 @@ impl ServiceConfig {
         self.logging.autofix(&self.name, self.idx)?;
         self.tcp.autofix(Some(rtcp))?;
         self.http.autofix(Some(rhttp))?;
+        match self.quic.as_mut() {
+            Some(quic) => quic.autofix(&self.tcp.addr)?,
+            None => {}
+        }
         self.service.autofix(&self.name)?;
         Ok(())
     }
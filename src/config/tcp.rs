@@ -2,16 +2,32 @@ use serde::Deserialize;
 
 use crate::utils::anyhow;
 
-use super::{bytes_size::BytesSize, tls::TlsConfig};
+use super::{
+    bytes_size::BytesSize, limits::LimitsConfig, proxy_protocol::ProxyProtocolConfig,
+    tls::TlsConfig, unix::UnixConfig,
+};
+
+/// the prefix of a unix socket address, `unix:/run/httpd.sock`.
+pub const UNIX_PREFIX: &str = "unix:";
 
 #[derive(Deserialize, Clone, Default, Debug)]
 pub struct TcpConfig {
+    // `host:port`, or `unix:/path/to.sock` for a unix socket
     #[serde(default, alias = "address", alias = "Address", alias = "Addr")]
     pub addr: String,
 
     #[serde(default)]
     pub tls: TlsConfig,
 
+    #[serde(default, alias = "Unix")]
+    pub unix: UnixConfig,
+
+    #[serde(default, alias = "Limits")]
+    pub limits: LimitsConfig,
+
+    #[serde(default, alias = "ProxyProtocol")]
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+
     #[serde(default, alias = "ReadStreamBufSize")]
     pub read_stream_buf_size: BytesSize,
 
@@ -28,6 +44,30 @@ impl TcpConfig {
             Some(v) => Some(&v.tls),
             None => None,
         })?;
+        self.unix.autofix(match root {
+            Some(v) => Some(&v.unix),
+            None => None,
+        })?;
+        self.limits.autofix(match root {
+            Some(v) => Some(&v.limits),
+            None => None,
+        })?;
+        if self.proxy_protocol.is_none() {
+            self.proxy_protocol = root.and_then(|v| v.proxy_protocol.clone());
+        }
+        match self.proxy_protocol.as_mut() {
+            Some(v) => v.autofix()?,
+            None => {}
+        }
+        match self.unix_path() {
+            Some("") => {
+                return anyhow::error(&format!(
+                    "unix socket address without path, `{}`",
+                    self.addr
+                ));
+            }
+            _ => {}
+        }
 
         match root {
             Some(root) => {
@@ -56,4 +96,9 @@ impl TcpConfig {
 
         Ok(())
     }
+
+    /// the socket file of a unix socket address.
+    pub fn unix_path(&self) -> Option<&str> {
+        self.addr.strip_prefix(UNIX_PREFIX)
+    }
 }
@@ -1,7 +1,133 @@
-use crate::utils::anyhow;
+use std::sync::Arc;
+
+use crate::{
+    tls::{
+        load_certified_key, load_certs,
+        resolver::{CertStore, SniResolver},
+        selfsigned,
+    },
+    utils::anyhow,
+};
 use serde::Deserialize;
+use tokio_rustls::rustls::{crypto::CryptoProvider, SupportedProtocolVersion};
 
-use super::duration_in_millis::DurationInMillis;
+use super::{
+    acme::{AcmeChallenge, AcmeConfig},
+    duration_in_millis::DurationInMillis,
+};
+
+#[derive(Deserialize, Clone, Default, Debug)]
+pub(crate) struct TlsCertConfig {
+    // exact names or wildcards such as `*.example.com`
+    #[serde(default, alias = "Domains", alias = "sni", alias = "SNI")]
+    pub domains: Vec<String>,
+
+    #[serde(default, alias = "Cert")]
+    pub cert: String,
+
+    #[serde(default, alias = "Key")]
+    pub key: String,
+
+    // used when no domain matches, or the client sends no SNI
+    #[serde(default, alias = "Default")]
+    pub default: Option<bool>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub(crate) enum ClientAuthMode {
+    // handshakes without a client certificate are rejected
+    #[default]
+    #[serde(alias = "required", alias = "require")]
+    Required,
+    // a client certificate is verified if sent
+    #[serde(alias = "optional")]
+    Optional,
+}
+
+#[derive(Deserialize, Clone, Default, Debug)]
+pub(crate) struct ClientAuthConfig {
+    #[serde(default, alias = "Mode")]
+    pub mode: ClientAuthMode,
+
+    // pem bundle of the trusted client ca certificates
+    #[serde(default, alias = "CA", alias = "Ca")]
+    pub ca: String,
+
+    // pem files of certificate revocation lists
+    #[serde(default, alias = "Crls", alias = "CRLs")]
+    pub crls: Vec<String>,
+}
+
+impl ClientAuthConfig {
+    fn verifier(
+        &self,
+        provider: Arc<CryptoProvider>,
+    ) -> anyhow::Result<Arc<dyn tokio_rustls::rustls::server::danger::ClientCertVerifier>> {
+        let mut roots = tokio_rustls::rustls::RootCertStore::empty();
+        let certs = match load_certs(&self.ca) {
+            Ok(v) => v,
+            Err(e) => {
+                return anyhow::error(&format!("load client ca `{}` failed, {}", self.ca, e));
+            }
+        };
+        for cert in certs {
+            anyhow::result(roots.add(cert))?;
+        }
+
+        let mut crls = vec![];
+        for fp in self.crls.iter() {
+            let file = match std::fs::File::open(fp) {
+                Ok(v) => v,
+                Err(e) => {
+                    return anyhow::error(&format!("load crl `{}` failed, {}", fp, e));
+                }
+            };
+            for crl in rustls_pemfile::crls(&mut std::io::BufReader::new(file)) {
+                match crl {
+                    Ok(crl) => crls.push(crl),
+                    Err(e) => {
+                        return anyhow::error(&format!("load crl `{}` failed, {}", fp, e));
+                    }
+                }
+            }
+        }
+
+        let mut builder =
+            tokio_rustls::rustls::server::WebPkiClientVerifier::builder_with_provider(
+                Arc::new(roots),
+                provider,
+            )
+            .with_crls(crls);
+        if self.mode == ClientAuthMode::Optional {
+            builder = builder.allow_unauthenticated();
+        }
+        anyhow::result(builder.build())
+    }
+}
+
+#[derive(Deserialize, Clone, Default, Debug)]
+pub(crate) struct SelfSignedConfig {
+    // hostnames besides `localhost` and the loopback addresses
+    #[serde(default, alias = "Domains")]
+    pub domains: Vec<String>,
+}
+
+// the minor number of `1.x`
+fn parse_version(v: &str) -> anyhow::Result<u8> {
+    let lower = v.trim().to_ascii_lowercase();
+    let num = lower
+        .strip_prefix("tlsv")
+        .or_else(|| lower.strip_prefix("tls"))
+        .unwrap_or(&lower);
+    match num {
+        "1.2" => Ok(2),
+        "1.3" => Ok(3),
+        _ => anyhow::error(&format!(
+            "unknown tls version `{}`, expect `1.2` or `1.3`",
+            v
+        )),
+    }
+}
 
 #[derive(Deserialize, Clone, Default, Debug)]
 pub(crate) struct TlsConfig {
@@ -11,8 +137,56 @@ pub(crate) struct TlsConfig {
     #[serde(default)]
     pub key: String,
 
+    #[serde(
+        default,
+        alias = "Certs",
+        alias = "certificates",
+        alias = "Certificates"
+    )]
+    pub certs: Vec<TlsCertConfig>,
+
     #[serde(default)]
     pub timeout: DurationInMillis,
+
+    // development only, generate a certificate instead of loading `cert`/`key`.
+    // it is kept in memory, or written to `cert`/`key` once and reused when both are set.
+    #[serde(default, alias = "SelfSigned")]
+    pub self_signed: Option<SelfSignedConfig>,
+
+    // obtain and renew a certificate from an acme ca
+    #[serde(default, alias = "Acme", alias = "ACME")]
+    pub acme: Option<AcmeConfig>,
+
+    // mutual tls, verify client certificates
+    #[serde(default, alias = "ClientAuth", alias = "mtls", alias = "mTLS")]
+    pub client_auth: Option<ClientAuthConfig>,
+
+    // how often the cert/key files are checked for changes
+    #[serde(default, alias = "WatchInterval")]
+    pub watch_interval: DurationInMillis,
+
+    // `1.2` or `1.3`, empty means no limit
+    #[serde(default, alias = "MinVersion")]
+    pub min_version: String,
+
+    #[serde(default, alias = "MaxVersion")]
+    pub max_version: String,
+
+    // rustls suite names such as `TLS13_AES_256_GCM_SHA384`, empty means all
+    #[serde(default, alias = "CipherSuites", alias = "ciphers", alias = "Ciphers")]
+    pub cipher_suites: Vec<String>,
+
+    // key exchange groups such as `X25519` or `secp384r1`, empty means all
+    #[serde(default, alias = "KxGroups", alias = "groups", alias = "Groups")]
+    pub kx_groups: Vec<String>,
+
+    // stateless resumption, issue session tickets
+    #[serde(default, alias = "SessionTickets")]
+    pub session_tickets: Option<bool>,
+
+    // stateful resumption, `0` disables the session cache
+    #[serde(default, alias = "SessionCacheSize")]
+    pub session_cache_size: Option<usize>,
 }
 
 impl TlsConfig {
@@ -28,31 +202,393 @@ impl TlsConfig {
         if self.timeout.as_millis() < 1 {
             self.timeout = DurationInMillis(std::time::Duration::from_secs(15));
         }
+        match root {
+            Some(root) => {
+                if self.watch_interval.is_zero() {
+                    self.watch_interval = root.watch_interval;
+                }
+            }
+            None => {}
+        }
+        if self.watch_interval.is_zero() {
+            self.watch_interval = DurationInMillis(std::time::Duration::from_secs(30));
+        }
+
+        match root {
+            Some(root) => {
+                if self.min_version.is_empty() {
+                    self.min_version = root.min_version.clone();
+                }
+                if self.max_version.is_empty() {
+                    self.max_version = root.max_version.clone();
+                }
+                if self.cipher_suites.is_empty() {
+                    self.cipher_suites = root.cipher_suites.clone();
+                }
+                if self.kx_groups.is_empty() {
+                    self.kx_groups = root.kx_groups.clone();
+                }
+                if self.session_tickets.is_none() {
+                    self.session_tickets = root.session_tickets;
+                }
+                if self.session_cache_size.is_none() {
+                    self.session_cache_size = root.session_cache_size;
+                }
+            }
+            None => {}
+        }
+        self.versions()?;
+
+        if self.self_signed.is_some() && (self.cert.is_empty() != self.key.is_empty()) {
+            return anyhow::error(
+                "self-signed tls needs both cert and key paths to persist, or neither",
+            );
+        }
+
+        match self.acme.as_mut() {
+            Some(acme) => acme.autofix()?,
+            None => {}
+        }
+
+        match self.client_auth.as_ref() {
+            Some(client_auth) => {
+                if client_auth.ca.is_empty() {
+                    return anyhow::error("tls client auth has an empty ca path");
+                }
+            }
+            None => {}
+        }
+
+        for item in self.certs.iter() {
+            if item.cert.is_empty() || item.key.is_empty() {
+                return anyhow::error(&format!(
+                    "tls certificate for {:?} has an empty cert or key path",
+                    item.domains
+                ));
+            }
+            if item.domains.is_empty() && !item.default.unwrap_or(false) {
+                return anyhow::error(&format!(
+                    "tls certificate `{}` has no domains and is not the default",
+                    item.cert
+                ));
+            }
+        }
         Ok(())
     }
 
-    pub(crate) fn load(&self) -> anyhow::Result<Option<tokio_rustls::rustls::ServerConfig>> {
-        if self.cert.is_empty() && self.key.is_empty() {
+    #[inline]
+    pub(crate) fn enabled(&self) -> bool {
+        !(self.cert.is_empty()
+            && self.key.is_empty()
+            && self.certs.is_empty()
+            && self.self_signed.is_none()
+            && self.acme.is_none())
+    }
+
+    /// whether the handshake answers tls-alpn-01 validations.
+    #[inline]
+    pub(crate) fn tls_alpn01(&self) -> bool {
+        match self.acme.as_ref() {
+            Some(acme) => acme.challenge == AcmeChallenge::TlsAlpn01,
+            None => false,
+        }
+    }
+
+    pub(crate) fn files(&self) -> Vec<&str> {
+        let mut files = vec![];
+        if !self.cert.is_empty() {
+            files.push(self.cert.as_str());
+            files.push(self.key.as_str());
+        }
+        for item in self.certs.iter() {
+            files.push(item.cert.as_str());
+            files.push(item.key.as_str());
+        }
+        match self.acme.as_ref() {
+            Some(acme) => {
+                files.push(acme.cert.as_str());
+                files.push(acme.key.as_str());
+            }
+            None => {}
+        }
+        files
+    }
+
+    pub(crate) fn load_store(&self, provider: &CryptoProvider) -> anyhow::Result<CertStore> {
+        let mut store = CertStore::default();
+        match self.self_signed.as_ref() {
+            Some(self_signed) => {
+                if self.cert.is_empty() {
+                    store
+                        .set_default(selfsigned::certified_key(provider, &self_signed.domains)?)?;
+                } else {
+                    selfsigned::persist(&self.cert, &self.key, &self_signed.domains)?;
+                }
+            }
+            None => {}
+        }
+        if !self.cert.is_empty() || !self.key.is_empty() {
+            store.set_default(load_certified_key(provider, &self.cert, &self.key, &[])?)?;
+        }
+
+        for item in self.certs.iter() {
+            let key = load_certified_key(provider, &item.cert, &item.key, &item.domains)?;
+            for domain in item.domains.iter() {
+                store.insert(domain, key.clone())?;
+            }
+            if item.default.unwrap_or(false) {
+                store.set_default(key)?;
+            }
+        }
+
+        match self.acme.as_ref() {
+            Some(acme) => {
+                // a placeholder until the first certificate is issued
+                let key = if std::path::Path::new(&acme.cert).exists() {
+                    load_certified_key(provider, &acme.cert, &acme.key, &acme.domains)?
+                } else {
+                    selfsigned::certified_key(provider, &acme.domains)?
+                };
+                for domain in acme.domains.iter() {
+                    store.insert(domain, key.clone())?;
+                }
+                if !store.has_default() && self.certs.is_empty() {
+                    store.set_default(key)?;
+                }
+            }
+            None => {}
+        }
+
+        if !store.has_default() {
+            match self.certs.first() {
+                Some(item) => {
+                    store.set_default(load_certified_key(
+                        provider,
+                        &item.cert,
+                        &item.key,
+                        &item.domains,
+                    )?)?;
+                }
+                None => {}
+            }
+        }
+        Ok(store)
+    }
+
+    pub(crate) fn versions(&self) -> anyhow::Result<Vec<&'static SupportedProtocolVersion>> {
+        let mut min = 2;
+        let mut max = 3;
+        if !self.min_version.is_empty() {
+            min = parse_version(&self.min_version)?;
+        }
+        if !self.max_version.is_empty() {
+            max = parse_version(&self.max_version)?;
+        }
+        if min > max {
+            return anyhow::error(&format!(
+                "tls min_version `{}` is greater than max_version `{}`",
+                self.min_version, self.max_version
+            ));
+        }
+
+        let mut versions = vec![];
+        if min <= 3 && max >= 3 {
+            versions.push(&tokio_rustls::rustls::version::TLS13);
+        }
+        if min <= 2 && max >= 2 {
+            versions.push(&tokio_rustls::rustls::version::TLS12);
+        }
+        Ok(versions)
+    }
+
+    // the process default provider narrowed to the configured suites and groups
+    fn provider(&self) -> anyhow::Result<CryptoProvider> {
+        // `builder` installs the process default provider from the crate features
+        let _ = tokio_rustls::rustls::ServerConfig::builder();
+        let mut provider = anyhow::option(CryptoProvider::get_default(), "no tls crypto provider")?
+            .as_ref()
+            .clone();
+
+        if !self.cipher_suites.is_empty() {
+            let mut suites = vec![];
+            for name in self.cipher_suites.iter() {
+                match provider
+                    .cipher_suites
+                    .iter()
+                    .find(|v| format!("{:?}", v.suite()).eq_ignore_ascii_case(name.trim()))
+                {
+                    Some(suite) => suites.push(*suite),
+                    None => {
+                        return anyhow::error(&format!(
+                            "unknown tls cipher suite `{}`, expect one of {:?}",
+                            name,
+                            provider
+                                .cipher_suites
+                                .iter()
+                                .map(|v| v.suite())
+                                .collect::<Vec<_>>()
+                        ));
+                    }
+                }
+            }
+            provider.cipher_suites = suites;
+        }
+
+        if !self.kx_groups.is_empty() {
+            let mut groups = vec![];
+            for name in self.kx_groups.iter() {
+                match provider
+                    .kx_groups
+                    .iter()
+                    .find(|v| format!("{:?}", v.name()).eq_ignore_ascii_case(name.trim()))
+                {
+                    Some(group) => groups.push(*group),
+                    None => {
+                        return anyhow::error(&format!(
+                            "unknown tls key exchange group `{}`, expect one of {:?}",
+                            name,
+                            provider
+                                .kx_groups
+                                .iter()
+                                .map(|v| v.name())
+                                .collect::<Vec<_>>()
+                        ));
+                    }
+                }
+            }
+            provider.kx_groups = groups;
+        }
+        Ok(provider)
+    }
+
+    pub(crate) fn load(
+        &self,
+    ) -> anyhow::Result<Option<(tokio_rustls::rustls::ServerConfig, Arc<SniResolver>)>> {
+        if !self.enabled() {
             return Ok(None);
         }
 
-        let mut certs = vec![];
-        for v in rustls_pemfile::certs(&mut std::io::BufReader::new(anyhow::result(
-            std::fs::File::open(&self.cert),
-        )?)) {
-            certs.push(anyhow::result(v)?);
+        let provider = Arc::new(self.provider()?);
+        let builder = tokio_rustls::rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&self.versions()?);
+        let builder = match builder {
+            Ok(v) => v,
+            Err(e) => {
+                return anyhow::error(&format!("bad tls protocol or cipher suite config, {}", e));
+            }
+        };
+        let builder = match self.client_auth.as_ref() {
+            Some(client_auth) => {
+                builder.with_client_cert_verifier(client_auth.verifier(provider.clone())?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let resolver = Arc::new(SniResolver::new(self.load_store(&provider)?));
+        let mut tlscfg = builder.with_cert_resolver(resolver.clone());
+
+        if self.session_tickets.unwrap_or(false) {
+            tlscfg.ticketer =
+                anyhow::result(tokio_rustls::rustls::crypto::aws_lc_rs::Ticketer::new())?;
+        }
+        match self.session_cache_size {
+            Some(0) => {
+                tlscfg.session_storage =
+                    Arc::new(tokio_rustls::rustls::server::NoServerSessionStorage {});
+            }
+            Some(size) => {
+                tlscfg.session_storage =
+                    tokio_rustls::rustls::server::ServerSessionMemoryCache::new(size);
+            }
+            None => {}
+        }
+        Ok(Some((tlscfg, resolver)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(min: &str, max: &str, suites: &[&str], groups: &[&str]) -> TlsConfig {
+        TlsConfig {
+            self_signed: Some(SelfSignedConfig::default()),
+            min_version: min.to_string(),
+            max_version: max.to_string(),
+            cipher_suites: suites.iter().map(|v| v.to_string()).collect(),
+            kx_groups: groups.iter().map(|v| v.to_string()).collect(),
+            ..Default::default()
         }
+    }
+
+    #[test]
+    fn test_versions() {
+        for (min, max) in [("1.1", ""), ("", "ssl3"), ("tls1.0", "1.3"), ("1.3", "1.2")] {
+            assert!(config(min, max, &[], &[]).autofix(None).is_err());
+        }
+
+        let mut cfg = config("TLSv1.3", "", &[], &[]);
+        cfg.autofix(None).unwrap();
+        assert_eq!(
+            cfg.versions().unwrap(),
+            vec![&tokio_rustls::rustls::version::TLS13]
+        );
+        let mut cfg = config("", "1.2", &[], &[]);
+        cfg.autofix(None).unwrap();
+        assert_eq!(
+            cfg.versions().unwrap(),
+            vec![&tokio_rustls::rustls::version::TLS12]
+        );
+        assert_eq!(config("", "", &[], &[]).versions().unwrap().len(), 2);
+
+        // inherited from the root
+        let root = config("1.3", "", &[], &[]);
+        let mut cfg = config("", "", &[], &[]);
+        cfg.autofix(Some(&root)).unwrap();
+        assert_eq!(cfg.min_version, "1.3");
+    }
 
-        let key = anyhow::result(rustls_pemfile::private_key(&mut std::io::BufReader::new(
-            anyhow::result(std::fs::File::open(&self.key))?,
-        )))?;
-        let key = anyhow::option(key, "none key")?;
+    #[test]
+    fn test_suites_and_groups() {
+        assert!(config("", "", &["TLS13_NOPE"], &[]).load().is_err());
+        assert!(config("", "", &[], &["x448_nope"]).load().is_err());
+        // no suite of the allowed version
+        assert!(
+            config("1.3", "", &["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"], &[])
+                .load()
+                .is_err()
+        );
 
-        let cfg = anyhow::result(
-            tokio_rustls::rustls::ServerConfig::builder()
-                .with_no_client_auth()
-                .with_single_cert(certs, Into::into(key)),
-        )?;
-        Ok(Some(cfg))
+        let (tlscfg, _) = config(
+            "",
+            "",
+            &[
+                "tls13_aes_256_gcm_sha384",
+                " TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+            ],
+            &["X25519", "secp384r1"],
+        )
+        .load()
+        .unwrap()
+        .unwrap();
+        let provider = tlscfg.crypto_provider();
+        assert_eq!(
+            provider
+                .cipher_suites
+                .iter()
+                .map(|v| format!("{:?}", v.suite()))
+                .collect::<Vec<_>>(),
+            vec![
+                "TLS13_AES_256_GCM_SHA384",
+                "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"
+            ]
+        );
+        assert_eq!(
+            provider
+                .kx_groups
+                .iter()
+                .map(|v| format!("{:?}", v.name()))
+                .collect::<Vec<_>>(),
+            vec!["X25519", "secp384r1"]
+        );
     }
 }
@@ -0,0 +1,50 @@
+use serde::Deserialize;
+
+use crate::utils::anyhow;
+
+// the socket file of a `unix:/path/to.sock` address
+#[derive(Deserialize, Clone, Default, Debug)]
+pub struct UnixConfig {
+    // octal permission bits of the socket file, such as `660`, the umask decides if empty
+    #[serde(default, alias = "Mode")]
+    pub mode: String,
+
+    // user name or id owning the socket file
+    #[serde(default, alias = "Owner", alias = "user", alias = "User")]
+    pub owner: String,
+
+    // group name or id of the socket file
+    #[serde(default, alias = "Group")]
+    pub group: String,
+}
+
+impl UnixConfig {
+    pub fn autofix(&mut self, root: Option<&Self>) -> anyhow::Result<()> {
+        match root {
+            Some(root) => {
+                if self.mode.is_empty() {
+                    self.mode = root.mode.clone();
+                }
+                if self.owner.is_empty() {
+                    self.owner = root.owner.clone();
+                }
+                if self.group.is_empty() {
+                    self.group = root.group.clone();
+                }
+            }
+            None => {}
+        }
+        self.mode()?;
+        Ok(())
+    }
+
+    pub fn mode(&self) -> anyhow::Result<Option<u32>> {
+        if self.mode.is_empty() {
+            return Ok(None);
+        }
+        match u32::from_str_radix(self.mode.trim_start_matches("0o"), 8) {
+            Ok(v) if v <= 0o7777 => Ok(Some(v)),
+            _ => anyhow::error(&format!("bad unix socket mode `{}`", self.mode)),
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! request cookies, `set-cookie` and the signed or encrypted values keyed by
+//! `http.cookies.secret`, RFC 6265.
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    internal::{header, multi_map::MultiMap},
+    utils::anyhow,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const B64: base64::engine::GeneralPurpose = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+const NONCE_LEN: usize = 12;
+
+fn hmac(key: &[u8]) -> HmacSha256 {
+    // hmac takes keys of any length
+    <HmacSha256 as Mac>::new_from_slice(key).unwrap()
+}
+
+/// the pairs of the `cookie` headers, RFC 6265 section 5.4. names keep their case, values
+/// lose their double quotes, pairs without `=` are skipped.
+pub(crate) fn parse(headers: Option<&Vec<String>>) -> MultiMap {
+    let mut cookies = MultiMap::new();
+    for v in headers.into_iter().flatten() {
+        for pair in v.split(';') {
+            let (name, value) = match pair.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+            if name.is_empty() {
+                continue;
+            }
+            let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(v) => v,
+                None => value,
+            };
+            cookies.append(name, value);
+        }
+    }
+    cookies
+}
+
+/// the keys of signed and encrypted cookies.
+#[derive(Clone)]
+pub(crate) struct CookieKeys {
+    sign: [u8; 32],
+    encrypt: [u8; 32],
+}
+
+impl std::fmt::Debug for CookieKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CookieKeys(..)")
+    }
+}
+
+impl CookieKeys {
+    /// both keys from one secret, HMAC-SHA256 of a distinct label each.
+    pub(crate) fn derive(secret: &[u8]) -> Self {
+        let key = |label: &[u8]| -> [u8; 32] {
+            let mut mac = hmac(secret);
+            mac.update(label);
+            mac.finalize().into_bytes().into()
+        };
+        Self {
+            sign: key(b"httpd cookie signing"),
+            encrypt: key(b"httpd cookie encryption"),
+        }
+    }
+
+    // bound to the name, a value can not be moved to another cookie
+    fn mac(&self, name: &str, value: &str) -> HmacSha256 {
+        let mut mac = hmac(&self.sign);
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    /// `value` and its tag, readable by the client but not changeable.
+    pub(crate) fn sign(&self, name: &str, value: &str) -> String {
+        let tag = self.mac(name, value).finalize().into_bytes();
+        format!("{}.{}", value, B64.encode(tag))
+    }
+
+    /// the value of a signed cookie, `None` if it was changed.
+    pub(crate) fn verify(&self, name: &str, signed: &str) -> Option<String> {
+        let (value, tag) = signed.rsplit_once('.')?;
+        let tag = B64.decode(tag).ok()?;
+        // constant time
+        self.mac(name, value).verify_slice(&tag).ok()?;
+        Some(value.to_string())
+    }
+
+    /// `value` sealed with AES-256-GCM under a random nonce, the name is associated data.
+    pub(crate) fn encrypt(&self, name: &str, value: &str) -> String {
+        let cipher = Aes256Gcm::new((&self.encrypt).into());
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: value.as_bytes(),
+            aad: name.as_bytes(),
+        };
+        let mut out = nonce.to_vec();
+        // fails only past 64GiB
+        out.extend(cipher.encrypt(&nonce, payload).unwrap());
+        B64.encode(out)
+    }
+
+    /// the value of an encrypted cookie, `None` if it was changed or sealed by another key.
+    pub(crate) fn decrypt(&self, name: &str, sealed: &str) -> Option<String> {
+        let raw = B64.decode(sealed).ok()?;
+        if raw.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, msg) = raw.split_at(NONCE_LEN);
+        let cipher = Aes256Gcm::new((&self.encrypt).into());
+        let payload = Payload {
+            msg,
+            aad: name.as_bytes(),
+        };
+        let plain = cipher.decrypt(Nonce::from_slice(nonce), payload).ok()?;
+        String::from_utf8(plain).ok()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// a `set-cookie` header, written by `ResponseWriter::setcookie`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SetCookie {
+    name: String,
+    value: String,
+    max_age: Option<i64>,
+    expires: Option<chrono::DateTime<chrono::Utc>>,
+    path: String,
+    domain: String,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+    partitioned: bool,
+}
+
+// %x21 / %x23-2B / %x2D-3A / %x3C-5B / %x5D-7E, RFC 6265 section 4.1.1
+fn is_cookie_octet(b: u8) -> bool {
+    b.is_ascii_graphic() && !b"\",;\\".contains(&b)
+}
+
+// an attribute value, no controls and no `;`
+fn is_attr_value(v: &str) -> bool {
+    v.bytes()
+        .all(|b| (b.is_ascii_graphic() || b == b' ') && b != b';')
+}
+
+impl SetCookie {
+    pub(crate) fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// removes the cookie `name` from the client, `path` and `domain` must be the ones it
+    /// was set with.
+    pub(crate) fn removal(name: &str) -> Self {
+        let mut cookie = Self::new(name, "");
+        cookie
+            .max_age(0)
+            .expires(chrono::DateTime::<chrono::Utc>::UNIX_EPOCH);
+        cookie
+    }
+
+    /// seconds, zero or less removes the cookie.
+    pub(crate) fn max_age(&mut self, secs: i64) -> &mut Self {
+        self.max_age = Some(secs);
+        self
+    }
+
+    pub(crate) fn expires(&mut self, at: chrono::DateTime<chrono::Utc>) -> &mut Self {
+        self.expires = Some(at);
+        self
+    }
+
+    pub(crate) fn path(&mut self, v: &str) -> &mut Self {
+        self.path = v.to_string();
+        self
+    }
+
+    pub(crate) fn domain(&mut self, v: &str) -> &mut Self {
+        self.domain = v.to_string();
+        self
+    }
+
+    pub(crate) fn secure(&mut self, v: bool) -> &mut Self {
+        self.secure = v;
+        self
+    }
+
+    pub(crate) fn http_only(&mut self, v: bool) -> &mut Self {
+        self.http_only = v;
+        self
+    }
+
+    /// `None` and `partitioned` imply `secure`, browsers drop such cookies otherwise.
+    pub(crate) fn same_site(&mut self, v: SameSite) -> &mut Self {
+        self.same_site = Some(v);
+        self
+    }
+
+    /// stored per top-level site, CHIPS.
+    pub(crate) fn partitioned(&mut self, v: bool) -> &mut Self {
+        self.partitioned = v;
+        self
+    }
+
+    /// sign the value, read back by `RequestReader::signed_cookie`.
+    pub(crate) fn signed(&mut self, keys: &CookieKeys) -> &mut Self {
+        self.value = keys.sign(&self.name, &self.value);
+        self
+    }
+
+    /// encrypt the value, read back by `RequestReader::encrypted_cookie`.
+    pub(crate) fn encrypted(&mut self, keys: &CookieKeys) -> &mut Self {
+        self.value = keys.encrypt(&self.name, &self.value);
+        self
+    }
+
+    /// the value of the header, `Err` for a name that is not a token or a value or an
+    /// attribute with characters a cookie can not hold.
+    pub(crate) fn to_header(&self) -> anyhow::Result<String> {
+        if self.name.is_empty() || !self.name.bytes().all(header::is_tchar) {
+            return anyhow::error(&format!("bad cookie name `{}`", self.name));
+        }
+        if !self.value.bytes().all(is_cookie_octet) {
+            return anyhow::error(&format!("bad value of cookie `{}`", self.name));
+        }
+        if !is_attr_value(&self.path) || !is_attr_value(&self.domain) {
+            return anyhow::error(&format!("bad attribute of cookie `{}`", self.name));
+        }
+
+        let mut out = format!("{}={}", self.name, self.value);
+        match self.max_age {
+            Some(secs) => out.push_str(&format!("; Max-Age={}", std::cmp::max(secs, 0))),
+            None => {}
+        }
+        match self.expires {
+            Some(at) => out.push_str(&at.format("; Expires=%a, %d %b %Y %H:%M:%S GMT").to_string()),
+            None => {}
+        }
+        if !self.path.is_empty() {
+            out.push_str("; Path=");
+            out.push_str(&self.path);
+        }
+        if !self.domain.is_empty() {
+            out.push_str("; Domain=");
+            out.push_str(&self.domain);
+        }
+        if self.secure || self.partitioned || self.same_site == Some(SameSite::None) {
+            out.push_str("; Secure");
+        }
+        if self.http_only {
+            out.push_str("; HttpOnly");
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => out.push_str("; SameSite=Strict"),
+            Some(SameSite::Lax) => out.push_str("; SameSite=Lax"),
+            Some(SameSite::None) => out.push_str("; SameSite=None"),
+            None => {}
+        }
+        if self.partitioned {
+            out.push_str("; Partitioned");
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let headers = vec!["a=1; B=\"2\";c; =x; a = 3".to_string(), "d=".to_string()];
+        let cookies = parse(Some(&headers));
+        assert_eq!(cookies.getall("a").unwrap(), &vec!["1", "3"]);
+        assert_eq!(cookies.get("B").unwrap(), "2");
+        assert!(cookies.get("b").is_none() && cookies.get("c").is_none());
+        assert_eq!(cookies.get("d").unwrap(), "");
+    }
+
+    #[test]
+    fn test_set_cookie() {
+        let mut cookie = SetCookie::new("sid", "abc");
+        cookie
+            .max_age(3600)
+            .expires(chrono::DateTime::from_timestamp(784111777, 0).unwrap())
+            .path("/")
+            .domain("example.com")
+            .http_only(true)
+            .same_site(SameSite::None)
+            .partitioned(true);
+        assert_eq!(
+            cookie.to_header().unwrap(),
+            "sid=abc; Max-Age=3600; Expires=Sun, 06 Nov 1994 08:49:37 GMT; Path=/; \
+                Domain=example.com; Secure; HttpOnly; SameSite=None; Partitioned"
+        );
+        assert_eq!(
+            SetCookie::removal("sid").to_header().unwrap(),
+            "sid=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+        assert!(SetCookie::new("a b", "1").to_header().is_err());
+        assert!(SetCookie::new("a", "1;2").to_header().is_err());
+        assert!(SetCookie::new("a", "1").path("/;x").to_header().is_err());
+    }
+
+    #[test]
+    fn test_keys() {
+        let keys = CookieKeys::derive(b"0123456789abcdef0123456789abcdef");
+        let other = CookieKeys::derive(b"0123456789abcdef0123456789abcdeF");
+
+        let signed = keys.sign("user", "alice.admin");
+        assert_eq!(keys.verify("user", &signed).unwrap(), "alice.admin");
+        assert!(keys.verify("other", &signed).is_none());
+        assert!(other.verify("user", &signed).is_none());
+        let forged = signed.replacen("alice", "mallo", 1);
+        assert!(keys.verify("user", &forged).is_none());
+
+        let sealed = keys.encrypt("user", "alice; admin");
+        assert!(!sealed.contains("alice"));
+        assert_ne!(sealed, keys.encrypt("user", "alice; admin"));
+        assert_eq!(keys.decrypt("user", &sealed).unwrap(), "alice; admin");
+        assert!(keys.decrypt("other", &sealed).is_none());
+        assert!(other.decrypt("user", &sealed).is_none());
+        assert!(keys.decrypt("user", "AAAA").is_none());
+
+        let mut cookie = SetCookie::new("user", "alice; admin");
+        assert!(cookie.encrypted(&keys).to_header().is_ok());
+    }
+}
@@ -1,6 +1,38 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
-use crate::config::service::ServiceConfig;
+use crate::{config::service::ServiceConfig, proxy_protocol::ProxyInfo, tls::TlsInfo};
+
+/// the address of the peer of a connection.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Peer {
+    Ip(SocketAddr),
+    // a unix socket client, usually unnamed
+    Unix,
+}
+
+impl Peer {
+    pub(crate) fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Ip(addr) => Some(addr.ip()),
+            Peer::Unix => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Peer::Ip(addr)
+    }
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Ip(addr) => addr.fmt(f),
+            Peer::Unix => f.write_str("unix:"),
+        }
+    }
+}
 
 pub(crate) struct ConnContext<
     R: tokio::io::AsyncBufReadExt + Unpin,
@@ -9,17 +41,20 @@ pub(crate) struct ConnContext<
     pub(crate) reader: R,
     pub(crate) writer: W,
     pub(crate) buf: Vec<u8>,
-    pub(crate) addr: SocketAddr,
+    pub(crate) addr: Peer,
     pub(crate) config: &'static ServiceConfig,
     pub(crate) over_tls: bool,
+    pub(crate) tls: Option<TlsInfo>,
+    // sent by the load balancer in front, `addr` is the client it tells
+    pub(crate) proxy: Option<ProxyInfo>,
 }
 
 impl<R: tokio::io::AsyncBufReadExt + Unpin, W: tokio::io::AsyncWriteExt + Unpin> ConnContext<R, W> {
     pub(crate) fn new(
         r: R,
         w: W,
-        addr: SocketAddr,
-        over_tls: bool,
+        addr: Peer,
+        tls: Option<TlsInfo>,
         config: &'static ServiceConfig,
     ) -> Self {
         Self {
@@ -27,7 +62,9 @@ impl<R: tokio::io::AsyncBufReadExt + Unpin, W: tokio::io::AsyncWriteExt + Unpin>
             writer: w,
             buf: Vec::with_capacity(config.tcp.buf_size.0),
             addr,
-            over_tls,
+            over_tls: tls.is_some(),
+            tls,
+            proxy: None,
             config,
         }
     }
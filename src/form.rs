@@ -0,0 +1,667 @@
+//! bodies of html forms, `application/x-www-form-urlencoded` and `multipart/form-data`,
+//! RFC 7578. multipart bodies are read from any `AsyncBufRead`, one part at a time; the
+//! server reads them off the connection for a service that asks, see `Service::multipart`.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt};
+
+use crate::{
+    config::form::FormConfig,
+    internal::{header, multi_map::MultiMap, uri},
+    message::Message,
+};
+
+// taken from the reader at once
+const READ_SIZE: usize = 64 * 1024;
+// the rest of a delimiter line
+const MAX_PADDING: usize = 256;
+
+#[derive(Debug)]
+pub(crate) enum FormError {
+    // not the expected form type, or a multipart type without a boundary
+    UnsupportedType,
+    Bad,
+    TooManyParts,
+    TooLarge,
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for FormError {
+    // the errors of the body readers, `message::ChunkedReader`
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => Self::Bad,
+            std::io::ErrorKind::FileTooLarge => Self::TooLarge,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+impl FormError {
+    /// the response to a request with this body.
+    pub(crate) fn status(&self) -> (u16, &'static str) {
+        match self {
+            Self::UnsupportedType => (415, "Unsupported Media Type"),
+            Self::Bad => (400, "Bad Request"),
+            Self::TooManyParts | Self::TooLarge => (413, "Content Too Large"),
+            Self::Io(_) => (500, "Internal Server Error"),
+        }
+    }
+}
+
+// `; name=value` pairs, names lowercased. quoted values lose their quotes, `escapes` for
+// the quoted-pairs of RFC 9110 section 5.6.4; browsers do not escape in multipart headers
+fn params(v: &str, escapes: bool) -> Vec<(String, String)> {
+    let mut out = vec![];
+    let mut rest = v;
+    loop {
+        rest = rest.trim_start_matches([';', ' ', '\t']);
+        if rest.is_empty() {
+            return out;
+        }
+        let end = rest.find(['=', ';']).unwrap_or(rest.len());
+        let name = rest[..end].trim().to_ascii_lowercase();
+        rest = &rest[end..];
+        let mut value = String::new();
+        match rest.strip_prefix('=') {
+            Some(v) => {
+                let v = v.trim_start();
+                match v.strip_prefix('"') {
+                    Some(quoted) => {
+                        let mut end = quoted.len();
+                        let mut escaped = false;
+                        for (idx, c) in quoted.char_indices() {
+                            if escaped {
+                                value.push(c);
+                                escaped = false;
+                            } else if c == '\\' && escapes {
+                                escaped = true;
+                            } else if c == '"' {
+                                end = idx + 1;
+                                break;
+                            } else {
+                                value.push(c);
+                            }
+                        }
+                        rest = &quoted[end..];
+                    }
+                    None => {
+                        let end = v.find(';').unwrap_or(v.len());
+                        value.push_str(v[..end].trim());
+                        rest = &v[end..];
+                    }
+                }
+            }
+            None => {}
+        }
+        if !name.is_empty() {
+            out.push((name, value));
+        }
+    }
+}
+
+/// the lowercased media type of a `content-type` value and its parameters.
+pub(crate) fn media_type(v: &str) -> (String, Vec<(String, String)>) {
+    let (mt, rest) = match v.split_once(';') {
+        Some(v) => v,
+        None => (v, ""),
+    };
+    (mt.trim().to_ascii_lowercase(), params(rest, true))
+}
+
+/// the fields of an `application/x-www-form-urlencoded` body, in order, no more than
+/// `max_fields`.
+pub(crate) fn parse_urlencoded(
+    body: &[u8],
+    dest: &mut MultiMap,
+    max_fields: usize,
+) -> Result<(), FormError> {
+    let body = match std::str::from_utf8(body) {
+        Ok(v) => v,
+        Err(_) => return Err(FormError::Bad),
+    };
+    if body.split('&').filter(|v| !v.is_empty()).count() > max_fields {
+        return Err(FormError::TooManyParts);
+    }
+    match uri::parse_query(body, dest) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(FormError::Bad),
+    }
+}
+
+/// whether the body of `req` is a form to read part by part, one with a content coding is
+/// left to the service.
+pub(crate) fn is_multipart(req: &Message) -> bool {
+    let (mt, _) = match req.headers.get("content-type") {
+        Some(v) => media_type(v),
+        None => return false,
+    };
+    mt == "multipart/form-data" && req.headers.get("content-encoding").is_none()
+}
+
+/// read the `multipart/form-data` body of `req` from `reader`, which ends with the body,
+/// into `req.form`. the epilogue is read and dropped.
+pub(crate) async fn read_multipart<R: AsyncBufRead + Unpin>(
+    req: &mut Message,
+    mut reader: R,
+    cfg: &FormConfig,
+) -> Result<(), FormError> {
+    let form = match req.headers.get("content-type") {
+        Some(v) => Multipart::new(&mut reader, v, cfg)?.collect().await?,
+        None => return Err(FormError::UnsupportedType),
+    };
+    if let Err(e) = tokio::io::copy_buf(&mut reader, &mut tokio::io::sink()).await {
+        return Err(e.into());
+    }
+    req.form = Some(form);
+    Ok(())
+}
+
+/// `read_multipart` for a body already in memory, as with http2 and http3.
+pub(crate) async fn read_buffered(req: &mut Message, cfg: &FormConfig) -> Result<(), FormError> {
+    let body = std::mem::take(&mut req.body);
+    read_multipart(req, body.inner(), cfg).await
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// a file part on disk, removed when dropped unless persisted.
+#[derive(Debug)]
+pub(crate) struct TempFile {
+    path: PathBuf,
+    size: u64,
+}
+
+impl TempFile {
+    async fn create(dir: &Path) -> std::io::Result<(Self, tokio::fs::File)> {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let nanos = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            Ok(v) => v.subsec_nanos(),
+            Err(_) => 0,
+        };
+        let path = dir.join(format!(
+            "httpd.form.{}.{}.{}",
+            std::process::id(),
+            SEQ.fetch_add(1, Ordering::Relaxed),
+            nanos
+        ));
+        let mut opts = tokio::fs::OpenOptions::new();
+        opts.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            opts.mode(0o600);
+        }
+        let file = opts.open(&path).await?;
+        Ok((Self { path, size: 0 }, file))
+    }
+
+    #[inline]
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[inline]
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// move the file to `to`, on the same file system, where it is kept.
+    pub(crate) fn persist(mut self, to: &Path) -> std::io::Result<()> {
+        std::fs::rename(&self.path, to)?;
+        self.path = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum PartData {
+    Memory(Vec<u8>),
+    File(TempFile),
+}
+
+#[derive(Debug)]
+pub(crate) struct Part {
+    // names lowercased
+    pub(crate) headers: MultiMap,
+    pub(crate) name: String,
+    pub(crate) filename: Option<String>,
+    // `text/plain` if not sent
+    pub(crate) content_type: String,
+    pub(crate) data: PartData,
+}
+
+/// the fields and the file parts of a whole `multipart/form-data` body.
+#[derive(Debug, Default)]
+pub(crate) struct Form {
+    pub(crate) fields: MultiMap,
+    pub(crate) files: Vec<Part>,
+}
+
+/// the parts of a `multipart/form-data` body, read as they are asked for. file parts
+/// larger than `spool_size` are written to temporary files.
+pub(crate) struct Multipart<'c, R: AsyncBufRead + Unpin> {
+    reader: R,
+    cfg: &'c FormConfig,
+    // CRLF `--` boundary
+    delimiter: Vec<u8>,
+    pending: Vec<u8>,
+    eof: bool,
+    parts: usize,
+    started: bool,
+    done: bool,
+}
+
+impl<'c, R: AsyncBufRead + Unpin> Multipart<'c, R> {
+    pub(crate) fn new(
+        reader: R,
+        content_type: &str,
+        cfg: &'c FormConfig,
+    ) -> Result<Self, FormError> {
+        let (mt, params) = media_type(content_type);
+        if mt != "multipart/form-data" {
+            return Err(FormError::UnsupportedType);
+        }
+        let boundary = match params.iter().find(|(k, _)| k == "boundary") {
+            Some((_, v)) => v.as_str(),
+            None => return Err(FormError::UnsupportedType),
+        };
+        // 1 to 70 characters, not ending with a space, RFC 2046 section 5.1.1
+        if boundary.is_empty()
+            || boundary.len() > 70
+            || boundary.ends_with(' ')
+            || !boundary.bytes().all(|b| b.is_ascii_graphic() || b == b' ')
+        {
+            return Err(FormError::UnsupportedType);
+        }
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        Ok(Self {
+            reader,
+            cfg,
+            delimiter,
+            // the first delimiter may start the body
+            pending: b"\r\n".to_vec(),
+            eof: false,
+            parts: 0,
+            started: false,
+            done: false,
+        })
+    }
+
+    // more of the body into `pending`, `false` at its end
+    async fn fill(&mut self) -> Result<bool, FormError> {
+        if self.eof {
+            return Ok(false);
+        }
+        let buf = match self.reader.fill_buf().await {
+            Ok(buf) => buf,
+            Err(e) => return Err(e.into()),
+        };
+        if buf.is_empty() {
+            self.eof = true;
+            return Ok(false);
+        }
+        let size = std::cmp::min(buf.len(), READ_SIZE);
+        self.pending.extend_from_slice(&buf[..size]);
+        self.reader.consume(size);
+        Ok(true)
+    }
+
+    // the preamble, up to the first delimiter
+    async fn skip_preamble(&mut self) -> Result<(), FormError> {
+        loop {
+            match find(&self.pending, &self.delimiter) {
+                Some(idx) => {
+                    self.pending.drain(..idx + self.delimiter.len());
+                    return Ok(());
+                }
+                None => {
+                    let keep = std::cmp::min(self.pending.len(), self.delimiter.len() - 1);
+                    self.pending.drain(..self.pending.len() - keep);
+                    if !self.fill().await? {
+                        return Err(FormError::Bad);
+                    }
+                }
+            }
+        }
+    }
+
+    // the rest of a delimiter line, `false` for the close delimiter
+    async fn after_delimiter(&mut self) -> Result<bool, FormError> {
+        loop {
+            if self.pending.starts_with(b"--") {
+                return Ok(false);
+            }
+            match find(&self.pending, b"\r\n") {
+                Some(idx) => {
+                    // transport padding
+                    if !self.pending[..idx]
+                        .iter()
+                        .all(|b| *b == b' ' || *b == b'\t')
+                    {
+                        return Err(FormError::Bad);
+                    }
+                    self.pending.drain(..idx + 2);
+                    return Ok(true);
+                }
+                None => {
+                    if self.pending.len() > MAX_PADDING || !self.fill().await? {
+                        return Err(FormError::Bad);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn read_headers(&mut self) -> Result<MultiMap, FormError> {
+        let max = self.cfg.max_part_header_size.0;
+        let mut headers = MultiMap::new();
+        let mut size = 0;
+        loop {
+            let idx = loop {
+                match find(&self.pending, b"\r\n") {
+                    Some(idx) => break idx,
+                    None => {
+                        if size + self.pending.len() > max {
+                            return Err(FormError::TooLarge);
+                        }
+                        if !self.fill().await? {
+                            return Err(FormError::Bad);
+                        }
+                    }
+                }
+            };
+            size += idx + 2;
+            if size > max {
+                return Err(FormError::TooLarge);
+            }
+            if idx == 0 {
+                self.pending.drain(..2);
+                return Ok(headers);
+            }
+            // filenames may be utf-8
+            let line = match std::str::from_utf8(&self.pending[..idx]) {
+                Ok(v) => v,
+                Err(_) => return Err(FormError::Bad),
+            };
+            if line.bytes().any(|b| (b < 0x20 && b != b'\t') || b == 0x7f) {
+                return Err(FormError::Bad);
+            }
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => return Err(FormError::Bad),
+            };
+            if name.is_empty() || !name.bytes().all(header::is_tchar) {
+                return Err(FormError::Bad);
+            }
+            headers.append(&name.to_ascii_lowercase(), value);
+            self.pending.drain(..idx + 2);
+        }
+    }
+
+    async fn read_data(&mut self, file: bool) -> Result<PartData, FormError> {
+        let cfg = self.cfg;
+        let mut memory = vec![];
+        let mut spooled: Option<(TempFile, tokio::fs::File)> = None;
+        let mut size = 0;
+        loop {
+            let (end, found) = match find(&self.pending, &self.delimiter) {
+                Some(idx) => (idx, true),
+                // a delimiter may begin at the end
+                None => (
+                    self.pending.len().saturating_sub(self.delimiter.len() - 1),
+                    false,
+                ),
+            };
+            if end > 0 {
+                size += end;
+                let limit = if file {
+                    cfg.max_file_size.0
+                } else {
+                    cfg.max_field_size.0
+                };
+                if limit > 0 && size > limit {
+                    return Err(FormError::TooLarge);
+                }
+                if file && spooled.is_none() && size > cfg.spool_size.0 {
+                    let (tmp, mut f) = match TempFile::create(&cfg.temp_dir()).await {
+                        Ok(v) => v,
+                        Err(e) => return Err(FormError::Io(e)),
+                    };
+                    if let Err(e) = f.write_all(&memory).await {
+                        return Err(FormError::Io(e));
+                    }
+                    memory = vec![];
+                    spooled = Some((tmp, f));
+                }
+                match spooled.as_mut() {
+                    Some((_, f)) => {
+                        if let Err(e) = f.write_all(&self.pending[..end]).await {
+                            return Err(FormError::Io(e));
+                        }
+                    }
+                    None => memory.extend_from_slice(&self.pending[..end]),
+                }
+            }
+            if found {
+                self.pending.drain(..end + self.delimiter.len());
+                break;
+            }
+            self.pending.drain(..end);
+            if !self.fill().await? {
+                return Err(FormError::Bad);
+            }
+        }
+
+        match spooled {
+            Some((mut tmp, mut f)) => {
+                if let Err(e) = f.flush().await {
+                    return Err(FormError::Io(e));
+                }
+                tmp.size = size as u64;
+                Ok(PartData::File(tmp))
+            }
+            None => Ok(PartData::Memory(memory)),
+        }
+    }
+
+    /// the next part, `None` after the last one.
+    pub(crate) async fn next_part(&mut self) -> Result<Option<Part>, FormError> {
+        if self.done {
+            return Ok(None);
+        }
+        if !self.started {
+            self.started = true;
+            self.skip_preamble().await?;
+        }
+        if !self.after_delimiter().await? {
+            // the epilogue is not read
+            self.done = true;
+            return Ok(None);
+        }
+        self.parts += 1;
+        if self.parts > self.cfg.max_parts {
+            return Err(FormError::TooManyParts);
+        }
+
+        let headers = self.read_headers().await?;
+        let (kind, params) = match headers.get("content-disposition") {
+            Some(v) => {
+                let (kind, rest) = match v.split_once(';') {
+                    Some(v) => v,
+                    None => (v.as_str(), ""),
+                };
+                (kind.trim().to_ascii_lowercase(), params(rest, false))
+            }
+            None => return Err(FormError::Bad),
+        };
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+        };
+        let name = match param("name") {
+            Some(name) if kind == "form-data" && !name.is_empty() => name,
+            _ => return Err(FormError::Bad),
+        };
+        let filename = param("filename");
+        let content_type = match headers.get("content-type") {
+            Some(v) => v.clone(),
+            None => "text/plain".to_string(),
+        };
+        let data = self.read_data(filename.is_some()).await?;
+        Ok(Some(Part {
+            headers,
+            name,
+            filename,
+            content_type,
+            data,
+        }))
+    }
+
+    /// every part, fields by name and file parts as they are.
+    pub(crate) async fn collect(mut self) -> Result<Form, FormError> {
+        let mut form = Form::default();
+        while let Some(part) = self.next_part().await? {
+            match (&part.filename, &part.data) {
+                (None, PartData::Memory(v)) => match std::str::from_utf8(v) {
+                    Ok(v) => form.fields.append(&part.name, v),
+                    Err(_) => return Err(FormError::Bad),
+                },
+                _ => form.files.push(part),
+            }
+        }
+        Ok(form)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::bytes_size::BytesSize;
+
+    const CONTENT_TYPE: &str = "multipart/form-data; boundary=\"--xY\"";
+
+    fn config() -> FormConfig {
+        let mut cfg = FormConfig::default();
+        cfg.autofix(None).unwrap();
+        cfg.spool_size = BytesSize(8);
+        cfg.max_field_size = BytesSize(16);
+        cfg.max_file_size = BytesSize(64);
+        cfg.max_parts = 3;
+        cfg
+    }
+
+    // read through a tiny buffer, delimiters are split across reads
+    fn collect(cfg: &FormConfig, body: &str) -> Result<Form, FormError> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let r = tokio::io::BufReader::with_capacity(3, body.as_bytes());
+            Multipart::new(r, CONTENT_TYPE, cfg)?.collect().await
+        })
+    }
+
+    #[test]
+    fn test_media_type() {
+        let (mt, params) = media_type("Multipart/Form-Data; charset=utf-8;boundary=\"a\\\"; b\"");
+        assert_eq!(mt, "multipart/form-data");
+        assert_eq!(
+            params,
+            vec![
+                ("charset".to_string(), "utf-8".to_string()),
+                ("boundary".to_string(), "a\"; b".to_string())
+            ]
+        );
+        let mut fields = MultiMap::new();
+        parse_urlencoded(b"a=1&b=x+y%21&a=2", &mut fields, 3).unwrap();
+        assert_eq!(fields.getall("a").unwrap(), &vec!["1", "2"]);
+        assert_eq!(fields.get("b").unwrap(), "x y!");
+        assert!(parse_urlencoded(b"a=%zz", &mut fields, 3).is_err());
+        assert!(matches!(
+            parse_urlencoded(b"a=1&b=2&&c=3&d", &mut fields, 3),
+            Err(FormError::TooManyParts)
+        ));
+    }
+
+    #[test]
+    fn test_multipart() {
+        let cfg = config();
+        let body = "preamble\r\n----xY\r\n\
+            Content-Disposition: form-data; name=\"a\"\r\n\r\nhello\r\n\
+            ----xY  \r\n\
+            content-disposition: form-data; name=\"f\"; filename=\"C:\\x\\\u{e9}.txt\"\r\n\
+            content-type: text/csv\r\n\r\n\
+            1,2\r\n3,4\r\n----x\r\n5,6\r\n\
+            ----xY--\r\nepilogue";
+        let form = collect(&cfg, body).unwrap();
+        assert_eq!(form.fields.get("a").unwrap(), "hello");
+        assert_eq!(form.files.len(), 1);
+        let part = &form.files[0];
+        assert_eq!(part.filename.as_deref(), Some("C:\\x\\\u{e9}.txt"));
+        assert_eq!(part.content_type, "text/csv");
+        let path = match &part.data {
+            PartData::File(tmp) => {
+                assert_eq!(tmp.size(), 20);
+                let content = std::fs::read_to_string(tmp.path()).unwrap();
+                assert_eq!(content, "1,2\r\n3,4\r\n----x\r\n5,6");
+                tmp.path().to_path_buf()
+            }
+            PartData::Memory(_) => panic!("not spooled"),
+        };
+        drop(form);
+        assert!(!path.exists());
+
+        let empty = collect(&cfg, "----xY--").unwrap();
+        assert!(empty.fields.len() == 0 && empty.files.is_empty());
+    }
+
+    #[test]
+    fn test_multipart_errors() {
+        let cfg = config();
+        let part = |name: &str, value: &str| {
+            format!(
+                "----xY\r\ncontent-disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                name, value
+            )
+        };
+        let status = |body: String| match collect(&cfg, &body) {
+            Ok(_) => 200,
+            Err(e) => e.status().0,
+        };
+        assert_eq!(status(format!("{}----xY--", part("a", "1"))), 200);
+        // never closed
+        assert_eq!(status(part("a", "1")), 400);
+        assert_eq!(status(format!("{}----xY--", part("", "1"))), 400);
+        assert_eq!(
+            status(format!("{}----xY--", part("a", &"x".repeat(17)))),
+            413
+        );
+        assert_eq!(status(format!("{}----xY--", part("a", "1").repeat(4))), 413);
+        assert_eq!(
+            status("----xY\r\nx: 1\r\n\r\n1\r\n----xY--".to_string()),
+            400
+        );
+        assert_eq!(status("----xYz\r\n".to_string()), 400);
+
+        let r = tokio::io::BufReader::new(&b""[..]);
+        assert!(Multipart::new(r, "multipart/form-data", &cfg).is_err());
+    }
+}
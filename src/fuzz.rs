@@ -0,0 +1,257 @@
+//! entry points of the fuzz targets in `fuzz/`. every input is fed to the parsers through an
+//! in-memory pipe, the first byte picks the mode and how the bytes are split.
+
+use std::sync::OnceLock;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::{
+    config::{bytes_size::BytesSize, service::ServiceConfig},
+    ctx::ConnContext,
+    message::{Message, MessageReadCode},
+    ws_impl,
+};
+
+const MAX_BODY_SIZE: usize = 64 * 1024;
+const MAX_WS_PAYLOAD: usize = 64 * 1024;
+
+fn config(lenient: bool) -> &'static ServiceConfig {
+    static CONFIGS: OnceLock<[&'static ServiceConfig; 2]> = OnceLock::new();
+    let configs = CONFIGS.get_or_init(|| {
+        [false, true].map(|lenient| {
+            let mut cfg = ServiceConfig::default();
+            cfg.http.autofix(None).unwrap();
+            cfg.tcp.autofix(None).unwrap();
+            cfg.http.max_url_size = BytesSize(1024);
+            cfg.http.max_header_line_size = BytesSize(1024);
+            cfg.http.max_headers_count = 64;
+            cfg.http.max_body_size = BytesSize(MAX_BODY_SIZE);
+            cfg.http.lenient = Some(lenient);
+            &*Box::leak(Box::new(cfg))
+        })
+    });
+    configs[lenient as usize]
+}
+
+fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    thread_local! {
+        static RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+    }
+    RUNTIME.with(|rt| rt.block_on(f))
+}
+
+// the mode byte: bit 0 is lenient, the rest sizes the pipe and the read buffer
+fn split(data: &[u8]) -> Option<(bool, usize, &[u8])> {
+    let (&mode, data) = data.split_first()?;
+    Some((mode & 1 == 1, 1 + (mode >> 1) as usize, data))
+}
+
+// a reader of `data` that gets at most `cap` bytes at once
+async fn pipe(data: &[u8], cap: usize) -> BufReader<tokio::io::DuplexStream> {
+    let (r, mut w) = tokio::io::duplex(cap);
+    let data = data.to_vec();
+    tokio::spawn(async move {
+        _ = w.write_all(&data).await;
+    });
+    BufReader::with_capacity(cap, r)
+}
+
+/// one request from the start of a connection, its body too if the headers are fine.
+pub fn read_headers(data: &[u8]) {
+    let (lenient, cap, data) = match split(data) {
+        Some(v) => v,
+        None => return,
+    };
+    let cfg = config(lenient);
+    block_on(async {
+        let r = pipe(data, cap).await;
+        let addr: std::net::SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let mut ctx = ConnContext::new(r, tokio::io::sink(), addr.into(), None, cfg);
+        let mut msg = Message::default();
+        match msg.read_headers(&mut ctx).await {
+            MessageReadCode::Ok => {}
+            _ => return,
+        }
+        assert!(!msg.firstline.0.is_empty() && !msg.firstline.1.is_empty());
+        assert!(msg.firstline.2.starts_with("HTTP/"));
+        assert!(msg.headers.len() <= cfg.http.max_headers_count as usize);
+        assert!(msg.framing().is_ok());
+        if msg.read_body(&mut ctx).await == MessageReadCode::Ok {
+            assert!(msg.body.inner().len() <= MAX_BODY_SIZE);
+        }
+    });
+}
+
+/// a chunked body and its trailers.
+pub fn read_chunked_body(data: &[u8]) {
+    let (lenient, cap, data) = match split(data) {
+        Some(v) => v,
+        None => return,
+    };
+    block_on(async {
+        let mut r = pipe(data, cap).await;
+        let mut msg = Message::default();
+        let mut buf = vec![0; cap];
+        let code = msg
+            .read_chunked_body(&mut r, &mut buf, MAX_BODY_SIZE, lenient)
+            .await;
+        if code == MessageReadCode::Ok {
+            assert!(msg.body.inner().len() <= MAX_BODY_SIZE);
+        }
+    });
+}
+
+/// the frames of a websocket client until the first error.
+pub fn read_ws_frame(data: &[u8]) {
+    let (_, cap, data) = match split(data) {
+        Some(v) => v,
+        None => return,
+    };
+    block_on(async {
+        let mut r = pipe(data, cap).await;
+        while let Ok(frame) = ws_impl::read_frame(&mut r, MAX_WS_PAYLOAD).await {
+            assert!(frame.payload.len() <= MAX_WS_PAYLOAD);
+        }
+    });
+}
+
+/// an HTTP/1.x request accepted in strict mode is read the same way by `httparse`.
+pub fn differential(data: &[u8]) {
+    let cfg = config(false);
+    let (code, msg, consumed) = block_on(async {
+        let addr: std::net::SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let mut ctx = ConnContext::new(
+            BufReader::new(data),
+            tokio::io::sink(),
+            addr.into(),
+            None,
+            cfg,
+        );
+        let mut msg = Message::default();
+        let code = msg.read_headers(&mut ctx).await;
+        let remain = ctx.reader.buffer().len() + ctx.reader.get_ref().len();
+        (code, msg, data.len() - remain)
+    });
+    // `httparse` knows no other versions
+    let version = match msg.firstline.2.as_str() {
+        "HTTP/1.0" => 0,
+        "HTTP/1.1" => 1,
+        _ => return,
+    };
+    if code != MessageReadCode::Ok {
+        return;
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(data) {
+        Ok(httparse::Status::Complete(size)) => assert_eq!(size, consumed, "{:?}", data),
+        v => panic!("httparse: {:?}, {:?}", v, data),
+    }
+    assert_eq!(req.method, Some(msg.firstline.0.as_str()));
+    assert_eq!(req.path, Some(msg.firstline.1.as_str()));
+    assert_eq!(req.version, Some(version));
+
+    let mut count = 0;
+    for h in req.headers.iter() {
+        let name = h.name.to_ascii_lowercase();
+        let values: Vec<&str> = req
+            .headers
+            .iter()
+            .filter(|v| v.name.eq_ignore_ascii_case(&name))
+            .map(|v| std::str::from_utf8(v.value).unwrap().trim())
+            .collect();
+        match msg.headers.getall(&name) {
+            Some(ours) => assert_eq!(ours, &values, "{:?}", data),
+            None => panic!("no header `{}`: {:?}", name, data),
+        }
+        count += 1;
+    }
+    let mut ours = 0;
+    msg.headers.each(&mut |_, vs| {
+        ours += vs.len();
+        true
+    });
+    assert_eq!(ours, count, "{:?}", data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift, the cases are the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 as usize
+        }
+
+        fn pick<'a>(&mut self, vs: &[&'a str]) -> &'a str {
+            vs[self.next() % vs.len()]
+        }
+    }
+
+    // a request of likely and unlikely pieces, some bytes flipped
+    fn request(rng: &mut Rng) -> Vec<u8> {
+        let methods = ["GET", "POST", "M-SEARCH", "G ET", "", "get", "GET\t"];
+        let uris = ["/", "/a?b=c", "*", "http://a/b", "/%20", "/a b", ""];
+        let versions = [
+            "HTTP/1.1", "HTTP/1.0", "HTTP/2.0", "HTTP/1.", "http/1.1", "HTTP/11",
+        ];
+        let ends = ["\r\n", "\r\n", "\r\n", "\n", "\r", ""];
+        let names = ["host", "Content-Length", "x-a", "x a", "", "x\t", "a@b"];
+        let seps = [":", ": ", ":\t", " :", "::", ""];
+        let values = ["a", " a ", "1, 1", "", "a\tb", "\x7f", "a\x01"];
+
+        let mut out = String::new();
+        out.push_str(rng.pick(&methods));
+        out.push(' ');
+        out.push_str(rng.pick(&uris));
+        out.push(' ');
+        out.push_str(rng.pick(&versions));
+        out.push_str(rng.pick(&ends));
+        for _ in 0..rng.next() % 4 {
+            out.push_str(rng.pick(&names));
+            out.push_str(rng.pick(&seps));
+            out.push_str(rng.pick(&values));
+            out.push_str(rng.pick(&ends));
+        }
+        out.push_str(rng.pick(&ends));
+        out.push_str("rest");
+
+        let mut bytes = out.into_bytes();
+        if rng.next().is_multiple_of(4) && !bytes.is_empty() {
+            let idx = rng.next() % bytes.len();
+            bytes[idx] = rng.next() as u8;
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_differential() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..5000 {
+            differential(&request(&mut rng));
+        }
+    }
+
+    #[test]
+    fn test_entries() {
+        let raw = b"POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n";
+        for mode in [0u8, 1, 6, 255] {
+            let mut data = vec![mode];
+            data.extend_from_slice(raw);
+            read_headers(&data);
+            read_chunked_body(&[&[mode][..], b"3;a=b\r\nabc\r\n0\r\nx: y\r\n\r\n"].concat());
+            read_ws_frame(&[&[mode][..], b"\x81\x82abcd\x00\x00"].concat());
+            differential(raw);
+        }
+        read_headers(&[]);
+    }
+}
@@ -0,0 +1,135 @@
+use tokio::io::AsyncReadExt;
+
+pub(crate) const DATA: u8 = 0x0;
+pub(crate) const HEADERS: u8 = 0x1;
+pub(crate) const PRIORITY: u8 = 0x2;
+pub(crate) const RST_STREAM: u8 = 0x3;
+pub(crate) const SETTINGS: u8 = 0x4;
+pub(crate) const PUSH_PROMISE: u8 = 0x5;
+pub(crate) const PING: u8 = 0x6;
+pub(crate) const GOAWAY: u8 = 0x7;
+pub(crate) const WINDOW_UPDATE: u8 = 0x8;
+pub(crate) const CONTINUATION: u8 = 0x9;
+
+pub(crate) const FLAG_ACK: u8 = 0x1;
+pub(crate) const FLAG_END_STREAM: u8 = 0x1;
+pub(crate) const FLAG_END_HEADERS: u8 = 0x4;
+pub(crate) const FLAG_PADDED: u8 = 0x8;
+pub(crate) const FLAG_PRIORITY: u8 = 0x20;
+
+pub(crate) const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+pub(crate) const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub(crate) const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub(crate) const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub(crate) const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub(crate) const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    Cancel = 0x8,
+    CompressionError = 0x9,
+    EnhanceYourCalm = 0xb,
+}
+
+pub(crate) const DEFAULT_WINDOW_SIZE: i64 = 65535;
+pub(crate) const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+pub(crate) const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FrameHeader {
+    pub(crate) len: usize,
+    pub(crate) kind: u8,
+    pub(crate) flags: u8,
+    pub(crate) stream: u32,
+}
+
+impl FrameHeader {
+    pub(crate) async fn read<R: tokio::io::AsyncBufReadExt + Unpin>(
+        reader: &mut R,
+    ) -> std::io::Result<Self> {
+        let mut raw = [0u8; 9];
+        reader.read_exact(&mut raw).await?;
+        Ok(Self {
+            len: ((raw[0] as usize) << 16) | ((raw[1] as usize) << 8) | raw[2] as usize,
+            kind: raw[3],
+            flags: raw[4],
+            stream: u32::from_be_bytes([raw[5], raw[6], raw[7], raw[8]]) & 0x7fff_ffff,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+pub(crate) fn write_frame(dest: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    let len = payload.len();
+    dest.extend_from_slice(&[(len >> 16) as u8, (len >> 8) as u8, len as u8, kind, flags]);
+    dest.extend_from_slice(&(stream & 0x7fff_ffff).to_be_bytes());
+    dest.extend_from_slice(payload);
+}
+
+pub(crate) fn write_settings(dest: &mut Vec<u8>, settings: &[(u16, u32)]) {
+    let mut payload = Vec::with_capacity(settings.len() * 6);
+    for (id, value) in settings {
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.extend_from_slice(&value.to_be_bytes());
+    }
+    write_frame(dest, SETTINGS, 0, 0, &payload);
+}
+
+pub(crate) fn write_rst_stream(dest: &mut Vec<u8>, stream: u32, code: ErrorCode) {
+    write_frame(dest, RST_STREAM, 0, stream, &(code as u32).to_be_bytes());
+}
+
+pub(crate) fn write_window_update(dest: &mut Vec<u8>, stream: u32, increment: u32) {
+    write_frame(dest, WINDOW_UPDATE, 0, stream, &increment.to_be_bytes());
+}
+
+pub(crate) fn write_goaway(dest: &mut Vec<u8>, last_stream: u32, code: ErrorCode) {
+    let mut payload = [0u8; 8];
+    payload[..4].copy_from_slice(&last_stream.to_be_bytes());
+    payload[4..].copy_from_slice(&(code as u32).to_be_bytes());
+    write_frame(dest, GOAWAY, 0, 0, &payload);
+}
+
+/// parse a SETTINGS payload into `(identifier, value)` pairs.
+pub(crate) fn parse_settings(payload: &[u8]) -> Result<Vec<(u16, u32)>, ErrorCode> {
+    if !payload.len().is_multiple_of(6) {
+        return Err(ErrorCode::FrameSizeError);
+    }
+    Ok(payload
+        .chunks(6)
+        .map(|v| {
+            (
+                u16::from_be_bytes([v[0], v[1]]),
+                u32::from_be_bytes([v[2], v[3], v[4], v[5]]),
+            )
+        })
+        .collect())
+}
+
+/// strip the padding of a DATA/HEADERS payload.
+pub(crate) fn unpad(header: &FrameHeader, payload: &[u8]) -> Result<(usize, usize), ErrorCode> {
+    if !header.has(FLAG_PADDED) {
+        return Ok((0, payload.len()));
+    }
+    match payload.first() {
+        Some(padlen) => {
+            let padlen = *padlen as usize;
+            if padlen + 1 > payload.len() {
+                return Err(ErrorCode::ProtocolError);
+            }
+            Ok((1, payload.len() - padlen))
+        }
+        None => Err(ErrorCode::FrameSizeError),
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::VecDeque;
+
+use super::huffman;
+
+// RFC 7541, Appendix A
+static STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// every entry costs 32 extra octets, RFC 7541 section 4.1
+const ENTRY_OVERHEAD: usize = 32;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum HpackError {
+    Integer,
+    Index,
+    String,
+    Huffman,
+    SizeUpdate,
+    // the decoded header list is over the `Budget`
+    TooLarge,
+}
+
+/// how large a decoded header list may be, `size` counts name, value and 32 octets a field
+/// as `SETTINGS_MAX_HEADER_LIST_SIZE` does.
+pub(crate) struct Budget {
+    pub(crate) fields: usize,
+    pub(crate) size: usize,
+}
+
+fn decode_int(src: &[u8], pos: &mut usize, prefix: u8) -> Result<usize, HpackError> {
+    let mask: u8 = ((1u16 << prefix) - 1) as u8;
+    let first = match src.get(*pos) {
+        Some(b) => *b & mask,
+        None => return Err(HpackError::Integer),
+    };
+    *pos += 1;
+    if first < mask {
+        return Ok(first as usize);
+    }
+
+    let mut value = mask as usize;
+    let mut shift = 0;
+    loop {
+        let b = match src.get(*pos) {
+            Some(b) => *b,
+            None => return Err(HpackError::Integer),
+        };
+        *pos += 1;
+        if shift > 28 {
+            return Err(HpackError::Integer);
+        }
+        value += ((b & 0x7f) as usize) << shift;
+        shift += 7;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_int(dest: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let mask: usize = (1 << prefix) - 1;
+    if value < mask {
+        dest.push(flags | value as u8);
+        return;
+    }
+    dest.push(flags | mask as u8);
+    value -= mask;
+    while value >= 0x80 {
+        dest.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    dest.push(value as u8);
+}
+
+fn decode_string(src: &[u8], pos: &mut usize) -> Result<String, HpackError> {
+    let huffman = match src.get(*pos) {
+        Some(b) => *b & 0x80 != 0,
+        None => return Err(HpackError::String),
+    };
+    let len = decode_int(src, pos, 7)?;
+    if src.len() - *pos < len {
+        return Err(HpackError::String);
+    }
+    let raw = &src[*pos..*pos + len];
+    *pos += len;
+
+    let bytes = if huffman {
+        let mut buf = Vec::with_capacity(len * 8 / 5);
+        if huffman::decode(raw, &mut buf).is_err() {
+            return Err(HpackError::Huffman);
+        }
+        buf
+    } else {
+        raw.to_vec()
+    };
+    match String::from_utf8(bytes) {
+        Ok(v) => Ok(v),
+        Err(_) => Err(HpackError::String),
+    }
+}
+
+pub(crate) struct Decoder {
+    entries: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    limit: usize,
+}
+
+impl Decoder {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            size: 0,
+            max_size: limit,
+            limit,
+        }
+    }
+
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            match self.entries.pop_back() {
+                Some((k, v)) => {
+                    self.size -= k.len() + v.len() + ENTRY_OVERHEAD;
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn insert(&mut self, k: &str, v: &str) {
+        let size = k.len() + v.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        if size > self.max_size {
+            return;
+        }
+        self.entries.push_front((k.to_string(), v.to_string()));
+        self.size += size;
+    }
+
+    fn get(&self, idx: usize) -> Result<(&str, &str), HpackError> {
+        if idx < 1 {
+            return Err(HpackError::Index);
+        }
+        if idx <= STATIC_TABLE.len() {
+            let (k, v) = STATIC_TABLE[idx - 1];
+            return Ok((k, v));
+        }
+        match self.entries.get(idx - STATIC_TABLE.len() - 1) {
+            Some((k, v)) => Ok((k.as_str(), v.as_str())),
+            None => Err(HpackError::Index),
+        }
+    }
+
+    /// decode a complete header block, calling `visitor` for every field in order. once the
+    /// list is over `budget` the visitor is not called anymore and `TooLarge` is returned, the
+    /// rest of the block is still read to keep the dynamic table in sync.
+    pub(crate) fn decode<V: FnMut(&str, &str)>(
+        &mut self,
+        src: &[u8],
+        budget: &Budget,
+        visitor: &mut V,
+    ) -> Result<(), HpackError> {
+        let mut pos = 0;
+        let mut fields = 0;
+        let mut size = 0;
+        let mut within = |k: &str, v: &str, fields: usize| {
+            size += k.len() + v.len() + ENTRY_OVERHEAD;
+            fields < budget.fields && size <= budget.size
+        };
+        let mut over = false;
+        while pos < src.len() {
+            let b = src[pos];
+            if b & 0x80 != 0 {
+                // indexed header field
+                let idx = decode_int(src, &mut pos, 7)?;
+                let (k, v) = self.get(idx)?;
+                over = over || !within(k, v, fields);
+                if !over {
+                    visitor(k, v);
+                }
+            } else if b & 0xc0 == 0x40 {
+                // literal with incremental indexing
+                let (k, v) = self.literal(src, &mut pos, 6)?;
+                over = over || !within(&k, &v, fields);
+                if !over {
+                    visitor(&k, &v);
+                }
+                self.insert(&k, &v);
+            } else if b & 0xe0 == 0x20 {
+                // dynamic table size update, only allowed at the beginning of a block
+                if fields > 0 {
+                    return Err(HpackError::SizeUpdate);
+                }
+                let size = decode_int(src, &mut pos, 5)?;
+                if size > self.limit {
+                    return Err(HpackError::SizeUpdate);
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // literal without indexing / never indexed
+                let (k, v) = self.literal(src, &mut pos, 4)?;
+                over = over || !within(&k, &v, fields);
+                if !over {
+                    visitor(&k, &v);
+                }
+            }
+            fields += 1;
+        }
+        if over {
+            return Err(HpackError::TooLarge);
+        }
+        Ok(())
+    }
+
+    fn literal(
+        &self,
+        src: &[u8],
+        pos: &mut usize,
+        prefix: u8,
+    ) -> Result<(String, String), HpackError> {
+        let idx = decode_int(src, pos, prefix)?;
+        let name = if idx == 0 {
+            decode_string(src, pos)?
+        } else {
+            self.get(idx)?.0.to_string()
+        };
+        let value = decode_string(src, pos)?;
+        Ok((name, value))
+    }
+}
+
+/// never touches the dynamic table, so the peer's `SETTINGS_HEADER_TABLE_SIZE` does not matter.
+#[derive(Default)]
+pub(crate) struct Encoder;
+
+impl Encoder {
+    pub(crate) fn encode(&mut self, k: &str, v: &str, dest: &mut Vec<u8>) {
+        let mut name_idx = 0;
+        for (idx, (sk, sv)) in STATIC_TABLE.iter().enumerate() {
+            if *sk != k {
+                continue;
+            }
+            if *sv == v {
+                encode_int(dest, 0x80, 7, idx + 1);
+                return;
+            }
+            if name_idx == 0 {
+                name_idx = idx + 1;
+            }
+        }
+
+        encode_int(dest, 0x00, 4, name_idx);
+        if name_idx == 0 {
+            encode_int(dest, 0x00, 7, k.len());
+            dest.extend_from_slice(k.as_bytes());
+        }
+        encode_int(dest, 0x00, 7, v.len());
+        dest.extend_from_slice(v.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_int, Budget, Decoder, Encoder, HpackError};
+
+    const ANY: Budget = Budget {
+        fields: usize::MAX,
+        size: usize::MAX,
+    };
+
+    fn unhex(v: &str) -> Vec<u8> {
+        let v: String = v.chars().filter(|c| !c.is_whitespace()).collect();
+        (0..v.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&v[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn decode(decoder: &mut Decoder, block: &str) -> Vec<(String, String)> {
+        let mut fields = vec![];
+        decoder
+            .decode(&unhex(block), &ANY, &mut |k, v| {
+                fields.push((k.to_string(), v.to_string()))
+            })
+            .unwrap();
+        fields
+    }
+
+    #[test]
+    fn test_rfc7541_c4() {
+        let mut decoder = Decoder::new(4096);
+
+        let fields = decode(&mut decoder, "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff");
+        assert_eq!(fields[3], (":authority".into(), "www.example.com".into()));
+
+        let fields = decode(&mut decoder, "8286 84be 5886 a8eb 1064 9cbf");
+        assert_eq!(fields[3], (":authority".into(), "www.example.com".into()));
+        assert_eq!(fields[4], ("cache-control".into(), "no-cache".into()));
+
+        let fields = decode(
+            &mut decoder,
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        );
+        assert_eq!(fields[2], (":path".into(), "/index.html".into()));
+        assert_eq!(fields[4], ("custom-key".into(), "custom-value".into()));
+        assert_eq!(decoder.entries.len(), 3);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut encoder = Encoder;
+        let mut block = vec![];
+        encoder.encode(":status", "200", &mut block);
+        encoder.encode("content-length", "12", &mut block);
+        encoder.encode("x-httpd", "hello", &mut block);
+
+        let mut decoder = Decoder::new(4096);
+        let mut fields = vec![];
+        decoder
+            .decode(&block, &ANY, &mut |k, v| {
+                fields.push(format!("{}: {}", k, v))
+            })
+            .unwrap();
+        assert_eq!(
+            fields,
+            vec![":status: 200", "content-length: 12", "x-httpd: hello"]
+        );
+    }
+
+    #[test]
+    fn test_budget() {
+        // a 4000 octets entry, then a thousand one octet references to it
+        let mut block = vec![0x40];
+        encode_int(&mut block, 0, 7, 1);
+        block.push(b'x');
+        encode_int(&mut block, 0, 7, 4000);
+        block.extend(std::iter::repeat_n(b'v', 4000));
+        block.extend(std::iter::repeat_n(0xbe, 1000));
+        // the table is kept in sync after the budget is spent
+        block.extend_from_slice(&[0x40, 0x01, b'y', 0x01, b'z']);
+
+        let mut decoder = Decoder::new(8192);
+        let budget = Budget {
+            fields: 128,
+            size: 16 * 1024,
+        };
+        let mut count = 0;
+        let result = decoder.decode(&block, &budget, &mut |_, _| count += 1);
+        assert_eq!(result, Err(HpackError::TooLarge));
+        assert_eq!(count, 4);
+
+        let mut next = vec![0xbe, 0xbf];
+        Encoder.encode(":path", "/", &mut next);
+        let mut fields = vec![];
+        decoder
+            .decode(&next, &budget, &mut |k, v| {
+                fields.push(format!("{}: {}", k, v.len()))
+            })
+            .unwrap();
+        assert_eq!(fields, vec!["y: 1", "x: 4000", ":path: 1"]);
+
+        let budget = Budget {
+            fields: 2,
+            size: usize::MAX,
+        };
+        let result = decoder.decode(&next, &budget, &mut |_, _| {});
+        assert_eq!(result, Err(HpackError::TooLarge));
+    }
+}
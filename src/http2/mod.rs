@@ -0,0 +1,1410 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt},
+    time::Instant,
+};
+
+use crate::{
+    config::service::ServiceConfig, ctx::ConnContext, form, internal::header, message::Message,
+    protocols::Protocol, reqr::RequestReader, respw::ResponseWriter, serve,
+    services::common::Service, shutdown, utils::anyhow,
+};
+
+use self::frame::{ErrorCode, FrameHeader};
+
+mod frame;
+mod hpack;
+mod huffman;
+
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const MAX_CONCURRENT_STREAMS: u32 = 128;
+const HEADER_TABLE_SIZE: usize = 4096;
+
+// connection-specific fields, not allowed in http2 messages. RFC 9113 section 8.2.2
+pub(crate) static CONNECTION_HEADERS: [&str; 6] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+    "http2-settings",
+];
+
+/// read the first bytes of a connection into `head` until they are the http2 preface or
+/// can not be its start, within `idle_timeout` for the first byte and `header_timeout` for
+/// the rest. prior knowledge, RFC 9113 section 3.3.
+pub(crate) async fn read_preface<R: tokio::io::AsyncRead + Unpin>(
+    cfg: &ServiceConfig,
+    r: &mut R,
+    head: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    let mut buf = [0u8; 24];
+    let mut deadline = Instant::now() + cfg.http.idle_timeout.0;
+    while head.len() < PREFACE.len() && PREFACE.starts_with(head) {
+        let read = r.read(&mut buf[..PREFACE.len() - head.len()]);
+        let size = tokio::select! {
+            result = tokio::time::timeout_at(deadline, read) => match result {
+                Ok(Ok(0)) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(Ok(size)) => size,
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Err(std::io::ErrorKind::TimedOut.into()),
+            },
+            _ = shutdown::wait() => {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+        };
+        if head.is_empty() {
+            deadline = Instant::now() + cfg.http.header_timeout.0;
+        }
+        head.extend_from_slice(&buf[..size]);
+    }
+    Ok(())
+}
+
+fn decode_settings(v: &str) -> Option<Vec<(u16, u32)>> {
+    use base64::Engine;
+
+    let raw = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(v.trim().trim_end_matches('='))
+        .ok()?;
+    frame::parse_settings(&raw).ok()
+}
+
+/// `Upgrade: h2c`, RFC 7540 section 3.2. returns `false` if the request should be served as http1.
+pub(crate) fn upgrade(req: &RequestReader, resp: &mut ResponseWriter) -> bool {
+    match req.version() {
+        Ok((1, 1)) => {}
+        _ => return false,
+    }
+    let headers = req.headers();
+    if !header::contains(headers.getall("upgrade"), "h2c")
+        || !header::contains(headers.getall("connection"), "upgrade")
+        || !header::contains(headers.getall("connection"), "http2-settings")
+    {
+        return false;
+    }
+    match req.headers().getall("http2-settings") {
+        Some(vs) => {
+            if vs.len() != 1 || decode_settings(&vs[0]).is_none() {
+                return false;
+            }
+        }
+        None => return false,
+    }
+
+    resp.version(1, 1).code(101, "Switching Protocols");
+    resp.header("connection", "Upgrade")
+        .header("upgrade", "h2c");
+    true
+}
+
+enum Error {
+    Io(std::io::Error),
+    Conn(ErrorCode),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<ErrorCode> for Error {
+    fn from(value: ErrorCode) -> Self {
+        Self::Conn(value)
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// services are written against a byte stream, a stream of a http2 connection has none
+type StreamContext = ConnContext<tokio::io::BufReader<tokio::io::Empty>, tokio::io::Sink>;
+
+#[derive(Default)]
+struct Stream {
+    req: Message,
+    body_size: usize,
+    send_window: i64,
+    headers_done: bool,
+    // END_STREAM is received
+    remote_closed: bool,
+    // when the body began, it has `body_timeout_of(body_size)` from then
+    body_since: Option<Instant>,
+    // the response from the service or a rejection, `sent` bytes of its body are out
+    resp: Option<Message>,
+    sent: usize,
+    // for the header block, the body, or the peer to open its window
+    deadline: Option<Instant>,
+}
+
+struct Conn {
+    cfg: &'static ServiceConfig,
+    decoder: hpack::Decoder,
+    encoder: hpack::Encoder,
+    payload: Vec<u8>,
+    out: Vec<u8>,
+
+    streams: HashMap<u32, Stream>,
+    // requests with a body to come, not yet shown to `Service::accept_body`
+    accepts: VecDeque<u32>,
+    // complete requests, for the service
+    ready: VecDeque<u32>,
+    // responses with a body left to send
+    sending: VecDeque<u32>,
+    last_stream: u32,
+    // (stream, header block, end_stream)
+    continuation: Option<(u32, Vec<u8>, bool)>,
+
+    send_window: i64,
+    initial_window: i64,
+    max_frame_size: usize,
+    goaway: bool,
+    // we sent GOAWAY on shutdown, no new streams
+    draining: bool,
+
+    max_body_size: usize,
+    // the advertised `SETTINGS_MAX_HEADER_LIST_SIZE` and `max_headers_count`
+    headers: hpack::Budget,
+}
+
+impl Conn {
+    fn new<R: tokio::io::AsyncBufReadExt + Unpin, W: AsyncWriteExt + Unpin>(
+        ctx: &ConnContext<R, W>,
+    ) -> Self {
+        Self {
+            cfg: ctx.config,
+            decoder: hpack::Decoder::new(HEADER_TABLE_SIZE),
+            encoder: hpack::Encoder,
+            payload: Vec::with_capacity(frame::DEFAULT_MAX_FRAME_SIZE),
+            out: Vec::with_capacity(ctx.config.tcp.buf_size.0),
+            streams: HashMap::new(),
+            accepts: VecDeque::new(),
+            ready: VecDeque::new(),
+            sending: VecDeque::new(),
+            last_stream: 0,
+            continuation: None,
+            send_window: frame::DEFAULT_WINDOW_SIZE,
+            initial_window: frame::DEFAULT_WINDOW_SIZE,
+            max_frame_size: frame::DEFAULT_MAX_FRAME_SIZE,
+            goaway: false,
+            draining: false,
+            max_body_size: ctx.config.http.max_body_size.0,
+            headers: hpack::Budget {
+                fields: ctx.config.http.max_headers_count as usize,
+                size: ctx.config.http.max_header_line_size.0 * 4,
+            },
+        }
+    }
+
+    fn apply_settings(&mut self, settings: &[(u16, u32)]) -> Result<()> {
+        for (id, value) in settings {
+            match *id {
+                frame::SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = *value as i64;
+                    if value > frame::MAX_WINDOW_SIZE {
+                        return Err(ErrorCode::FlowControlError.into());
+                    }
+                    let delta = value - self.initial_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                    }
+                    self.initial_window = value;
+                }
+                frame::SETTINGS_MAX_FRAME_SIZE => {
+                    if *value < frame::DEFAULT_MAX_FRAME_SIZE as u32 || *value > 0xff_ffff {
+                        return Err(ErrorCode::ProtocolError.into());
+                    }
+                    self.max_frame_size = *value as usize;
+                }
+                frame::SETTINGS_ENABLE_PUSH => {
+                    if *value > 1 {
+                        return Err(ErrorCode::ProtocolError.into());
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush<W: AsyncWriteExt + Unpin>(&mut self, writer: &mut W) -> Result<()> {
+        let out = &mut self.out;
+        let result = tokio::time::timeout(self.cfg.http.write_timeout.0, async {
+            if !out.is_empty() {
+                writer.write_all(out).await?;
+                out.clear();
+            }
+            writer.flush().await
+        })
+        .await;
+        match result {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(Error::Io(std::io::ErrorKind::TimedOut.into())),
+        }
+    }
+
+    async fn read_frame<R: tokio::io::AsyncBufReadExt + Unpin>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<FrameHeader> {
+        let header = FrameHeader::read(reader).await?;
+        if header.len > frame::DEFAULT_MAX_FRAME_SIZE {
+            return Err(ErrorCode::FrameSizeError.into());
+        }
+        self.payload.resize(header.len, 0);
+        reader.read_exact(&mut self.payload).await?;
+        Ok(header)
+    }
+
+    fn open_stream(&mut self, id: u32) -> Result<bool> {
+        if id.is_multiple_of(2) || id <= self.last_stream {
+            return Err(ErrorCode::ProtocolError.into());
+        }
+        self.last_stream = id;
+        if self.goaway || self.draining || self.streams.len() >= MAX_CONCURRENT_STREAMS as usize {
+            frame::write_rst_stream(&mut self.out, id, ErrorCode::RefusedStream);
+            return Ok(false);
+        }
+        self.streams.insert(
+            id,
+            Stream {
+                send_window: self.initial_window,
+                deadline: Some(Instant::now() + self.cfg.http.header_timeout.0),
+                ..Default::default()
+            },
+        );
+        Ok(true)
+    }
+
+    fn reset(&mut self, id: u32, code: ErrorCode) {
+        self.forget(id);
+        frame::write_rst_stream(&mut self.out, id, code);
+    }
+
+    fn forget(&mut self, id: u32) {
+        self.streams.remove(&id);
+        self.accepts.retain(|v| *v != id);
+        self.ready.retain(|v| *v != id);
+        self.sending.retain(|v| *v != id);
+    }
+
+    fn finish_headers(&mut self, id: u32, block: &[u8], end_stream: bool) -> Result<()> {
+        let mut fields: Vec<(String, String)> = vec![];
+        let decoded = self.decoder.decode(block, &self.headers, &mut |k, v| {
+            fields.push((k.to_string(), v.to_string()));
+        });
+        match decoded {
+            Ok(_) => {}
+            Err(hpack::HpackError::TooLarge) => {
+                if self.streams.contains_key(&id) {
+                    self.reset(id, ErrorCode::ProtocolError);
+                }
+                return Ok(());
+            }
+            Err(_) => return Err(ErrorCode::CompressionError.into()),
+        }
+
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None => return Ok(()), // refused, the block was decoded only to keep the hpack state in sync
+        };
+
+        if stream.headers_done {
+            // trailers
+            if !end_stream {
+                self.reset(id, ErrorCode::ProtocolError);
+                return Ok(());
+            }
+            // trailers count against the same field limit as the headers
+            if stream.req.headers.len() + fields.len() > self.headers.fields {
+                self.reset(id, ErrorCode::ProtocolError);
+                return Ok(());
+            }
+            for (k, v) in fields.iter() {
+                if k.starts_with(':') {
+                    self.reset(id, ErrorCode::ProtocolError);
+                    return Ok(());
+                }
+                stream.req.headers.append(k, v);
+            }
+            stream.remote_closed = true;
+            if stream.resp.is_none() {
+                stream.deadline = None;
+                self.ready.push_back(id);
+            }
+            return Ok(());
+        }
+
+        let mut regular = false;
+        let mut scheme = false;
+        let mut authority: Option<String> = None;
+        let mut bad = false;
+        let req = &mut stream.req;
+        for (k, v) in fields.iter() {
+            if bad {
+                break;
+            }
+            if k.bytes().any(|c| c.is_ascii_uppercase()) || CONNECTION_HEADERS.contains(&k.as_str())
+            {
+                bad = true;
+                break;
+            }
+            if k == "te" && !v.eq_ignore_ascii_case("trailers") {
+                bad = true;
+                break;
+            }
+            if !k.starts_with(':') {
+                regular = true;
+                req.headers.append(k, v);
+                continue;
+            }
+            if regular {
+                bad = true;
+                break;
+            }
+            match k.as_str() {
+                ":method" if req.firstline.0.is_empty() => req.firstline.0.push_str(v),
+                ":path" if req.firstline.1.is_empty() && !v.is_empty() => {
+                    req.firstline.1.push_str(v)
+                }
+                ":scheme" if !scheme => scheme = true,
+                ":authority" if authority.is_none() => authority = Some(v.clone()),
+                _ => bad = true,
+            }
+        }
+
+        if !bad {
+            if req.firstline.0 == "CONNECT" {
+                bad = scheme || !req.firstline.1.is_empty() || authority.is_none();
+            } else {
+                bad = !scheme || req.firstline.0.is_empty() || req.firstline.1.is_empty();
+            }
+        }
+        if bad {
+            self.reset(id, ErrorCode::ProtocolError);
+            return Ok(());
+        }
+
+        req.firstline.2.push_str("HTTP/2.0");
+        match authority {
+            Some(authority) => {
+                if req.headers.get("host").is_none() {
+                    req.headers.set("host", &authority);
+                }
+            }
+            None => {}
+        }
+        stream.headers_done = true;
+        if end_stream {
+            stream.remote_closed = true;
+            stream.deadline = None;
+            self.ready.push_back(id);
+        } else {
+            let now = Instant::now();
+            stream.body_since = Some(now);
+            stream.deadline = Some(now + self.cfg.http.body_timeout_of(0));
+            self.accepts.push_back(id);
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, header: FrameHeader) -> Result<()> {
+        match self.continuation.as_ref() {
+            Some((id, _, _)) => {
+                if header.kind != frame::CONTINUATION || header.stream != *id {
+                    return Err(ErrorCode::ProtocolError.into());
+                }
+            }
+            None => {
+                if header.kind == frame::CONTINUATION {
+                    return Err(ErrorCode::ProtocolError.into());
+                }
+            }
+        }
+
+        let payload = std::mem::take(&mut self.payload);
+        let result = self.handle_payload(header, &payload);
+        self.payload = payload;
+        result
+    }
+
+    fn handle_payload(&mut self, header: FrameHeader, payload: &[u8]) -> Result<()> {
+        let id = header.stream;
+        match header.kind {
+            frame::DATA => {
+                if id == 0 {
+                    return Err(ErrorCode::ProtocolError.into());
+                }
+                let (begin, end) = frame::unpad(&header, payload)?;
+                if !payload.is_empty() {
+                    // the body is buffered in memory and bounded by `max_body_size`, so credit back immediately
+                    frame::write_window_update(&mut self.out, 0, payload.len() as u32);
+                }
+
+                let max_body_size = self.max_body_size;
+                match self.streams.get_mut(&id) {
+                    Some(stream) if stream.headers_done && !stream.remote_closed => {
+                        // the body of a rejected request is dropped, and no more is asked for
+                        let rejected = stream.resp.is_some();
+                        stream.body_size += end - begin;
+                        if !rejected {
+                            if stream.body_size > max_body_size {
+                                self.reset(id, ErrorCode::Cancel);
+                                return Ok(());
+                            }
+                            stream.req.body.write_all_to_internal(&payload[begin..end]);
+                        }
+                        if header.has(frame::FLAG_END_STREAM) {
+                            stream.remote_closed = true;
+                            if !rejected {
+                                stream.deadline = None;
+                                self.ready.push_back(id);
+                            }
+                        } else if !rejected {
+                            if !payload.is_empty() {
+                                frame::write_window_update(&mut self.out, id, payload.len() as u32);
+                            }
+                            stream.deadline = stream
+                                .body_since
+                                .map(|v| v + self.cfg.http.body_timeout_of(stream.body_size));
+                        }
+                    }
+                    _ => {
+                        if id > self.last_stream {
+                            return Err(ErrorCode::ProtocolError.into());
+                        }
+                        frame::write_rst_stream(&mut self.out, id, ErrorCode::StreamClosed);
+                    }
+                }
+            }
+            frame::HEADERS => {
+                if id == 0 {
+                    return Err(ErrorCode::ProtocolError.into());
+                }
+                let (mut begin, end) = frame::unpad(&header, payload)?;
+                if header.has(frame::FLAG_PRIORITY) {
+                    begin += 5;
+                    if begin > end {
+                        return Err(ErrorCode::FrameSizeError.into());
+                    }
+                }
+
+                match self.streams.get(&id) {
+                    Some(stream) => {
+                        if !stream.headers_done || stream.remote_closed {
+                            return Err(ErrorCode::ProtocolError.into());
+                        }
+                    }
+                    None => {
+                        self.open_stream(id)?;
+                    }
+                }
+
+                let end_stream = header.has(frame::FLAG_END_STREAM);
+                if header.has(frame::FLAG_END_HEADERS) {
+                    self.finish_headers(id, &payload[begin..end], end_stream)?;
+                } else {
+                    self.continuation = Some((id, payload[begin..end].to_vec(), end_stream));
+                }
+            }
+            frame::CONTINUATION => {
+                let (_, block, _) = self.continuation.as_mut().unwrap(); // checked in `handle`
+                block.extend_from_slice(payload);
+                if block.len() > frame::DEFAULT_MAX_FRAME_SIZE * 8 {
+                    return Err(ErrorCode::EnhanceYourCalm.into());
+                }
+                if header.has(frame::FLAG_END_HEADERS) {
+                    let (id, block, end_stream) = self.continuation.take().unwrap();
+                    self.finish_headers(id, &block, end_stream)?;
+                }
+            }
+            frame::PRIORITY => {
+                if payload.len() != 5 {
+                    return Err(ErrorCode::FrameSizeError.into());
+                }
+            }
+            frame::RST_STREAM => {
+                if id == 0 {
+                    return Err(ErrorCode::ProtocolError.into());
+                }
+                if payload.len() != 4 {
+                    return Err(ErrorCode::FrameSizeError.into());
+                }
+                self.forget(id);
+            }
+            frame::SETTINGS => {
+                if id != 0 {
+                    return Err(ErrorCode::ProtocolError.into());
+                }
+                if header.has(frame::FLAG_ACK) {
+                    if !payload.is_empty() {
+                        return Err(ErrorCode::FrameSizeError.into());
+                    }
+                    return Ok(());
+                }
+                let settings = frame::parse_settings(payload)?;
+                self.apply_settings(&settings)?;
+                frame::write_frame(&mut self.out, frame::SETTINGS, frame::FLAG_ACK, 0, &[]);
+            }
+            frame::PUSH_PROMISE => {
+                return Err(ErrorCode::ProtocolError.into());
+            }
+            frame::PING => {
+                if id != 0 {
+                    return Err(ErrorCode::ProtocolError.into());
+                }
+                if payload.len() != 8 {
+                    return Err(ErrorCode::FrameSizeError.into());
+                }
+                if !header.has(frame::FLAG_ACK) {
+                    frame::write_frame(&mut self.out, frame::PING, frame::FLAG_ACK, 0, payload);
+                }
+            }
+            frame::GOAWAY => {
+                self.goaway = true;
+            }
+            frame::WINDOW_UPDATE => {
+                if payload.len() != 4 {
+                    return Err(ErrorCode::FrameSizeError.into());
+                }
+                let increment =
+                    (u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
+                        & 0x7fff_ffff) as i64;
+                if id == 0 {
+                    if increment == 0 {
+                        return Err(ErrorCode::ProtocolError.into());
+                    }
+                    self.send_window += increment;
+                    if self.send_window > frame::MAX_WINDOW_SIZE {
+                        return Err(ErrorCode::FlowControlError.into());
+                    }
+                    return Ok(());
+                }
+                match self.streams.get_mut(&id) {
+                    Some(stream) => {
+                        stream.send_window += increment;
+                        if increment == 0 {
+                            self.reset(id, ErrorCode::ProtocolError);
+                        } else if stream.send_window > frame::MAX_WINDOW_SIZE {
+                            self.reset(id, ErrorCode::FlowControlError);
+                        }
+                    }
+                    None => {}
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // a response without the service, such as for a timeout or a too large body
+    fn error_response(&self, code: u16, reason: &str) -> Message {
+        let mut resp = Message::default();
+        serve::error_response(self.cfg, &mut resp, code, reason);
+        resp
+    }
+
+    // write the header block of a response, its body goes out as the windows allow, see `pump`
+    fn start_response(&mut self, id: u32, mut resp: Message) -> Result<()> {
+        if !self.streams.contains_key(&id) {
+            return Ok(()); // reset by the peer
+        }
+        resp.body.end()?;
+        let status = if resp.firstline.1.is_empty() {
+            "200"
+        } else {
+            resp.firstline.1.as_str()
+        };
+        let mut block = Vec::with_capacity(256);
+        let encoder = &mut self.encoder;
+        encoder.encode(":status", status, &mut block);
+        resp.headers.each(&mut |k: &str, vs: &Vec<String>| {
+            let k = k.to_ascii_lowercase();
+            if k == "content-length" || CONNECTION_HEADERS.contains(&k.as_str()) {
+                return true;
+            }
+            for v in vs {
+                encoder.encode(&k, v, &mut block);
+            }
+            true
+        });
+        let body_size = resp.body.inner().len();
+        encoder.encode("content-length", body_size.to_string().as_str(), &mut block);
+
+        let mut chunks = block.chunks(self.max_frame_size).peekable();
+        let mut kind = frame::HEADERS;
+        while let Some(chunk) = chunks.next() {
+            let mut flags = 0;
+            if kind == frame::HEADERS && body_size == 0 {
+                flags |= frame::FLAG_END_STREAM;
+            }
+            if chunks.peek().is_none() {
+                flags |= frame::FLAG_END_HEADERS;
+            }
+            frame::write_frame(&mut self.out, kind, flags, id, chunk);
+            kind = frame::CONTINUATION;
+        }
+
+        match self.streams.get_mut(&id) {
+            Some(stream) => {
+                stream.resp = Some(resp);
+                stream.deadline = Some(Instant::now() + self.cfg.http.write_timeout.0);
+                self.sending.push_back(id);
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    // the bodies of the responses, as far as the flow-control windows allow
+    fn pump(&mut self) {
+        let mut idx = 0;
+        while idx < self.sending.len() {
+            let id = self.sending[idx];
+            let stream = match self.streams.get_mut(&id) {
+                Some(stream) => stream,
+                None => {
+                    self.sending.remove(idx);
+                    continue;
+                }
+            };
+            let Stream {
+                resp,
+                sent,
+                send_window,
+                deadline,
+                remote_closed,
+                ..
+            } = stream;
+            let body = match resp.as_ref() {
+                Some(resp) => resp.body.inner(),
+                None => &[],
+            };
+            let before = *sent;
+            while *sent < body.len() {
+                let window = std::cmp::min(*send_window, self.send_window);
+                if window <= 0 {
+                    break;
+                }
+                let size = std::cmp::min(
+                    std::cmp::min(body.len() - *sent, self.max_frame_size),
+                    window as usize,
+                );
+                let mut flags = 0;
+                if *sent + size >= body.len() {
+                    flags |= frame::FLAG_END_STREAM;
+                }
+                frame::write_frame(
+                    &mut self.out,
+                    frame::DATA,
+                    flags,
+                    id,
+                    &body[*sent..*sent + size],
+                );
+                *sent += size;
+                *send_window -= size as i64;
+                self.send_window -= size as i64;
+            }
+            if *sent < body.len() {
+                // waits for WINDOW_UPDATE
+                if *sent > before {
+                    *deadline = Some(Instant::now() + self.cfg.http.write_timeout.0);
+                }
+                idx += 1;
+                continue;
+            }
+
+            // a response before the end of the request, the rest is not wanted. RFC 9113 section 8.1
+            let remote_closed = *remote_closed;
+            self.sending.remove(idx);
+            self.streams.remove(&id);
+            if !remote_closed {
+                frame::write_rst_stream(&mut self.out, id, ErrorCode::NoError);
+            }
+        }
+    }
+
+    // the request headers are read and a body follows
+    fn accept<S: Service>(&mut self, service: &S, sctx: &StreamContext, id: u32) -> Result<()> {
+        let stream = match self.streams.get(&id) {
+            Some(stream) if stream.resp.is_none() => stream,
+            _ => return Ok(()),
+        };
+        let too_large = match stream.req.get_content_length() {
+            Ok(size) => size > self.max_body_size,
+            Err(_) => false,
+        };
+        let resp = if too_large {
+            self.error_response(413, "Content Too Large")
+        } else {
+            let mut resp = Message::default();
+            if service.accept_body(sctx, &stream.req, &mut resp) {
+                return Ok(());
+            }
+            resp
+        };
+        #[cfg(debug_assertions)]
+        {
+            log::trace!("http2 request body rejected, {}, stream {}", sctx.addr, id);
+        }
+        self.start_response(id, resp)
+    }
+
+    // streams past their deadline, the connection fails if a header block is late
+    fn expire(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let expired: Vec<u32> = self
+            .streams
+            .iter()
+            .filter(|(_, v)| v.deadline.is_some_and(|v| v <= now))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            let (headers_done, responding) = match self.streams.get_mut(&id) {
+                Some(stream) => {
+                    stream.deadline = None;
+                    (stream.headers_done, stream.resp.is_some())
+                }
+                None => continue,
+            };
+            if !headers_done {
+                return Err(Error::Io(std::io::ErrorKind::TimedOut.into()));
+            }
+            if responding {
+                // the peer does not open its window
+                self.reset(id, ErrorCode::Cancel);
+                continue;
+            }
+            #[cfg(debug_assertions)]
+            {
+                log::trace!("http2 request body timed out, stream {}", id);
+            }
+            self.accepts.retain(|v| *v != id);
+            let resp = self.error_response(408, "Request Timeout");
+            self.start_response(id, resp)?;
+        }
+        Ok(())
+    }
+
+    async fn run<
+        S: Service + Send + Sync + 'static,
+        R: tokio::io::AsyncBufReadExt + Unpin + Send,
+        W: AsyncWriteExt + Unpin + Send,
+    >(
+        &mut self,
+        service: Arc<S>,
+        ctx: &mut ConnContext<R, W>,
+        upgraded: Option<Message>,
+    ) -> Result<()> {
+        frame::write_settings(
+            &mut self.out,
+            &[
+                (
+                    frame::SETTINGS_MAX_CONCURRENT_STREAMS,
+                    MAX_CONCURRENT_STREAMS,
+                ),
+                (
+                    frame::SETTINGS_MAX_HEADER_LIST_SIZE,
+                    self.headers.size as u32,
+                ),
+            ],
+        );
+        self.flush(&mut ctx.writer).await?;
+
+        let mut preface = [0u8; 24];
+        match tokio::time::timeout(
+            ctx.config.http.header_timeout.0,
+            ctx.reader.read_exact(&mut preface),
+        )
+        .await
+        {
+            Ok(result) => {
+                result?;
+            }
+            Err(_) => return Err(Error::Io(std::io::ErrorKind::TimedOut.into())),
+        }
+        if preface != PREFACE {
+            return Err(ErrorCode::ProtocolError.into());
+        }
+
+        match upgraded {
+            Some(mut req) => {
+                // the upgrade request is stream 1, half-closed(remote)
+                for name in CONNECTION_HEADERS {
+                    req.headers.delete(name);
+                }
+                req.firstline.2.clear();
+                req.firstline.2.push_str("HTTP/2.0");
+                self.last_stream = 1;
+                self.streams.insert(
+                    1,
+                    Stream {
+                        req,
+                        send_window: self.initial_window,
+                        headers_done: true,
+                        remote_closed: true,
+                        ..Default::default()
+                    },
+                );
+                self.ready.push_back(1);
+            }
+            None => {}
+        }
+
+        let mut sctx = ConnContext::new(
+            tokio::io::BufReader::new(tokio::io::empty()),
+            tokio::io::sink(),
+            ctx.addr,
+            ctx.tls.clone(),
+            ctx.config,
+        );
+        sctx.proxy = ctx.proxy.clone();
+        let sctx = Arc::new(sctx);
+        // the streams are served at once, each by a task; they end with the connection
+        let mut handlers: tokio::task::JoinSet<(u32, anyhow::Result<Protocol>, Message)> =
+            tokio::task::JoinSet::new();
+        let mut handled: HashMap<tokio::task::Id, u32> = HashMap::new();
+
+        loop {
+            while let Some(id) = self.accepts.pop_front() {
+                self.accept(service.as_ref(), &sctx, id)?;
+            }
+            while let Some(id) = self.ready.pop_front() {
+                let mut req = match self.streams.get_mut(&id) {
+                    Some(stream) => std::mem::take(&mut stream.req),
+                    None => continue,
+                };
+                let service = service.clone();
+                let sctx = sctx.clone();
+                let handle = handlers.spawn(async move {
+                    let mut resp = Message::default();
+                    if service.multipart() && form::is_multipart(&req) {
+                        match form::read_buffered(&mut req, &sctx.config.http.forms).await {
+                            Ok(_) => {}
+                            Err(e) => {
+                                let (code, reason) = e.status();
+                                serve::error_response(sctx.config, &mut resp, code, reason);
+                                return (id, Ok(Protocol::Current { keep_alive: true }), resp);
+                            }
+                        }
+                    }
+                    let result = service.http(sctx.as_ref(), &mut req, &mut resp).await;
+                    (id, result, resp)
+                });
+                handled.insert(handle.id(), id);
+            }
+            self.pump();
+            if (self.goaway || self.draining) && self.streams.is_empty() {
+                break;
+            }
+            self.flush(&mut ctx.writer).await?;
+
+            // an idle connection is closed, a stream past its deadline is answered or reset
+            let idle = self.streams.is_empty();
+            let deadline = if idle {
+                Some(Instant::now() + ctx.config.http.idle_timeout.0)
+            } else {
+                self.streams.values().filter_map(|v| v.deadline).min()
+            };
+            tokio::select! {
+                result = ctx.reader.fill_buf() => {
+                    result?;
+                },
+                done = handlers.join_next_with_id(), if !handlers.is_empty() => {
+                    let (id, result, resp) = match done {
+                        Some(Ok((task, done))) => {
+                            handled.remove(&task);
+                            done
+                        }
+                        Some(Err(e)) => {
+                            log::error!(service = ctx.config.name.as_str(); "http2 handler failed, {}", e);
+                            match handled.remove(&e.id()) {
+                                Some(id) if self.streams.contains_key(&id) => {
+                                    self.reset(id, ErrorCode::InternalError);
+                                }
+                                _ => {}
+                            }
+                            continue;
+                        }
+                        None => continue,
+                    };
+                    match result {
+                        Ok(_) => self.start_response(id, resp)?,
+                        Err(e) => {
+                            log::error!(service = ctx.config.name.as_str(); "handle failed, {}", e);
+                            if self.streams.contains_key(&id) {
+                                self.reset(id, ErrorCode::InternalError);
+                            }
+                        }
+                    }
+                    continue;
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    if idle {
+                        #[cfg(debug_assertions)]
+                        {
+                            log::trace!("http2 connection idle, {}", ctx.addr);
+                        }
+                        break;
+                    }
+                    self.expire()?;
+                    continue;
+                },
+                _ = shutdown::wait(), if !self.draining => {
+                    // streams already open may finish, RFC 9113 section 6.8
+                    self.draining = true;
+                    frame::write_goaway(&mut self.out, self.last_stream, ErrorCode::NoError);
+                    continue;
+                }
+            }
+
+            // a frame, once begun, arrives within `header_timeout`
+            let header = match tokio::time::timeout(
+                ctx.config.http.header_timeout.0,
+                self.read_frame(&mut ctx.reader),
+            )
+            .await
+            {
+                Ok(header) => header?,
+                Err(_) => return Err(Error::Io(std::io::ErrorKind::TimedOut.into())),
+            };
+            self.handle(header)?;
+        }
+
+        if !self.draining {
+            frame::write_goaway(&mut self.out, self.last_stream, ErrorCode::NoError);
+        }
+        self.flush(&mut ctx.writer).await
+    }
+}
+
+pub(crate) async fn serve<
+    S: Service + Send + Sync + 'static,
+    R: tokio::io::AsyncBufReadExt + Unpin + Send,
+    W: AsyncWriteExt + Unpin + Send,
+>(
+    service: Arc<S>,
+    mut ctx: ConnContext<R, W>,
+    upgraded: Option<Message>,
+) {
+    let mut conn = Conn::new(&ctx);
+
+    match upgraded.as_ref() {
+        Some(req) => match req
+            .headers
+            .get("http2-settings")
+            .map(|v| decode_settings(v))
+        {
+            Some(Some(settings)) => {
+                if conn.apply_settings(&settings).is_err() {
+                    return;
+                }
+            }
+            _ => return,
+        },
+        None => {}
+    }
+
+    match conn.run(service, &mut ctx, upgraded).await {
+        Ok(_) => {}
+        Err(Error::Io(e)) => {
+            #[cfg(debug_assertions)]
+            {
+                log::trace!("http2 connection closed, {}, {}", ctx.addr, e);
+            }
+        }
+        Err(Error::Conn(code)) => {
+            #[cfg(debug_assertions)]
+            {
+                log::trace!("http2 connection error, {}, {:?}", ctx.addr, code);
+            }
+            frame::write_goaway(&mut conn.out, conn.last_stream, code);
+            _ = conn.flush(&mut ctx.writer).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf, WriteHalf};
+
+    use super::*;
+
+    // answers with the path of the request, `/slow` late; the body of `/reject` is refused
+    struct Paths(&'static ServiceConfig);
+
+    impl Service for Paths {
+        fn config(&self) -> &'static ServiceConfig {
+            self.0
+        }
+
+        async fn init(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn http<
+            R: tokio::io::AsyncBufReadExt + Unpin + Send,
+            W: tokio::io::AsyncWriteExt + Unpin + Send,
+        >(
+            &self,
+            _ctx: &ConnContext<R, W>,
+            req: &mut Message,
+            resp: &mut Message,
+        ) -> impl std::future::Future<Output = anyhow::Result<Protocol>> + Send {
+            let path = req.firstline.1.clone();
+            ResponseWriter::from(&mut *resp)
+                .version(1, 1)
+                .code(200, "OK");
+            resp.body.write_all_to_internal(path.as_bytes());
+            async move {
+                if path == "/slow" {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                }
+                Ok(Protocol::Current { keep_alive: true })
+            }
+        }
+
+        fn accept_body<
+            R: tokio::io::AsyncBufReadExt + Unpin + Send,
+            W: tokio::io::AsyncWriteExt + Unpin + Send,
+        >(
+            &self,
+            _ctx: &ConnContext<R, W>,
+            req: &Message,
+            resp: &mut Message,
+        ) -> bool {
+            if req.firstline.1 != "/reject" {
+                return true;
+            }
+            ResponseWriter::from(resp)
+                .version(1, 1)
+                .code(403, "Forbidden");
+            false
+        }
+    }
+
+    struct Client {
+        r: BufReader<ReadHalf<DuplexStream>>,
+        w: WriteHalf<DuplexStream>,
+        decoder: hpack::Decoder,
+        // RST_STREAM seen while reading responses
+        resets: Vec<(u32, Vec<u8>)>,
+    }
+
+    impl Client {
+        fn connect(timeouts: Option<Duration>) -> Self {
+            let mut cfg = ServiceConfig::default();
+            cfg.http.autofix(None).unwrap();
+            cfg.tcp.autofix(None).unwrap();
+            cfg.http.h2c = Some(true);
+            cfg.http.max_body_size = crate::config::bytes_size::BytesSize(100);
+            match timeouts {
+                Some(v) => {
+                    cfg.http.idle_timeout.0 = v;
+                    cfg.http.header_timeout.0 = v;
+                    cfg.http.body_timeout.0 = v;
+                }
+                None => {}
+            }
+            let service = Arc::new(Paths(Box::leak(Box::new(cfg))));
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let (r, w) = tokio::io::split(server);
+            let addr: std::net::SocketAddr = "127.0.0.1:80".parse().unwrap();
+            tokio::spawn(crate::serve::serve(service, r, w, addr.into(), None, None));
+            let (r, w) = tokio::io::split(client);
+            Self {
+                r: BufReader::new(r),
+                w,
+                decoder: hpack::Decoder::new(HEADER_TABLE_SIZE),
+                resets: vec![],
+            }
+        }
+
+        async fn send(&mut self, raw: &[u8]) {
+            self.w.write_all(raw).await.unwrap();
+        }
+
+        async fn start(&mut self) {
+            let mut raw = PREFACE.to_vec();
+            frame::write_settings(&mut raw, &[]);
+            self.send(&raw).await;
+        }
+
+        // `None` once the server closes
+        async fn frame(&mut self) -> Option<(FrameHeader, Vec<u8>)> {
+            let header = FrameHeader::read(&mut self.r).await.ok()?;
+            let mut payload = vec![0u8; header.len];
+            self.r.read_exact(&mut payload).await.ok()?;
+            Some((header, payload))
+        }
+
+        // the status and the body of each stream, in the order the streams end
+        async fn responses(&mut self, count: usize) -> Vec<(u32, String, String)> {
+            let mut open: HashMap<u32, (String, String)> = HashMap::new();
+            let mut done = vec![];
+            while done.len() < count {
+                let (header, payload) = self.frame().await.unwrap();
+                match header.kind {
+                    frame::HEADERS => {
+                        let mut status = String::new();
+                        let any = hpack::Budget {
+                            fields: usize::MAX,
+                            size: usize::MAX,
+                        };
+                        self.decoder
+                            .decode(&payload, &any, &mut |k, v| {
+                                if k == ":status" {
+                                    status = v.to_string();
+                                }
+                            })
+                            .unwrap();
+                        open.insert(header.stream, (status, String::new()));
+                    }
+                    frame::DATA => {
+                        let entry = open.get_mut(&header.stream).unwrap();
+                        entry.1.push_str(std::str::from_utf8(&payload).unwrap());
+                    }
+                    frame::RST_STREAM => {
+                        self.resets.push((header.stream, payload));
+                        continue;
+                    }
+                    _ => continue,
+                }
+                if header.has(frame::FLAG_END_STREAM) {
+                    let (status, body) = open.remove(&header.stream).unwrap();
+                    done.push((header.stream, status, body));
+                }
+            }
+            done
+        }
+    }
+
+    fn request(id: u32, method: &str, path: &str, headers: &[(&str, &str)], end: bool) -> Vec<u8> {
+        let mut encoder = hpack::Encoder;
+        let mut block = vec![];
+        encoder.encode(":method", method, &mut block);
+        encoder.encode(":scheme", "http", &mut block);
+        encoder.encode(":path", path, &mut block);
+        encoder.encode(":authority", "localhost", &mut block);
+        for (k, v) in headers {
+            encoder.encode(k, v, &mut block);
+        }
+        let mut flags = frame::FLAG_END_HEADERS;
+        if end {
+            flags |= frame::FLAG_END_STREAM;
+        }
+        let mut raw = vec![];
+        frame::write_frame(&mut raw, frame::HEADERS, flags, id, &block);
+        raw
+    }
+
+    fn run(test: impl std::future::Future<Output = ()>) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            tokio::time::timeout(Duration::from_secs(5), test)
+                .await
+                .unwrap()
+        });
+    }
+
+    #[test]
+    fn test_prior_knowledge() {
+        run(async {
+            let mut client = Client::connect(None);
+            // a preface split across reads is still told from http1
+            client.send(&PREFACE[..3]).await;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let mut raw = PREFACE[3..].to_vec();
+            frame::write_settings(&mut raw, &[]);
+            raw.extend(request(1, "GET", "/slow", &[], true));
+            raw.extend(request(3, "GET", "/fast", &[], true));
+            client.send(&raw).await;
+
+            // the streams are served at once, the fast one ends first
+            assert_eq!(
+                client.responses(2).await,
+                vec![
+                    (3, "200".to_string(), "/fast".to_string()),
+                    (1, "200".to_string(), "/slow".to_string())
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_frames() {
+        run(async {
+            let mut client = Client::connect(None);
+            client.start().await;
+            let mut raw = vec![];
+            frame::write_frame(&mut raw, frame::PING, 0, 0, b"12345678");
+            client.send(&raw).await;
+
+            let (mut settings_ack, mut pong) = (false, false);
+            while !(settings_ack && pong) {
+                let (header, payload) = client.frame().await.unwrap();
+                match header.kind {
+                    frame::SETTINGS if header.has(frame::FLAG_ACK) => settings_ack = true,
+                    frame::PING => {
+                        assert!(header.has(frame::FLAG_ACK));
+                        assert_eq!(payload, b"12345678");
+                        pong = true;
+                    }
+                    _ => {}
+                }
+            }
+
+            // a stream id must grow, a connection error
+            client.send(&request(5, "GET", "/a", &[], true)).await;
+            assert_eq!(client.responses(1).await[0].2, "/a");
+            client.send(&request(3, "GET", "/b", &[], true)).await;
+            loop {
+                match client.frame().await {
+                    Some((header, payload)) if header.kind == frame::GOAWAY => {
+                        assert_eq!(payload[7], ErrorCode::ProtocolError as u8);
+                        break;
+                    }
+                    Some(_) => {}
+                    None => panic!("closed without GOAWAY"),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_accept_body() {
+        run(async {
+            let mut client = Client::connect(None);
+            client.start().await;
+            let mut raw = request(1, "POST", "/reject", &[("content-length", "4")], false);
+            raw.extend(request(
+                3,
+                "POST",
+                "/big",
+                &[("content-length", "101")],
+                false,
+            ));
+            raw.extend(request(5, "POST", "/ok", &[("content-length", "4")], false));
+            frame::write_frame(&mut raw, frame::DATA, frame::FLAG_END_STREAM, 5, b"body");
+            client.send(&raw).await;
+
+            let mut got = client.responses(3).await;
+            got.sort();
+            assert_eq!(
+                got.iter().map(|v| (v.0, v.1.as_str())).collect::<Vec<_>>(),
+                vec![(1, "403"), (3, "413"), (5, "200")]
+            );
+            // the bodies not wanted are stopped
+            client.resets.sort();
+            assert_eq!(
+                client.resets,
+                vec![(1, vec![0, 0, 0, 0]), (3, vec![0, 0, 0, 0])]
+            );
+        });
+    }
+
+    #[test]
+    fn test_header_list_size() {
+        run(async {
+            let mut client = Client::connect(None);
+            client.start().await;
+            let mut encoder = hpack::Encoder;
+            let mut block = vec![];
+            encoder.encode(":method", "GET", &mut block);
+            encoder.encode(":scheme", "http", &mut block);
+            encoder.encode(":path", "/bomb", &mut block);
+            encoder.encode(":authority", "localhost", &mut block);
+            // a 4000 octets entry added to the table, then referenced over and over
+            block.extend([0x40, 1, b'x', 0x7f, 0xa1, 0x1e]);
+            block.extend([b'v'; 4000]);
+            block.extend([0xbe; 200]);
+            let mut raw = vec![];
+            frame::write_frame(
+                &mut raw,
+                frame::HEADERS,
+                frame::FLAG_END_HEADERS | frame::FLAG_END_STREAM,
+                1,
+                &block,
+            );
+            // the table is still in sync, the entry can be used once
+            let mut block = vec![];
+            encoder.encode(":method", "GET", &mut block);
+            encoder.encode(":scheme", "http", &mut block);
+            encoder.encode(":path", "/ok", &mut block);
+            encoder.encode(":authority", "localhost", &mut block);
+            block.push(0xbe);
+            frame::write_frame(
+                &mut raw,
+                frame::HEADERS,
+                frame::FLAG_END_HEADERS | frame::FLAG_END_STREAM,
+                3,
+                &block,
+            );
+            client.send(&raw).await;
+
+            let got = client.responses(1).await;
+            assert_eq!(got, vec![(3, "200".to_string(), "/ok".to_string())]);
+            assert_eq!(client.resets, vec![(1, vec![0, 0, 0, 1])]);
+        });
+    }
+
+    #[test]
+    fn test_upgrade() {
+        run(async {
+            let mut client = Client::connect(None);
+            client
+                .send(
+                    b"GET /up HTTP/1.1\r\nhost: localhost\r\nconnection: Upgrade, HTTP2-Settings\r\n\
+                    upgrade: h2c\r\nhttp2-settings: AAMAAABkAAQAAP__\r\n\r\n",
+                )
+                .await;
+            let mut head = vec![];
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(client.r.read_u8().await.unwrap());
+            }
+            assert!(head.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+
+            client.start().await;
+            assert_eq!(
+                client.responses(1).await,
+                vec![(1, "200".to_string(), "/up".to_string())]
+            );
+        });
+    }
+
+    #[test]
+    fn test_timeouts() {
+        run(async {
+            // an idle connection gets GOAWAY
+            let mut client = Client::connect(Some(Duration::from_millis(100)));
+            client.start().await;
+            let mut goaway = false;
+            while let Some((header, _)) = client.frame().await {
+                goaway |= header.kind == frame::GOAWAY;
+            }
+            assert!(goaway);
+
+            // a preface never finished, and a body never sent
+            let mut client = Client::connect(Some(Duration::from_millis(100)));
+            client.send(&PREFACE[..8]).await;
+            assert!(client.frame().await.is_none());
+
+            let mut client = Client::connect(Some(Duration::from_millis(100)));
+            client.start().await;
+            client
+                .send(&request(1, "POST", "/a", &[("content-length", "4")], false))
+                .await;
+            assert_eq!(client.responses(1).await[0].1, "408");
+        });
+    }
+}
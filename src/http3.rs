@@ -0,0 +1,360 @@
+use std::{net::ToSocketAddrs, sync::Arc};
+
+use bytes::Buf;
+
+use tokio_rustls::rustls::pki_types::CertificateDer;
+
+use crate::{
+    ctx::ConnContext,
+    form,
+    http2::CONNECTION_HEADERS,
+    message::Message,
+    protocols::Protocol,
+    serve,
+    services::common::Service,
+    shutdown,
+    tls::{ClientCert, TlsInfo},
+    upgrade,
+    utils::anyhow,
+};
+
+/// the udp side of a service, it outlives the generations of the service config so a
+/// reload keeps the socket and its connections.
+pub(crate) struct Listener {
+    socket: Option<std::net::UdpSocket>,
+    endpoint: Option<(quinn::Endpoint, upgrade::Handover)>,
+}
+
+impl Listener {
+    pub(crate) fn new(socket: Option<std::net::UdpSocket>) -> Self {
+        Self {
+            socket,
+            endpoint: None,
+        }
+    }
+
+    fn listen(
+        &mut self,
+        name: &str,
+        addr: &str,
+        server_config: quinn::ServerConfig,
+    ) -> anyhow::Result<quinn::Endpoint> {
+        let addrs: Vec<std::net::SocketAddr> = anyhow::result(addr.to_socket_addrs())?.collect();
+        match self.endpoint.as_mut() {
+            Some((endpoint, handover)) => {
+                if !addrs.contains(&anyhow::result(endpoint.local_addr())?) {
+                    let socket = upgrade::udp_socket(name, addr)?;
+                    *handover = upgrade::hand_over_udp(name, &socket)?;
+                    anyhow::result(endpoint.rebind(socket))?;
+                }
+                endpoint.set_server_config(Some(server_config));
+                Ok(endpoint.clone())
+            }
+            None => {
+                let socket = match self.socket.take() {
+                    Some(v) => v,
+                    None => upgrade::udp_socket(name, addr)?,
+                };
+                let handover = upgrade::hand_over_udp(name, &socket)?;
+                let runtime = anyhow::option(quinn::default_runtime(), "no async runtime")?;
+                let endpoint = anyhow::result(quinn::Endpoint::new(
+                    quinn::EndpointConfig::default(),
+                    Some(server_config),
+                    socket,
+                    runtime,
+                ))?;
+                self.endpoint = Some((endpoint.clone(), handover));
+                Ok(endpoint)
+            }
+        }
+    }
+
+    /// refuse new connections, the config has no quic any more.
+    pub(crate) fn pause(&self) {
+        match self.endpoint.as_ref() {
+            Some((endpoint, _)) => endpoint.set_server_config(None),
+            None => {}
+        }
+    }
+
+    /// connections got GOAWAY, let them finish before closing the socket.
+    pub(crate) async fn close(self) {
+        match self.endpoint {
+            Some((endpoint, _)) => {
+                endpoint.set_server_config(None);
+                endpoint.wait_idle().await;
+                endpoint.close(quinn::VarInt::from_u32(0), b"shutdown");
+            }
+            None => {}
+        }
+    }
+}
+
+/// serve a generation of the service on the `listener`, stops accepting on shutdown.
+pub(crate) async fn serve(
+    service: Arc<impl Service + Send + Sync + 'static>,
+    mut tlscfg: tokio_rustls::rustls::ServerConfig,
+    scope: &shutdown::Scope,
+    listener: &mut Listener,
+) -> anyhow::Result<()> {
+    let cfg = service.config();
+    let quic = anyhow::option(cfg.quic.as_ref(), "empty quic config")?;
+
+    tlscfg.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = anyhow::result(quinn::crypto::rustls::QuicServerConfig::try_from(tlscfg))?;
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_idle_timeout(Some(anyhow::result(quinn::IdleTimeout::try_from(
+        quic.idle_timeout.0,
+    ))?));
+    server_config.transport_config(Arc::new(transport));
+
+    let endpoint = listener.listen(&cfg.name, &quic.addr, server_config)?;
+    println!("httpd: listening @ {}(udp), http3 ✅", quic.addr);
+
+    loop {
+        tokio::select! {
+            incoming = endpoint.accept() => {
+                match incoming {
+                    Some(incoming) => {
+                        let service = service.clone();
+                        scope.spawn(async move {
+                            serve_conn(service, incoming).await;
+                        });
+                    },
+                    None => {
+                        break;
+                    },
+                }
+            },
+            _ = shutdown::wait() => {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn serve_conn(service: Arc<impl Service + Send + Sync + 'static>, incoming: quinn::Incoming) {
+    let _guard = shutdown::ConnGuard::new();
+    let conn = match incoming.await {
+        Ok(conn) => conn,
+        Err(e) => {
+            #[cfg(debug_assertions)]
+            {
+                log::trace!("quic handshake failed, {}", e);
+            }
+            return;
+        }
+    };
+    let addr = conn.remote_address();
+    let info = TlsInfo {
+        sni: conn
+            .handshake_data()
+            .and_then(|v| v.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+            .and_then(|v| v.server_name),
+        client_cert: conn
+            .peer_identity()
+            .and_then(|v| v.downcast::<Vec<CertificateDer<'static>>>().ok())
+            .and_then(|v| ClientCert::parse(Some(v.as_slice()))),
+    };
+
+    let cfg = service.config();
+    let mut h3conn: h3::server::Connection<h3_quinn::Connection, bytes::Bytes> =
+        match h3::server::builder()
+            .max_field_section_size(
+                cfg.http.max_header_line_size.u64() * cfg.http.max_headers_count as u64,
+            )
+            .build(h3_quinn::Connection::new(conn))
+            .await
+        {
+            Ok(h3conn) => h3conn,
+            Err(e) => {
+                #[cfg(debug_assertions)]
+                {
+                    log::trace!("http3 connection failed, {}, {}", addr, e);
+                }
+                return;
+            }
+        };
+
+    let mut draining = false;
+    loop {
+        let accepted = tokio::select! {
+            v = h3conn.accept() => Some(v),
+            _ = shutdown::wait(), if !draining => None,
+        };
+        let accepted = match accepted {
+            Some(v) => v,
+            None => {
+                // GOAWAY, requests already accepted may finish
+                draining = true;
+                if h3conn.shutdown(0).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        match accepted {
+            Ok(Some(resolver)) => {
+                let service = service.clone();
+                let info = info.clone();
+                shutdown::spawn(async move {
+                    let _guard = shutdown::ConnGuard::new();
+                    let (req, stream) = match resolver.resolve_request().await {
+                        Ok(v) => v,
+                        Err(e) => {
+                            #[cfg(debug_assertions)]
+                            {
+                                log::trace!("read http3 request failed, {}, {}", addr, e);
+                            }
+                            return;
+                        }
+                    };
+                    handle(service.as_ref(), addr, info, req, stream).await;
+                });
+            }
+            Ok(None) => {
+                break;
+            }
+            Err(e) => {
+                #[cfg(debug_assertions)]
+                {
+                    log::trace!("http3 connection closed, {}, {}", addr, e);
+                }
+                break;
+            }
+        }
+    }
+}
+
+async fn handle(
+    service: &impl Service,
+    addr: std::net::SocketAddr,
+    info: TlsInfo,
+    req: http::Request<()>,
+    mut stream: h3::server::RequestStream<h3_quinn::BidiStream<bytes::Bytes>, bytes::Bytes>,
+) {
+    let cfg = service.config();
+    // services are written against a byte stream, there is none for a http3 request
+    let ctx = ConnContext::new(
+        tokio::io::BufReader::new(tokio::io::empty()),
+        tokio::io::sink(),
+        addr.into(),
+        Some(info),
+        cfg,
+    );
+
+    let mut reqmsg = Message::default();
+    reqmsg.firstline.0.push_str(req.method().as_str());
+    match req.uri().path_and_query() {
+        Some(pq) => reqmsg.firstline.1.push_str(pq.as_str()),
+        None => reqmsg.firstline.1.push('/'),
+    }
+    reqmsg.firstline.2.push_str("HTTP/3.0");
+    for (k, v) in req.headers() {
+        match v.to_str() {
+            Ok(v) => reqmsg.headers.append(k.as_str(), v),
+            Err(_) => {
+                stream.stop_stream(h3::error::Code::H3_MESSAGE_ERROR);
+                return;
+            }
+        }
+    }
+    match req.uri().authority() {
+        Some(authority) => {
+            if reqmsg.headers.get("host").is_none() {
+                reqmsg.headers.set("host", authority.as_str());
+            }
+        }
+        None => {}
+    }
+
+    let mut size: usize = 0;
+    loop {
+        match stream.recv_data().await {
+            Ok(Some(mut chunk)) => {
+                size += chunk.remaining();
+                if size > cfg.http.max_body_size.0 {
+                    stream.stop_sending(h3::error::Code::H3_REQUEST_REJECTED);
+                    return;
+                }
+                while chunk.has_remaining() {
+                    let n = chunk.chunk().len();
+                    reqmsg.body.write_all_to_internal(chunk.chunk());
+                    chunk.advance(n);
+                }
+            }
+            Ok(None) => {
+                break;
+            }
+            Err(_) => {
+                return;
+            }
+        }
+    }
+
+    let mut respmsg = Message::default();
+    let result = if service.multipart() && form::is_multipart(&reqmsg) {
+        form::read_buffered(&mut reqmsg, &cfg.http.forms).await
+    } else {
+        Ok(())
+    };
+    let result = match result {
+        Ok(_) => service.http(&ctx, &mut reqmsg, &mut respmsg).await,
+        Err(e) => {
+            let (code, reason) = e.status();
+            serve::error_response(cfg, &mut respmsg, code, reason);
+            Ok(Protocol::Current { keep_alive: true })
+        }
+    };
+    match result {
+        Ok(_) => {}
+        Err(e) => {
+            log::error!(service = cfg.name.as_str(); "handle failed, {}", e);
+            stream.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+            return;
+        }
+    }
+
+    if respmsg.body.end().is_err() {
+        stream.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+        return;
+    }
+    let mut builder =
+        http::Response::builder().status(respmsg.firstline.1.parse::<u16>().unwrap_or(200));
+    respmsg.headers.each(&mut |k: &str, vs: &Vec<String>| {
+        let k = k.to_ascii_lowercase();
+        if k == "content-length" || CONNECTION_HEADERS.contains(&k.as_str()) {
+            return true;
+        }
+        for v in vs {
+            builder = std::mem::take(&mut builder).header(k.as_str(), v.as_str());
+        }
+        true
+    });
+    let body = respmsg.body.inner();
+    builder = builder.header("content-length", body.len());
+
+    let resp = match builder.body(()) {
+        Ok(resp) => resp,
+        Err(e) => {
+            log::error!(service = cfg.name.as_str(); "bad http3 response, {}", e);
+            stream.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+            return;
+        }
+    };
+    if stream.send_response(resp).await.is_err() {
+        return;
+    }
+    if !body.is_empty()
+        && stream
+            .send_data(bytes::Bytes::copy_from_slice(body))
+            .await
+            .is_err()
+    {
+        return;
+    }
+    _ = stream.finish().await;
+}
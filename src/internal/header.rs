@@ -1,9 +1,37 @@
+/// a character of a token such as a method, a field name or a cookie name, RFC 9110 section
+/// 5.6.2.
+pub fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// the lowercased elements of a comma separated header, every value of it in order.
+pub fn tokens(ovs: Option<&Vec<String>>) -> Vec<String> {
+    let mut tokens = vec![];
+    match ovs {
+        Some(vs) => {
+            for v in vs.iter() {
+                for token in v.split(',') {
+                    let token = token.trim();
+                    if !token.is_empty() {
+                        tokens.push(token.to_ascii_lowercase());
+                    }
+                }
+            }
+        }
+        None => {}
+    }
+    tokens
+}
+
+/// whether a comma separated header has the element `target`, ignoring case.
 pub fn contains(ovs: Option<&Vec<String>>, target: &str) -> bool {
     match ovs {
         Some(vs) => {
             for v in vs.iter() {
-                if v == target || v.contains(target) {
-                    return true;
+                for token in v.split(',') {
+                    if token.trim().eq_ignore_ascii_case(target) {
+                        return true;
+                    }
                 }
             }
             false
@@ -11,3 +39,21 @@ pub fn contains(ovs: Option<&Vec<String>>, target: &str) -> bool {
         None => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contains() {
+        let vs = vec!["gzip, Chunked".to_string()];
+        assert!(contains(Some(&vs), "chunked"));
+        assert!(!contains(Some(&vec!["xchunked".to_string()]), "chunked"));
+        assert!(!contains(Some(&vec!["chunked-x".to_string()]), "chunked"));
+        assert!(!contains(None, "chunked"));
+        assert_eq!(
+            tokens(Some(&vec!["a, ,B".to_string(), "c".to_string()])),
+            vec!["a", "b", "c"]
+        );
+    }
+}
@@ -1,2 +1,3 @@
 pub mod header;
 pub mod multi_map;
+pub mod uri;
@@ -197,6 +197,24 @@ impl MultiMap {
         None
     }
 
+    /// the value appended last for `k`.
+    pub fn last_mut(&mut self, k: &str) -> Option<&mut String> {
+        if self.ismap {
+            return self
+                .map
+                .as_mut()
+                .unwrap()
+                .get_mut(k)
+                .and_then(|vs| vs.last_mut());
+        }
+        for (key, vs) in self.vec.iter_mut() {
+            if key == k {
+                return vs.last_mut();
+            }
+        }
+        None
+    }
+
     pub fn get(&self, k: &str) -> Option<&String> {
         match self.getall(k) {
             Some(vs) => vs.first(),
@@ -245,6 +263,11 @@ impl MultiMap {
         }
         self.vec.len()
     }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,318 @@
+use super::multi_map::MultiMap;
+
+/// the form of a request target, RFC 9112 section 3.2.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UriForm {
+    // `/path?query`
+    Origin,
+    // `http://host/path?query`, sent to proxies
+    Absolute,
+    // `host:port`, only for `CONNECT`
+    Authority,
+    // `*`, only for `OPTIONS`
+    Asterisk,
+}
+
+/// a parsed request target. `path` is percent-decoded with its dot-segments removed, so
+/// it never leaves the root; `rawpath` and `rawquery` are as sent.
+#[derive(Debug)]
+pub struct Uri {
+    pub form: UriForm,
+    // lowercased, absolute-form only
+    pub scheme: String,
+    // lowercased, empty in origin-form, without the brackets of an ipv6 literal
+    pub host: String,
+    pub port: Option<u16>,
+    pub path: String,
+    pub rawpath: String,
+    pub query: MultiMap,
+    pub rawquery: String,
+    // not sent by conforming clients
+    pub fragment: String,
+}
+
+fn unhex(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// percent-decode `v`, `plus` for `+` as space in a query or a form. `Err` for a bad escape,
+/// a NUL or bytes that are not utf-8.
+pub(crate) fn decode(v: &str, plus: bool) -> Result<String, ()> {
+    if !v.bytes().any(|b| b == b'%' || (plus && b == b'+')) {
+        return Ok(v.to_string());
+    }
+    let bytes = v.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'%' => {
+                if idx + 2 >= bytes.len() {
+                    return Err(());
+                }
+                let b = match (unhex(bytes[idx + 1]), unhex(bytes[idx + 2])) {
+                    (Some(h), Some(l)) => h << 4 | l,
+                    _ => return Err(()),
+                };
+                if b == 0 {
+                    return Err(());
+                }
+                out.push(b);
+                idx += 3;
+            }
+            b'+' if plus => {
+                out.push(b' ');
+                idx += 1;
+            }
+            b => {
+                out.push(b);
+                idx += 1;
+            }
+        }
+    }
+    String::from_utf8(out).map_err(|_| ())
+}
+
+/// the pairs of a query or an `application/x-www-form-urlencoded` body, in order. a name
+/// without `=` has an empty value, empty names are dropped.
+pub(crate) fn parse_query(v: &str, dest: &mut MultiMap) -> Result<(), ()> {
+    for pair in v.split('&') {
+        let (k, v) = match pair.split_once('=') {
+            Some(kv) => kv,
+            None => (pair, ""),
+        };
+        if k.is_empty() {
+            continue;
+        }
+        dest.append(&decode(k, true)?, &decode(v, true)?);
+    }
+    Ok(())
+}
+
+/// RFC 3986 section 5.2.4, on a path starting with `/`.
+pub fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = vec![];
+    let mut parts = path.split('/').skip(1).peekable();
+    while let Some(seg) = parts.next() {
+        match seg {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => {
+                segments.push(seg);
+                continue;
+            }
+        }
+        // a path ending with a dot-segment names a directory
+        if parts.peek().is_none() {
+            segments.push("");
+        }
+    }
+    let mut out = String::with_capacity(path.len());
+    for seg in segments.iter() {
+        out.push('/');
+        out.push_str(seg);
+    }
+    if out.is_empty() {
+        out.push('/');
+    }
+    out
+}
+
+// `host[:port]`, without userinfo, RFC 9110 section 4.2.4
+fn parse_authority(v: &str) -> Result<(String, Option<u16>), ()> {
+    if v.is_empty() || v.contains('@') {
+        return Err(());
+    }
+    let (host, port) = if v.starts_with('[') {
+        match v.find(']') {
+            Some(end) => {
+                let host = &v[1..end];
+                if host.parse::<std::net::Ipv6Addr>().is_err() {
+                    return Err(());
+                }
+                match &v[end + 1..] {
+                    "" => (host, None),
+                    rest => match rest.strip_prefix(':') {
+                        Some(port) => (host, Some(port)),
+                        None => return Err(()),
+                    },
+                }
+            }
+            None => return Err(()),
+        }
+    } else {
+        let (host, port) = match v.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (v, None),
+        };
+        if host.is_empty()
+            || !host
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-._~%!$&'()*+,;=".contains(&b))
+        {
+            return Err(());
+        }
+        (host, port)
+    };
+    let port = match port {
+        Some(port) => {
+            if port.is_empty() || !port.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(());
+            }
+            Some(port.parse::<u16>().map_err(|_| ())?)
+        }
+        None => None,
+    };
+    Ok((host.to_ascii_lowercase(), port))
+}
+
+impl Uri {
+    /// parse the request target of a request with `method`.
+    pub(crate) fn parse(method: &str, target: &str) -> Result<Self, ()> {
+        let mut uri = Self {
+            form: UriForm::Origin,
+            scheme: String::new(),
+            host: String::new(),
+            port: None,
+            path: String::new(),
+            rawpath: String::new(),
+            query: MultiMap::new(),
+            rawquery: String::new(),
+            fragment: String::new(),
+        };
+        if target.is_empty() || !target.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(());
+        }
+
+        if method == "CONNECT" {
+            let (host, port) = parse_authority(target)?;
+            if port.is_none() {
+                return Err(());
+            }
+            uri.form = UriForm::Authority;
+            uri.host = host;
+            uri.port = port;
+            return Ok(uri);
+        }
+        if target == "*" {
+            uri.form = UriForm::Asterisk;
+            uri.path.push('*');
+            uri.rawpath.push('*');
+            return Ok(uri);
+        }
+
+        let mut rest = target;
+        match rest.split_once('#') {
+            Some((v, fragment)) => {
+                uri.fragment = decode(fragment, false)?;
+                rest = v;
+            }
+            None => {}
+        }
+        match rest.split_once('?') {
+            Some((v, query)) => {
+                uri.rawquery.push_str(query);
+                parse_query(query, &mut uri.query)?;
+                rest = v;
+            }
+            None => {}
+        }
+
+        if !rest.starts_with('/') {
+            let (scheme, hier) = match rest.split_once("://") {
+                Some(v) => v,
+                None => return Err(()),
+            };
+            let mut chars = scheme.bytes();
+            if !chars.next().is_some_and(|b| b.is_ascii_alphabetic())
+                || !chars.all(|b| b.is_ascii_alphanumeric() || b"+-.".contains(&b))
+            {
+                return Err(());
+            }
+            let (authority, path) = match hier.find('/') {
+                Some(idx) => hier.split_at(idx),
+                None => (hier, "/"),
+            };
+            let (host, port) = parse_authority(authority)?;
+            uri.form = UriForm::Absolute;
+            uri.scheme = scheme.to_ascii_lowercase();
+            uri.host = host;
+            uri.port = port;
+            rest = path;
+        }
+
+        uri.rawpath.push_str(rest);
+        uri.path = remove_dot_segments(&decode(rest, false)?);
+        Ok(uri)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_dot_segments() {
+        for (raw, path) in [
+            ("/", "/"),
+            ("/a/b/c/./../../g", "/a/g"),
+            ("/a/b/..", "/a/"),
+            ("/..", "/"),
+            ("/../../a", "/a"),
+            ("/a/./", "/a/"),
+            ("/a//b", "/a//b"),
+            ("/a/.../b", "/a/.../b"),
+        ] {
+            assert_eq!(remove_dot_segments(raw), path, "{}", raw);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let uri = Uri::parse("GET", "/a%20b/../c%2F..%2F..%2Fetc?x=1&y=a+b&x=%3D&flag&=v").unwrap();
+        assert_eq!(uri.form, UriForm::Origin);
+        assert_eq!(uri.path, "/etc");
+        assert_eq!(uri.rawpath, "/a%20b/../c%2F..%2F..%2Fetc");
+        assert_eq!(uri.query.getall("x").unwrap(), &vec!["1", "="]);
+        assert_eq!(uri.query.get("y").unwrap(), "a b");
+        assert_eq!(uri.query.get("flag").unwrap(), "");
+        assert_eq!(uri.query.len(), 3);
+
+        let uri = Uri::parse("GET", "HTTP://Example.com:8080?q#top").unwrap();
+        assert_eq!(uri.form, UriForm::Absolute);
+        assert_eq!(
+            (uri.scheme.as_str(), uri.host.as_str(), uri.port),
+            ("http", "example.com", Some(8080))
+        );
+        assert_eq!((uri.path.as_str(), uri.fragment.as_str()), ("/", "top"));
+
+        let uri = Uri::parse("CONNECT", "[::1]:443").unwrap();
+        assert_eq!(uri.form, UriForm::Authority);
+        assert_eq!((uri.host.as_str(), uri.port), ("::1", Some(443)));
+        assert_eq!(Uri::parse("OPTIONS", "*").unwrap().form, UriForm::Asterisk);
+
+        for (method, raw) in [
+            ("GET", ""),
+            ("GET", "a/b"),
+            ("GET", "/%zz"),
+            ("GET", "/%2"),
+            ("GET", "/a%00"),
+            ("GET", "/%ff"),
+            ("GET", "http://user@a/"),
+            ("GET", "http://a:x/"),
+            ("GET", "1http://a/"),
+            ("CONNECT", "a"),
+            ("CONNECT", "a:99999"),
+            ("CONNECT", "[::1:443"),
+        ] {
+            assert!(Uri::parse(method, raw).is_err(), "{}", raw);
+        }
+    }
+}
@@ -0,0 +1,571 @@
+//! the server as a library: the `httpd` binary runs `main`, the fuzz targets in `fuzz/` call
+//! into `fuzz`, which needs the `fuzzing` feature.
+#![allow(dead_code, unused)]
+
+use std::sync::Arc;
+
+use crate::utils::anyhow;
+use crate::{
+    config::Config,
+    services::{
+        admin::AdminService, common::Service, fs::FsService, helloworld::HelloWorldService,
+    },
+};
+use clap::Parser;
+use config::service::ServiceConfig;
+
+mod compression;
+mod config;
+mod cookie;
+mod ctx;
+mod form;
+mod http2;
+#[cfg(feature = "http3")]
+mod http3;
+pub mod internal;
+mod limits;
+mod listener;
+mod logging;
+mod message;
+mod protocols;
+mod proxy_protocol;
+mod reqr;
+mod respw;
+mod serve;
+mod services;
+mod shutdown;
+mod supervisor;
+mod systemd;
+mod tls;
+mod upgrade;
+mod utils;
+mod ws;
+mod ws_impl;
+
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzz;
+
+const PROGRAM_NAME: &'static str = "
+██╗  ██╗████████╗████████╗██████╗ ██████╗    ██████╗ ███████╗
+██║  ██║╚══██╔══╝╚══██╔══╝██╔══██╗██╔══██╗   ██╔══██╗██╔════╝
+███████║   ██║      ██║   ██████╔╝██║  ██║   ██████╔╝███████╗
+██╔══██║   ██║      ██║   ██╔═══╝ ██║  ██║   ██╔══██╗╚════██║
+██║  ██║   ██║      ██║   ██║     ██████╔╝██╗██║  ██║███████║
+╚═╝  ╚═╝   ╚═╝      ╚═╝   ╚═╝     ╚═════╝ ╚═╝╚═╝  ╚═╝╚══════╝                                                             
+";
+
+#[derive(clap::Parser, Debug)]
+#[command(name = PROGRAM_NAME)]
+#[command(about = "A simple http server", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Args {
+    #[arg(name = "config", default_value = "")]
+    /// config file path(toml)
+    pub file: String,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// write a self-signed certificate and key for development
+    Cert {
+        /// certificate output path
+        #[arg(long, default_value = "cert.pem")]
+        cert: String,
+
+        /// private key output path
+        #[arg(long, default_value = "key.pem")]
+        key: String,
+
+        /// hostnames besides `localhost` and the loopback addresses
+        domains: Vec<String>,
+    },
+}
+
+fn load_config(args: Args) -> anyhow::Result<Config> {
+    #[cfg(debug_assertions)]
+    let args = Args::parse_from(vec!["httpd", "./httpd.toml"]);
+
+    if !args.file.trim().is_empty() {
+        let config = Config::load(&args.file)?;
+        // the only change of the working directory, a reload reads its paths the same way
+        anyhow::result(std::env::set_current_dir(config.workdir()))?;
+        return Ok(config);
+    }
+
+    let mut config = Config::default();
+    config.autofix()?;
+    Ok(config)
+}
+
+#[cfg(feature = "http3")]
+type QuicEndpoint = http3::Listener;
+#[cfg(not(feature = "http3"))]
+type QuicEndpoint = ();
+
+async fn accept_loop(
+    listener: &listener::Listener,
+    tlscfg: Option<tokio_rustls::rustls::ServerConfig>,
+    timeout: std::time::Duration,
+    mut service: impl Service + Send + Sync + 'static,
+    scope: &shutdown::Scope,
+    endpoint: &mut QuicEndpoint,
+) -> anyhow::Result<()> {
+    (service.init().await)?;
+    let service = Arc::new(service);
+
+    #[cfg(feature = "http3")]
+    {
+        if tlscfg.is_some() && service.config().quic.is_some() {
+            let h3cfg = tlscfg.clone().unwrap();
+            let h3 = async {
+                match http3::serve(service.clone(), h3cfg, scope, endpoint).await {
+                    Err(e) => {
+                        log::error!(service = service.config().name.as_str(); "http3 serve error, {:?}", e);
+                    }
+                    _ => {}
+                }
+            };
+            let tcp = tls_accept_loop(listener, tlscfg.unwrap(), timeout, service.clone(), scope);
+            tokio::join!(tcp, h3);
+            return Ok(());
+        }
+        // quic is gone from the reloaded config
+        endpoint.pause();
+    }
+
+    if tlscfg.is_some() {
+        tls_accept_loop(listener, tlscfg.unwrap(), timeout, service, scope).await;
+        return Ok(());
+    }
+
+    let limits = limits::service(&service.config().name);
+    loop {
+        tokio::select! {
+            result = async { limits.room(service.config()).await; listener.accept().await } => {
+                match result {
+                    Ok((mut stream, addr)) => {
+                        let service = service.clone();
+                        let permit = limits.admit(service.config());
+                        scope.spawn(async move {
+                            let (addr, proxy) = match proxy_protocol::accept(service.config(), &mut stream, addr).await {
+                                Some(v) => v,
+                                None => return,
+                            };
+                            let (r, w) = tokio::io::split(stream);
+                            let _permit = match permit.and_then(|v| v.client(&addr, service.config())) {
+                                Some(v) => v,
+                                None => return serve::reject(service.config(), r, w, addr).await,
+                            };
+                            serve::serve(service, r, w, addr, None, proxy).await;
+                        });
+                    },
+                    Err(e) => {
+                        #[cfg(debug_assertions)]
+                        {
+                            log::trace!("accept failed, {}", e);
+                        }
+                    },
+                }
+            },
+            _ = shutdown::wait() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn tls_accept_loop(
+    listener: &listener::Listener,
+    tlscfg: tokio_rustls::rustls::ServerConfig,
+    timeout: std::time::Duration,
+    service: Arc<impl Service + Send + Sync + 'static>,
+    scope: &shutdown::Scope,
+) {
+    let acceptor =
+        tls::acme::challenge::TlsAcceptor::new(tlscfg, service.config().tcp.tls.tls_alpn01());
+    let limits = limits::service(&service.config().name);
+    loop {
+        tokio::select! {
+            result = async { limits.room(service.config()).await; listener.accept().await } => {
+                match result {
+                    Err(e) => {
+                        #[cfg(debug_assertions)]
+                        {
+                            log::trace!("accept failed, {}", e);
+                        }
+                    },
+                    Ok((mut stream, addr)) => {
+                        let acceptor = acceptor.clone();
+                        let service = service.clone();
+                        let permit = limits.admit(service.config());
+                        scope.spawn(async move {
+                            // the load balancer passes the tls bytes through after its header
+                            let (addr, proxy) = match proxy_protocol::accept(service.config(), &mut stream, addr).await {
+                                Some(v) => v,
+                                None => return,
+                            };
+                            let handshake_result = match tokio::time::timeout(timeout, acceptor.accept(stream)).await {
+                                Ok(r) => Some(r),
+                                Err(_) => None,
+                            };
+                            match handshake_result {
+                                Some(handshake_result) => {
+                                    match handshake_result {
+                                        Ok(stream) => {
+                                            // a tls-alpn-01 validation ends with the handshake
+                                            if stream.get_ref().1.alpn_protocol() == Some(tls::acme::challenge::ACME_TLS_ALPN) {
+                                                return;
+                                            }
+                                            let info = tls::TlsInfo::from_conn(stream.get_ref().1);
+                                            let (r, w) = tokio::io::split(stream);
+                                            let _permit = match permit.and_then(|v| v.client(&addr, service.config())) {
+                                                Some(v) => v,
+                                                None => return serve::reject(service.config(), r, w, addr).await,
+                                            };
+                                            serve::serve(service, r, w, addr, Some(info), proxy).await;
+                                        },
+                                        Err(e) => {
+                                            #[cfg(debug_assertions)]
+                                            {
+                                                log::trace!("tls handshake failed, {}, {}", addr, e);
+                                            }
+                                        },
+                                    }
+                                },
+                                None => {
+                                    #[cfg(debug_assertions)]
+                                    {
+                                        log::trace!("tls handshake timeout, {}", addr);
+                                    }
+                                },
+                            }
+                        });
+                    },
+                }
+            },
+            _ = shutdown::wait() => {
+                break;
+            }
+        }
+    }
+}
+
+// the tls of a service generation, shared by the runtimes of the service
+type TlsGeneration = Option<(
+    &'static ServiceConfig,
+    anyhow::Result<Option<tokio_rustls::rustls::ServerConfig>>,
+)>;
+type Tls = tokio::sync::watch::Receiver<TlsGeneration>;
+
+/// load the tls of every generation of the service once, with a single acme client and
+/// certificate watcher whose resolver all runtimes share. they stop with the generation.
+async fn keep_tls(
+    config: &'static ServiceConfig,
+    mut updates: supervisor::Updates,
+    tx: tokio::sync::watch::Sender<TlsGeneration>,
+) {
+    let mut config = config;
+    loop {
+        let mut tasks = tokio::task::JoinSet::new();
+        let tlscfg = match config.tcp.tls.load() {
+            Ok(Some((tlscfg, resolver))) => {
+                if config.tcp.tls.acme.is_some() {
+                    tasks.spawn(tls::acme::run(config, resolver.clone()));
+                }
+                tasks.spawn(tls::reload::watch(config, resolver));
+                Ok(Some(tlscfg))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        tx.send_replace(Some((config, tlscfg)));
+        match supervisor::next(&mut updates).await {
+            Some(next) => config = next,
+            None => break,
+        }
+    }
+}
+
+/// serve one generation of the service config on `listener`, until shutdown.
+async fn generation(
+    config: &'static ServiceConfig,
+    listener: &listener::Listener,
+    scope: &shutdown::Scope,
+    endpoint: &mut QuicEndpoint,
+    tls: &mut Tls,
+) -> anyhow::Result<()> {
+    let tlscfg = match tls
+        .wait_for(|v| matches!(v, Some((cfg, _)) if std::ptr::eq(*cfg, config)))
+        .await
+    {
+        Ok(v) => match v.as_ref() {
+            Some((_, tlscfg)) => tlscfg.clone()?,
+            None => None,
+        },
+        Err(_) => return anyhow::error("the tls of the service is gone"),
+    };
+    let mut logo = format!("listening @ {}", config.tcp.addr,);
+    if tlscfg.is_some() {
+        logo = format!("{}, tls ✅", logo);
+    }
+    if config.quic.is_some() {
+        #[cfg(not(feature = "http3"))]
+        {
+            println!(
+                "httpd: service `{}` has a quic config, but built without the `http3` feature",
+                config.name
+            );
+        }
+        if tlscfg.is_none() {
+            println!(
+                "httpd: service `{}` has a quic config, but quic requires tls",
+                config.name
+            );
+        }
+    }
+    println!("httpd: {}, serve as {}", logo, config.service.kind());
+
+    let timeout = config.tcp.tls.timeout.0;
+    match &config.service {
+        config::service::Service::HelloWorld { .. } => {
+            let service = HelloWorldService::new(config);
+            (accept_loop(listener, tlscfg, timeout, service, scope, endpoint).await)?;
+        }
+        config::service::Service::FileSystem { .. } => {
+            let service = FsService::new(config);
+            (accept_loop(listener, tlscfg, timeout, service, scope, endpoint).await)?;
+        }
+        config::service::Service::Admin { .. } => {
+            let service = AdminService::new(config);
+            (accept_loop(listener, tlscfg, timeout, service, scope, endpoint).await)?;
+        }
+        config::service::Service::Forward { .. } => todo!(),
+        config::service::Service::Upstream { .. } => todo!(),
+    };
+
+    Ok(())
+}
+
+async fn run(
+    config: &'static ServiceConfig,
+    tcp: listener::StdListener,
+    udp: Option<std::net::UdpSocket>,
+    mut updates: supervisor::Updates,
+    mut tls: Tls,
+    pending: upgrade::Pending,
+) -> anyhow::Result<()> {
+    let (listener, handover) = tcp.listen(config)?;
+    drop(pending);
+    #[cfg(feature = "http3")]
+    let mut endpoint = http3::Listener::new(udp);
+    #[cfg(not(feature = "http3"))]
+    let mut endpoint = ();
+    let mut config = config;
+
+    loop {
+        let scope = shutdown::Scope::new();
+        let next = tokio::select! {
+            result = generation(config, &listener, &scope, &mut endpoint, &mut tls) => {
+                result?;
+                break;
+            },
+            next = supervisor::next(&mut updates) => next,
+        };
+        match next {
+            // the listener goes on with the new config, the old connections finish as on shutdown
+            Some(next) => {
+                scope.close();
+                config = next;
+            }
+            None => {
+                // a new service may bind the address with SO_REUSEPORT, nothing may queue here
+                drop(handover);
+                drop(listener);
+                if !scope.retire(supervisor::grace()).await {
+                    log::warn!(
+                        "service `{}` removed, force close its remaining connections",
+                        config.name
+                    );
+                }
+                break;
+            }
+        }
+    }
+
+    #[cfg(feature = "http3")]
+    {
+        endpoint.close().await;
+    }
+    Ok(())
+}
+
+fn run_multi_threads(config: &'static Config) -> anyhow::Result<()> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    let mut builder = builder.enable_all();
+    if config.runtime.worker_threads > 0 {
+        builder = builder.worker_threads(config.runtime.worker_threads as usize);
+    }
+
+    let runtime = anyhow::result(builder.build())?;
+
+    runtime.block_on(async {
+        let handle = tokio::runtime::Handle::current();
+        supervisor::start(
+            config,
+            Box::new(move |service, listeners, updates| {
+                spawn(std::slice::from_ref(&handle), service, listeners, updates);
+                Ok(())
+            }),
+        )?;
+        upgrade::ready();
+        systemd::ready();
+        systemd::watchdog(std::slice::from_ref(&tokio::runtime::Handle::current()));
+
+        shutdown::wait().await;
+        drain().await;
+        Ok(())
+    })
+}
+
+// the listeners are closed, give the open connections `shutdown_timeout` to finish
+async fn drain() {
+    if !shutdown::drain(supervisor::grace()).await {
+        log::warn!(
+            "shutdown timeout, force close {} connections",
+            shutdown::conns()
+        );
+    }
+}
+
+// a task per runtime with a listener each, the first one serves http3 and keeps the tls too
+fn spawn(
+    handles: &[tokio::runtime::Handle],
+    service: &'static ServiceConfig,
+    listeners: supervisor::Listeners,
+    updates: supervisor::Updates,
+) {
+    let (tx, tls) = tokio::sync::watch::channel(None);
+    handles[0].spawn(keep_tls(service, updates.clone(), tx));
+
+    let mut udp = listeners.udp;
+    for (handle, tcp) in handles.iter().zip(listeners.tcp) {
+        let udp = udp.take();
+        let updates = updates.clone();
+        let tls = tls.clone();
+        let pending = upgrade::Pending::new();
+        handle.spawn(async move {
+            match run(service, tcp, udp, updates, tls, pending).await {
+                Err(e) => {
+                    log::error!("service serve error, {:?}", e);
+                }
+                _ => {}
+            }
+        });
+    }
+}
+
+fn run_per_core(config: &'static Config) -> anyhow::Result<()> {
+    let cpus = match config.runtime.pin_cpus {
+        true => utils::cpus::allowed(),
+        false => vec![],
+    };
+    if config.runtime.pin_cpus && cpus.is_empty() {
+        log::warn!("pin_cpus is not supported on this system, the runtimes are not pinned");
+    }
+
+    let mut handles = vec![];
+    let mut threads = vec![];
+    for i in 0..config.runtime.runtimes() {
+        let mut builder = tokio::runtime::Builder::new_current_thread();
+        let builder = builder.enable_all();
+        let runtime = anyhow::result(builder.build())?;
+        handles.push(runtime.handle().clone());
+
+        let cpu = match cpus.is_empty() {
+            true => None,
+            false => Some(cpus[i % cpus.len()]),
+        };
+        let builder = std::thread::Builder::new().name(format!("httpd.core:{}", i));
+        let result = builder.spawn(move || {
+            match cpu {
+                Some(cpu) => match utils::cpus::pin(cpu) {
+                    Ok(_) => {}
+                    Err(e) => log::warn!("pin runtime {} to cpu {} failed, {}", i, cpu, e),
+                },
+                None => {}
+            }
+            runtime.block_on(async {
+                shutdown::wait().await;
+                drain().await;
+            });
+        });
+        threads.push(anyhow::result(result)?);
+    }
+
+    let watched = handles.clone();
+    supervisor::start(
+        config,
+        Box::new(move |service, listeners, updates| {
+            spawn(&handles, service, listeners, updates);
+            Ok(())
+        }),
+    )?;
+    upgrade::ready();
+    systemd::ready();
+    systemd::watchdog(&watched);
+
+    for thread in threads {
+        match thread.join() {
+            Err(e) => {
+                log::error!("runtime thread panicked, {:?}", e);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// the entry of the `httpd` binary.
+pub fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    match args.command.as_ref() {
+        Some(Command::Cert { cert, key, domains }) => {
+            tls::selfsigned::write(cert, key, domains)?;
+            println!(
+                "httpd: self-signed certificate written to {} and {}",
+                cert, key
+            );
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let mut config: Config = load_config(args)?;
+    systemd::inherit()?;
+    upgrade::inherit()?;
+    anyhow::result(shutdown::listen_signals())?;
+    let _g = config.logging()?;
+    // a config lives as long as the connections served by it, see `supervisor::reload`
+    let config: &'static Config = Box::leak(Box::new(config));
+
+    println!("{}", PROGRAM_NAME);
+    println!("httpd: load configuration ok, pid: {}", std::process::id());
+    if (config.services.len() < 1) {
+        println!("httpd: empty service, exit");
+        return Ok(());
+    }
+
+    if config.runtime.per_core.is_some() && config.runtime.per_core.unwrap() {
+        run_per_core(config)?;
+    } else {
+        run_multi_threads(config)?;
+    }
+
+    println!("httpd: shutdown");
+    Ok(())
+}
@@ -0,0 +1,286 @@
+//! connection limits of the process, of a service and of a client ip. the counters are shared
+//! by every runtime and kept across reloads, the admin service exposes them at `/metrics`.
+//! quic connections are not counted.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+use crate::{config::service::ServiceConfig, ctx::Peer};
+
+fn lock<T>(v: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match v.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// take one of `max`, zero for no limit
+fn acquire(counter: &AtomicUsize, max: usize) -> bool {
+    counter
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| {
+            if max > 0 && v >= max {
+                return None;
+            }
+            Some(v + 1)
+        })
+        .is_ok()
+}
+
+struct Global {
+    max: AtomicUsize,
+    conns: AtomicUsize,
+    released: tokio::sync::Notify,
+}
+
+fn global() -> &'static Global {
+    static GLOBAL: OnceLock<Global> = OnceLock::new();
+    GLOBAL.get_or_init(|| Global {
+        max: AtomicUsize::new(0),
+        conns: AtomicUsize::new(0),
+        released: tokio::sync::Notify::new(),
+    })
+}
+
+/// the limit of every service together, see `RuntimeConfig::max_conns`.
+pub(crate) fn set_max_conns(max: usize) {
+    global().max.store(max, Ordering::SeqCst);
+}
+
+/// the connections of a service, by its name.
+pub(crate) struct ServiceLimits {
+    conns: AtomicUsize,
+    // admitted by the process and service limits
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    rejected_ip: AtomicU64,
+    // times the listener stopped accepting
+    paused: AtomicU64,
+    ips: Mutex<HashMap<IpAddr, usize>>,
+    released: tokio::sync::Notify,
+}
+
+fn services() -> &'static Mutex<BTreeMap<String, Arc<ServiceLimits>>> {
+    static SERVICES: OnceLock<Mutex<BTreeMap<String, Arc<ServiceLimits>>>> = OnceLock::new();
+    SERVICES.get_or_init(Default::default)
+}
+
+pub(crate) fn service(name: &str) -> Arc<ServiceLimits> {
+    lock(services())
+        .entry(name.to_string())
+        .or_insert_with(|| {
+            Arc::new(ServiceLimits {
+                conns: AtomicUsize::new(0),
+                accepted: AtomicU64::new(0),
+                rejected: AtomicU64::new(0),
+                rejected_ip: AtomicU64::new(0),
+                paused: AtomicU64::new(0),
+                ips: Mutex::new(HashMap::new()),
+                released: tokio::sync::Notify::new(),
+            })
+        })
+        .clone()
+}
+
+impl ServiceLimits {
+    fn full(&self, max: usize) -> bool {
+        let gmax = global().max.load(Ordering::SeqCst);
+        (gmax > 0 && global().conns.load(Ordering::SeqCst) >= gmax)
+            || (max > 0 && self.conns.load(Ordering::SeqCst) >= max)
+    }
+
+    /// resolves once a connection may be accepted, at once unless the policy is `pause`.
+    pub(crate) async fn room(&self, cfg: &ServiceConfig) {
+        let limits = &cfg.tcp.limits;
+        if !limits.pauses() {
+            return;
+        }
+        let mut paused = false;
+        loop {
+            let global_released = global().released.notified();
+            let released = self.released.notified();
+            if !self.full(limits.max_conns) {
+                return;
+            }
+            if !paused {
+                paused = true;
+                self.paused.fetch_add(1, Ordering::Relaxed);
+                #[cfg(debug_assertions)]
+                {
+                    log::trace!(service = cfg.name.as_str(); "connection limit reached, pause accepting");
+                }
+            }
+            tokio::select! {
+                _ = global_released => {},
+                _ = released => {},
+            }
+        }
+    }
+
+    /// count an accepted connection, `None` if the process or the service is at its limit.
+    pub(crate) fn admit(self: &Arc<Self>, cfg: &ServiceConfig) -> Option<Permit> {
+        if !acquire(&global().conns, global().max.load(Ordering::SeqCst)) {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        if !acquire(&self.conns, cfg.tcp.limits.max_conns) {
+            release(&global().conns, &global().released);
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        Some(Permit {
+            limits: self.clone(),
+            ip: None,
+        })
+    }
+}
+
+fn release(counter: &AtomicUsize, released: &tokio::sync::Notify) {
+    counter.fetch_sub(1, Ordering::SeqCst);
+    released.notify_waiters();
+}
+
+/// an admitted connection, counted until dropped.
+pub(crate) struct Permit {
+    limits: Arc<ServiceLimits>,
+    ip: Option<IpAddr>,
+}
+
+impl Permit {
+    /// count the connection for its client, known after the PROXY protocol header. `None`
+    /// if the client is at its limit, the permit is released.
+    pub(crate) fn client(mut self, peer: &Peer, cfg: &ServiceConfig) -> Option<Self> {
+        let max = cfg.tcp.limits.max_conns_per_ip;
+        let ip = match peer.ip() {
+            Some(ip) if max > 0 => ip,
+            _ => return Some(self),
+        };
+        {
+            let mut ips = lock(&self.limits.ips);
+            let count = ips.entry(ip).or_insert(0);
+            if *count >= max {
+                drop(ips);
+                self.limits.rejected_ip.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            *count += 1;
+        }
+        self.ip = Some(ip);
+        Some(self)
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        match self.ip {
+            Some(ip) => {
+                let mut ips = lock(&self.limits.ips);
+                match ips.get_mut(&ip) {
+                    Some(count) if *count > 1 => *count -= 1,
+                    _ => {
+                        ips.remove(&ip);
+                    }
+                }
+            }
+            None => {}
+        }
+        release(&self.limits.conns, &self.limits.released);
+        release(&global().conns, &global().released);
+    }
+}
+
+// the name, type and value of a metric of every service
+type Family = (&'static str, &'static str, fn(&ServiceLimits) -> u64);
+
+/// the counters in the prometheus text format.
+pub(crate) fn metrics() -> String {
+    let mut out = String::new();
+    let g = global();
+    _ = writeln!(out, "# TYPE httpd_connections gauge");
+    _ = writeln!(out, "httpd_connections {}", g.conns.load(Ordering::SeqCst));
+    _ = writeln!(out, "# TYPE httpd_connections_max gauge");
+    _ = writeln!(
+        out,
+        "httpd_connections_max {}",
+        g.max.load(Ordering::SeqCst)
+    );
+
+    let services = lock(services());
+    let families: [Family; 4] = [
+        ("httpd_service_connections", "gauge", |v| {
+            v.conns.load(Ordering::SeqCst) as u64
+        }),
+        ("httpd_service_clients", "gauge", |v| {
+            lock(&v.ips).len() as u64
+        }),
+        ("httpd_service_accepted_total", "counter", |v| {
+            v.accepted.load(Ordering::Relaxed)
+        }),
+        ("httpd_service_paused_total", "counter", |v| {
+            v.paused.load(Ordering::Relaxed)
+        }),
+    ];
+    for (family, kind, value) in families {
+        _ = writeln!(out, "# TYPE {} {}", family, kind);
+        for (name, v) in services.iter() {
+            _ = writeln!(out, "{}{{service=\"{}\"}} {}", family, name, value(v));
+        }
+    }
+    _ = writeln!(out, "# TYPE httpd_service_rejected_total counter");
+    for (name, v) in services.iter() {
+        for (limit, count) in [("conns", &v.rejected), ("ip", &v.rejected_ip)] {
+            _ = writeln!(
+                out,
+                "httpd_service_rejected_total{{service=\"{}\",limit=\"{}\"}} {}",
+                name,
+                limit,
+                count.load(Ordering::Relaxed)
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permits() {
+        let mut cfg = ServiceConfig::default();
+        cfg.name = "limits.test".to_string();
+        cfg.tcp.limits.max_conns = 2;
+        cfg.tcp.limits.max_conns_per_ip = 1;
+        let limits = service(&cfg.name);
+
+        let a: Peer = "10.0.0.1:1000"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let b: Peer = "10.0.0.2:1000"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let first = limits.admit(&cfg).unwrap().client(&a, &cfg).unwrap();
+        // over the ip limit, the permit is given back
+        assert!(limits.admit(&cfg).unwrap().client(&a, &cfg).is_none());
+        let second = limits.admit(&cfg).unwrap().client(&b, &cfg).unwrap();
+        assert!(limits.admit(&cfg).is_none());
+
+        drop(first);
+        assert_eq!(lock(&limits.ips).len(), 1);
+        let _third = limits.admit(&cfg).unwrap().client(&a, &cfg).unwrap();
+        drop(second);
+        assert_eq!(limits.conns.load(Ordering::SeqCst), 1);
+        assert_eq!(limits.rejected.load(Ordering::SeqCst), 1);
+        assert_eq!(limits.rejected_ip.load(Ordering::SeqCst), 1);
+        assert!(metrics().contains("httpd_service_connections{service=\"limits.test\"} 1"));
+    }
+}
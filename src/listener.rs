@@ -0,0 +1,333 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    config::{service::ServiceConfig, unix::UnixConfig},
+    ctx::Peer,
+    upgrade,
+    utils::anyhow,
+};
+
+/// a listening socket of a service bound before it is started, one per runtime.
+pub(crate) enum StdListener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl StdListener {
+    /// inherit or bind the listener of `cfg`, see `upgrade::tcp_listener`.
+    pub(crate) fn bind(cfg: &ServiceConfig, reuse_port: bool) -> anyhow::Result<Self> {
+        match cfg.tcp.unix_path() {
+            #[cfg(unix)]
+            Some(path) => Ok(StdListener::Unix(upgrade::unix_listener(
+                &cfg.name,
+                path,
+                &cfg.tcp.unix,
+            )?)),
+            #[cfg(not(unix))]
+            Some(_) => anyhow::error("unix sockets are not supported on this system"),
+            None => Ok(StdListener::Tcp(upgrade::tcp_listener(
+                &cfg.name,
+                &cfg.tcp.addr,
+                reuse_port,
+            )?)),
+        }
+    }
+
+    /// whether a socket passed by the old binary or systemd for `cfg` is not claimed yet.
+    pub(crate) fn inherited(cfg: &ServiceConfig) -> bool {
+        match cfg.tcp.unix_path() {
+            Some(path) => upgrade::inherits(upgrade::Kind::Unix, &cfg.name, path),
+            None => upgrade::inherits(upgrade::Kind::Tcp, &cfg.name, &cfg.tcp.addr),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> std::io::Result<Self> {
+        match self {
+            StdListener::Tcp(v) => Ok(StdListener::Tcp(v.try_clone()?)),
+            #[cfg(unix)]
+            StdListener::Unix(v) => Ok(StdListener::Unix(v.try_clone()?)),
+        }
+    }
+
+    /// whether another runtime may bind the address instead of sharing this socket.
+    pub(crate) fn reuses_port(&self) -> bool {
+        match self {
+            StdListener::Tcp(v) => upgrade::reuses_port(v),
+            #[cfg(unix)]
+            StdListener::Unix(_) => false,
+        }
+    }
+
+    /// register the socket with the current runtime, it is handed to a new binary until the
+    /// `Handover` is dropped.
+    pub(crate) fn listen(
+        self,
+        cfg: &ServiceConfig,
+    ) -> anyhow::Result<(Listener, upgrade::Handover)> {
+        match self {
+            StdListener::Tcp(v) => {
+                let handover = upgrade::hand_over_tcp(&cfg.name, &v)?;
+                let listener = anyhow::result(tokio::net::TcpListener::from_std(v))?;
+                Ok((Listener::Tcp(listener), handover))
+            }
+            #[cfg(unix)]
+            StdListener::Unix(v) => {
+                let path = anyhow::option(cfg.tcp.unix_path(), "not a unix socket address")?;
+                let handover = upgrade::hand_over_unix(&cfg.name, path, &v)?;
+                let listener = anyhow::result(tokio::net::UnixListener::from_std(v))?;
+                Ok((Listener::Unix(listener), handover))
+            }
+        }
+    }
+}
+
+pub(crate) enum Listener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    pub(crate) async fn accept(&self) -> std::io::Result<(Stream, Peer)> {
+        match self {
+            Listener::Tcp(v) => {
+                let (stream, addr) = v.accept().await?;
+                Ok((Stream::Tcp(stream), Peer::Ip(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(v) => {
+                let (stream, _) = v.accept().await?;
+                Ok((Stream::Unix(stream), Peer::Unix))
+            }
+        }
+    }
+}
+
+/// an accepted connection.
+pub(crate) enum Stream {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(v) => Pin::new(v).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(v) => Pin::new(v).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(v) => Pin::new(v).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(v) => Pin::new(v).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(v) => Pin::new(v).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Stream::Unix(v) => Pin::new(v).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Tcp(v) => v.is_write_vectored(),
+            #[cfg(unix)]
+            Stream::Unix(v) => v.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(v) => Pin::new(v).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(v) => Pin::new(v).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(v) => Pin::new(v).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(v) => Pin::new(v).poll_shutdown(cx),
+        }
+    }
+}
+
+/// bind a unix socket at `path`. a socket file nobody listens on any more, left by a process
+/// that did not exit cleanly, is replaced.
+#[cfg(unix)]
+pub(crate) fn bind_unix(
+    path: &str,
+    cfg: &UnixConfig,
+) -> anyhow::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    };
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) => {
+            if !meta.file_type().is_socket() {
+                return anyhow::error(&format!("`{}` exists and is not a socket", path));
+            }
+            match UnixStream::connect(path) {
+                Ok(_) => {
+                    return anyhow::error(&format!("`{}` is in use by another process", path));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    log::info!("remove stale unix socket `{}`", path);
+                    anyhow::result(std::fs::remove_file(path))?;
+                }
+                Err(e) => {
+                    return anyhow::error(&format!("check unix socket `{}` failed, {}", path, e));
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return anyhow::error(&format!("stat `{}` failed, {}", path, e)),
+    }
+
+    let listener = anyhow::result(UnixListener::bind(path))?;
+    set_unix_permissions(path, cfg)?;
+    Ok(listener)
+}
+
+/// the mode and the owner of a unix socket file, also applied to a bound socket on reload.
+#[cfg(unix)]
+pub(crate) fn set_unix_permissions(path: &str, cfg: &UnixConfig) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    match cfg.mode()? {
+        Some(mode) => {
+            let perm = std::fs::Permissions::from_mode(mode);
+            anyhow::result(std::fs::set_permissions(path, perm))?;
+        }
+        None => {}
+    }
+    let uid = match cfg.owner.as_str() {
+        "" => None,
+        owner => Some(user_id(owner)?),
+    };
+    let gid = match cfg.group.as_str() {
+        "" => None,
+        group => Some(group_id(group)?),
+    };
+    if uid.is_some() || gid.is_some() {
+        match std::os::unix::fs::chown(path, uid, gid) {
+            Ok(_) => {}
+            Err(e) => return anyhow::error(&format!("chown `{}` failed, {}", path, e)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn user_id(name: &str) -> anyhow::Result<u32> {
+    match name.parse::<u32>() {
+        Ok(v) => return Ok(v),
+        Err(_) => {}
+    }
+    let cname = anyhow::result(std::ffi::CString::new(name))?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut found = std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getpwnam_r(
+            cname.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if ret != 0 || found.is_null() {
+        return anyhow::error(&format!("unknown user `{}`", name));
+    }
+    Ok(pwd.pw_uid)
+}
+
+#[cfg(unix)]
+fn group_id(name: &str) -> anyhow::Result<u32> {
+    match name.parse::<u32>() {
+        Ok(v) => return Ok(v),
+        Err(_) => {}
+    }
+    let cname = anyhow::result(std::ffi::CString::new(name))?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut found = std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getgrnam_r(
+            cname.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if ret != 0 || found.is_null() {
+        return anyhow::error(&format!("unknown group `{}`", name));
+    }
+    Ok(grp.gr_gid)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_unix_stale() {
+        let dir = std::env::temp_dir().join(format!("httpd.unix.{}", std::process::id()));
+        _ = std::fs::create_dir_all(&dir);
+        let path = dir.join("a.sock");
+        let path = path.to_str().unwrap();
+        let cfg = UnixConfig {
+            mode: "600".to_string(),
+            ..Default::default()
+        };
+
+        let listener = bind_unix(path, &cfg).unwrap();
+        // still listened on
+        assert!(bind_unix(path, &cfg).is_err());
+        drop(listener);
+        // the file is left behind, nobody listens on it
+        assert!(std::fs::metadata(path).is_ok());
+        let _listener = bind_unix(path, &cfg).unwrap();
+
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::write(dir.join("b.sock"), b"").unwrap();
+        assert!(bind_unix(dir.join("b.sock").to_str().unwrap(), &cfg).is_err());
+
+        _ = std::fs::remove_dir_all(&dir);
+    }
+}
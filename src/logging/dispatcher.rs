@@ -8,8 +8,26 @@ enum Message {
     LogItem(Item),
 }
 
+// installed for the whole process, the sender is taken on shutdown and later records are
+// dropped
 struct Dispatcher {
-    sx: std::sync::mpsc::Sender<Message>,
+    sx: std::sync::RwLock<Option<std::sync::mpsc::Sender<Message>>>,
+}
+
+impl Dispatcher {
+    fn send(&self, msg: Message) -> bool {
+        let sx = match self.sx.read() {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match sx.as_ref() {
+            Some(sx) => {
+                sx.send(msg).expect("logging: send message failed");
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 impl log::Log for Dispatcher {
@@ -18,16 +36,14 @@ impl log::Log for Dispatcher {
     }
 
     fn log(&self, record: &log::Record) {
-        self.sx
-            .send(Message::LogItem(Item::from(record)))
-            .expect("logging: send log message failed");
+        self.send(Message::LogItem(Item::from(record)));
     }
 
     fn flush(&self) {
         let lock = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-        self.sx
-            .send(Message::Flush(lock.clone()))
-            .expect("logging: send flush message failed");
+        if !self.send(Message::Flush(lock.clone())) {
+            return;
+        }
 
         loop {
             if lock.load(std::sync::atomic::Ordering::SeqCst) {
@@ -45,9 +61,13 @@ pub struct ShutdownGuard {
 
 impl Drop for ShutdownGuard {
     fn drop(&mut self) {
-        let ptr: *mut Dispatcher = unsafe { std::mem::transmute(self.ptr) };
-        let ptr = unsafe { Box::from_raw(ptr) };
-        std::mem::drop(ptr);
+        // the logger can not be uninstalled, other threads may be in `log` still. closing the
+        // channel ends the consumer once the sent records are written
+        let sx = match self.ptr.sx.write() {
+            Ok(mut g) => g.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        std::mem::drop(sx);
 
         loop {
             if self.signal.load(std::sync::atomic::Ordering::SeqCst) {
@@ -65,7 +85,9 @@ pub fn init(
     samap: Slab<Vec<usize>>,
 ) -> anyhow::Result<ShutdownGuard> {
     let (sx, rx) = std::sync::mpsc::channel();
-    let dispatcher = Dispatcher { sx };
+    let dispatcher = Dispatcher {
+        sx: std::sync::RwLock::new(Some(sx)),
+    };
     let ptr: &'static Dispatcher = Box::leak(Box::new(dispatcher));
 
     let guard = ShutdownGuard {
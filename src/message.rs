@@ -1,9 +1,10 @@
-use std::io::Write;
+use std::{future::Future, io::Write};
 
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt};
 
 use crate::compression::BoxedWriteCompressionImpl;
 use crate::config::http::HttpConfig;
+use crate::form::Form;
 use crate::internal::header;
 use crate::{ctx::ConnContext, internal::multi_map::MultiMap};
 
@@ -93,7 +94,13 @@ impl MessageBody {
 
     pub(crate) fn clear(&mut self) {
         match self.end() {
-            Ok(_) => {}
+            Ok(_) => {
+                // the buffer is reused by the next message of the connection
+                match self.internal.as_mut() {
+                    Some(buf) => buf.clear(),
+                    None => {}
+                }
+            }
             Err(_) => {
                 self.cw.take();
                 self.internal = Some(Box::new(bytebuffer::ByteBuffer::default()));
@@ -138,6 +145,8 @@ pub(crate) struct Message {
     pub(crate) firstline: (String, String, String),
     pub(crate) headers: MultiMap,
     pub(crate) body: MessageBody,
+    // a `multipart/form-data` request body read part by part, see `Service::multipart`
+    pub(crate) form: Option<Form>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -145,10 +154,33 @@ pub(crate) enum MessageReadCode {
     Ok,
     ConnReadError,
     BadDatagram,
+    ReachMaxUrlSize,
+    ReachMaxHeaderLineSize,
     ReachMaxBodySize,
     BadContentLength,
     ReachMaxHeadersCount,
     BadChunkSize,
+    BadTransferEncoding,
+    UnsupportedTransferEncoding,
+}
+
+impl MessageReadCode {
+    /// the response to a request that failed to read, `None` if there is nobody to answer.
+    pub(crate) fn status(&self) -> Option<(u16, &'static str)> {
+        match self {
+            MessageReadCode::Ok | MessageReadCode::ConnReadError => None,
+            MessageReadCode::BadDatagram
+            | MessageReadCode::BadContentLength
+            | MessageReadCode::BadChunkSize
+            | MessageReadCode::BadTransferEncoding => Some((400, "Bad Request")),
+            MessageReadCode::ReachMaxUrlSize => Some((414, "URI Too Long")),
+            MessageReadCode::ReachMaxHeaderLineSize | MessageReadCode::ReachMaxHeadersCount => {
+                Some((431, "Request Header Fields Too Large"))
+            }
+            MessageReadCode::ReachMaxBodySize => Some((413, "Content Too Large")),
+            MessageReadCode::UnsupportedTransferEncoding => Some((501, "Not Implemented")),
+        }
+    }
 }
 
 const MAX_HEADER_NAME_LENGTH: usize = 256;
@@ -168,6 +200,9 @@ macro_rules! read_const_length_body_impl {
                 _buf = &mut _buf[..$remain_size];
             }
             match $reader.read(_buf).await {
+                Ok(0) => {
+                    return MessageReadCode::ConnReadError;
+                }
                 Ok(size) => {
                     $self.$write(&_buf[..size]);
                     $remain_size -= size;
@@ -183,46 +218,133 @@ macro_rules! read_const_length_body_impl {
     };
 }
 
+// the longest `chunk-size [ chunk-ext ]` line and trailer section accepted
+const MAX_CHUNK_LINE_LENGTH: u64 = 1024;
+const MAX_TRAILER_LINE_LENGTH: u64 = 8 * 1024;
+const MAX_TRAILERS_COUNT: usize = 64;
+
+// `HTTP/` DIGIT `.` DIGIT, RFC 9112 section 2.3
+fn is_version(v: &[u8]) -> bool {
+    match v {
+        [b'H', b'T', b'T', b'P', b'/', major, b'.', minor] => {
+            major.is_ascii_digit() && minor.is_ascii_digit()
+        }
+        _ => false,
+    }
+}
+
+// the length of a line without its end, `None` if it does not end with CRLF. a bare LF ends
+// a line only in lenient mode, RFC 9112 section 2.2
+fn line_end(line: &[u8], lenient: bool) -> Option<usize> {
+    if line.ends_with(b"\r\n") {
+        return Some(line.len() - 2);
+    }
+    if lenient && line.ends_with(b"\n") {
+        return Some(line.len() - 1);
+    }
+    None
+}
+
+// `chunk-size [ chunk-ext ]`, RFC 9112 section 7.1
+fn chunk_size(line: &[u8]) -> Option<usize> {
+    for b in line {
+        if !b.is_ascii_graphic() && *b != b' ' && *b != b'\t' {
+            return None;
+        }
+    }
+    let digits = match line.iter().position(|b| *b == b';') {
+        Some(idx) => {
+            let mut digits = &line[..idx];
+            while let [rest @ .., b' ' | b'\t'] = digits {
+                digits = rest;
+            }
+            digits
+        }
+        None => line,
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut size: usize = 0;
+    for b in digits {
+        let digit = (*b as char).to_digit(16)? as usize;
+        size = size.checked_mul(16)?.checked_add(digit)?;
+    }
+    Some(size)
+}
+
+// the trailer section is read and dropped
+async fn skip_trailers<R: AsyncBufReadExt + Unpin>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+    lenient: bool,
+) -> MessageReadCode {
+    for _ in 0..MAX_TRAILERS_COUNT {
+        line.clear();
+        match reader
+            .take(MAX_TRAILER_LINE_LENGTH)
+            .read_until(b'\n', line)
+            .await
+        {
+            Ok(0) | Err(_) => return MessageReadCode::ConnReadError,
+            Ok(_) => {}
+        }
+        match line_end(line, lenient) {
+            Some(0) => return MessageReadCode::Ok,
+            Some(_) => {}
+            None => return MessageReadCode::BadDatagram,
+        }
+    }
+    MessageReadCode::ReachMaxHeadersCount
+}
+
 macro_rules! read_chunked_body_impl {
-    ($self:ident, $reader:ident, $buf:ident, $max_body_size:ident, $write:ident) => {
+    ($self:ident, $reader:ident, $buf:ident, $max_body_size:ident, $lenient:ident, $write:ident) => {
         let bufcap = $buf.capacity();
         unsafe { $buf.set_len(bufcap) };
 
-        let mut lenline = String::with_capacity(128);
-        let mut remain_size: usize;
+        let mut line: Vec<u8> = Vec::with_capacity(128);
         let mut read_size: usize = 0;
         loop {
-            lenline.clear();
-            match $reader.take(128).read_line(&mut lenline).await {
-                Ok(_) => match lenline.as_str().trim().parse::<usize>() {
-                    Ok(num) => {
-                        remain_size = num;
-                    }
-                    Err(_) => {
-                        return MessageReadCode::BadChunkSize;
-                    }
-                },
-                Err(_) => {
+            line.clear();
+            match $reader
+                .take(MAX_CHUNK_LINE_LENGTH)
+                .read_until(b'\n', &mut line)
+                .await
+            {
+                Ok(0) | Err(_) => {
                     return MessageReadCode::ConnReadError;
                 }
+                Ok(_) => {}
             }
-
-            read_size += remain_size;
-            if read_size > $max_body_size {
-                return MessageReadCode::ReachMaxBodySize;
+            let mut remain_size =
+                match line_end(&line, $lenient).and_then(|end| chunk_size(&line[..end])) {
+                    Some(num) => num,
+                    None => {
+                        return MessageReadCode::BadChunkSize;
+                    }
+                };
+            if remain_size < 1 {
+                return skip_trailers($reader, &mut line, $lenient).await;
             }
 
-            loop {
-                if remain_size < 1 {
-                    break;
+            read_size = match read_size.checked_add(remain_size) {
+                Some(v) if v <= $max_body_size => v,
+                _ => {
+                    return MessageReadCode::ReachMaxBodySize;
                 }
+            };
 
+            loop {
                 let mut _buf = $buf.as_mut_slice();
                 if remain_size < bufcap {
                     _buf = &mut _buf[..remain_size];
                 }
 
                 match $reader.read(_buf).await {
+                    Ok(0) => {
+                        return MessageReadCode::ConnReadError;
+                    }
                     Ok(size) => {
                         $self.$write(&_buf[..size]);
                         remain_size -= size;
@@ -236,14 +358,250 @@ macro_rules! read_chunked_body_impl {
                 }
             }
 
-            match $reader.take(2).read_line(&mut lenline).await {
-                Ok(_) => return MessageReadCode::Ok,
-                Err(_) => return MessageReadCode::BadDatagram,
+            // the data ends with CRLF
+            line.clear();
+            match $reader.take(2).read_until(b'\n', &mut line).await {
+                Ok(0) | Err(_) => {
+                    return MessageReadCode::ConnReadError;
+                }
+                Ok(_) => {}
+            }
+            if line_end(&line, $lenient) != Some(0) {
+                return MessageReadCode::BadChunkSize;
             }
         }
     };
 }
 
+/// a request body read from `reader`, it fails with `TimedOut` once the time taken is over
+/// `HttpConfig::body_timeout_of` the bytes received so far, a deadline that grows as the
+/// body comes in at `min_body_rate` or faster.
+pub(crate) struct PacedReader<'r, R> {
+    reader: &'r mut R,
+    config: &'static HttpConfig,
+    since: tokio::time::Instant,
+    received: usize,
+    deadline: std::pin::Pin<Box<tokio::time::Sleep>>,
+    timed_out: bool,
+}
+
+impl<'r, R: AsyncBufRead + Unpin> PacedReader<'r, R> {
+    pub(crate) fn new(reader: &'r mut R, config: &'static HttpConfig) -> Self {
+        let since = tokio::time::Instant::now();
+        Self {
+            reader,
+            config,
+            since,
+            received: 0,
+            deadline: Box::pin(tokio::time::sleep_until(since + config.body_timeout_of(0))),
+            timed_out: false,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for PacedReader<'_, R> {
+    fn poll_fill_buf(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.timed_out || this.deadline.as_mut().poll(cx).is_ready() {
+            this.timed_out = true;
+            return std::task::Poll::Ready(Err(std::io::ErrorKind::TimedOut.into()));
+        }
+        std::pin::Pin::new(&mut *this.reader).poll_fill_buf(cx)
+    }
+
+    fn consume(self: std::pin::Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        std::pin::Pin::new(&mut *this.reader).consume(amt);
+        if amt > 0 {
+            this.received += amt;
+            let deadline = this.since + this.config.body_timeout_of(this.received);
+            this.deadline.as_mut().reset(deadline);
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for PacedReader<'_, R> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        poll_read_buffered(self, cx, buf)
+    }
+}
+
+// `AsyncRead` by way of `AsyncBufRead`
+fn poll_read_buffered<T: AsyncBufRead + ?Sized>(
+    mut reader: std::pin::Pin<&mut T>,
+    cx: &mut std::task::Context<'_>,
+    buf: &mut tokio::io::ReadBuf<'_>,
+) -> std::task::Poll<std::io::Result<()>> {
+    let available = std::task::ready!(reader.as_mut().poll_fill_buf(cx))?;
+    let size = std::cmp::min(available.len(), buf.remaining());
+    buf.put_slice(&available[..size]);
+    reader.consume(size);
+    std::task::Poll::Ready(Ok(()))
+}
+
+#[derive(Clone, Copy)]
+enum ChunkState {
+    Size,
+    Data(usize),
+    // the CRLF after the data
+    DataEnd,
+    // the trailer lines read so far
+    Trailers(usize),
+    Done,
+}
+
+fn bad_chunk() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "bad chunked body")
+}
+
+/// the data of a chunked body read from `reader` as it comes, the trailers are dropped.
+/// a body over `max_body_size` fails with `FileTooLarge`, a bad one with `InvalidData`.
+pub(crate) struct ChunkedReader<R> {
+    reader: R,
+    state: ChunkState,
+    line: Vec<u8>,
+    received: usize,
+    max_body_size: usize,
+    lenient: bool,
+}
+
+impl<R: AsyncBufRead + Unpin> ChunkedReader<R> {
+    pub(crate) fn new(reader: R, config: &HttpConfig) -> Self {
+        Self {
+            reader,
+            state: ChunkState::Size,
+            line: Vec::with_capacity(128),
+            received: 0,
+            max_body_size: config.max_body_size.0,
+            lenient: config.lenient(),
+        }
+    }
+
+    // a line into `self.line`, up to `max` bytes
+    fn poll_line(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        max: u64,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        loop {
+            let buf = std::task::ready!(std::pin::Pin::new(&mut self.reader).poll_fill_buf(cx))?;
+            if buf.is_empty() {
+                return std::task::Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            let (size, done) = match buf.iter().position(|b| *b == b'\n') {
+                Some(idx) => (idx + 1, true),
+                None => (buf.len(), false),
+            };
+            self.line.extend_from_slice(&buf[..size]);
+            std::pin::Pin::new(&mut self.reader).consume(size);
+            if self.line.len() as u64 > max {
+                return std::task::Poll::Ready(Err(bad_chunk()));
+            }
+            if done {
+                return std::task::Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for ChunkedReader<R> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        poll_read_buffered(self, cx, buf)
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for ChunkedReader<R> {
+    fn poll_fill_buf(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        loop {
+            let max = match this.state {
+                ChunkState::Data(remain) => {
+                    let buf =
+                        std::task::ready!(std::pin::Pin::new(&mut this.reader).poll_fill_buf(cx))?;
+                    if buf.is_empty() {
+                        return std::task::Poll::Ready(Err(
+                            std::io::ErrorKind::UnexpectedEof.into()
+                        ));
+                    }
+                    let size = std::cmp::min(buf.len(), remain);
+                    return std::task::Poll::Ready(Ok(&buf[..size]));
+                }
+                ChunkState::Done => return std::task::Poll::Ready(Ok(&[])),
+                ChunkState::Size => MAX_CHUNK_LINE_LENGTH,
+                ChunkState::DataEnd => 2,
+                ChunkState::Trailers(_) => MAX_TRAILER_LINE_LENGTH,
+            };
+            std::task::ready!(this.poll_line(cx, max))?;
+            let end = line_end(&this.line, this.lenient);
+            this.state = match this.state {
+                ChunkState::Size => match end.and_then(|end| chunk_size(&this.line[..end])) {
+                    Some(0) => ChunkState::Trailers(0),
+                    Some(size) => {
+                        this.received = match this.received.checked_add(size) {
+                            Some(v) if v <= this.max_body_size => v,
+                            _ => {
+                                return std::task::Poll::Ready(Err(
+                                    std::io::ErrorKind::FileTooLarge.into(),
+                                ))
+                            }
+                        };
+                        ChunkState::Data(size)
+                    }
+                    None => return std::task::Poll::Ready(Err(bad_chunk())),
+                },
+                ChunkState::DataEnd if end == Some(0) => ChunkState::Size,
+                ChunkState::Trailers(_) if end == Some(0) => ChunkState::Done,
+                ChunkState::Trailers(count) if end.is_some() && count + 1 < MAX_TRAILERS_COUNT => {
+                    ChunkState::Trailers(count + 1)
+                }
+                _ => return std::task::Poll::Ready(Err(bad_chunk())),
+            };
+            this.line.clear();
+        }
+    }
+
+    fn consume(self: std::pin::Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        match this.state {
+            ChunkState::Data(remain) => {
+                std::pin::Pin::new(&mut this.reader).consume(amt);
+                this.state = if amt >= remain {
+                    ChunkState::DataEnd
+                } else {
+                    ChunkState::Data(remain - amt)
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+/// how the body of a request is delimited, RFC 9112 section 6.3.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Framing {
+    Length(usize),
+    Chunked,
+}
+
 impl Message {
     pub(crate) fn clear(&mut self) {
         self.firstline.0.clear();
@@ -251,16 +609,57 @@ impl Message {
         self.firstline.2.clear();
         self.headers.clear();
         self.body.clear();
+        self.form = None;
     }
 
     pub(crate) fn get_content_length(&self) -> Result<usize, ()> {
-        match self.headers.get("content-length") {
-            Some(vs) => match vs.parse::<usize>() {
-                Ok(num) => Ok(num),
-                Err(_) => Err(()),
-            },
-            None => Ok(0),
+        match self.framing() {
+            Ok(Framing::Length(num)) => Ok(num),
+            _ => Err(()),
+        }
+    }
+
+    /// the framing of the body, a request delimited two ways or with conflicting lengths is
+    /// an error.
+    pub(crate) fn framing(&self) -> Result<Framing, MessageReadCode> {
+        let lengths = self.headers.getall("content-length");
+        match self.headers.getall("transfer-encoding") {
+            Some(vs) if !vs.is_empty() => {
+                if lengths.is_some_and(|v| !v.is_empty()) {
+                    return Err(MessageReadCode::BadContentLength);
+                }
+                let codings = header::tokens(Some(vs));
+                // `chunked` is the final coding, and only once
+                match codings.iter().position(|v| v == "chunked") {
+                    Some(idx) if idx + 1 == codings.len() => {}
+                    _ => return Err(MessageReadCode::BadTransferEncoding),
+                }
+                if codings.len() > 1 {
+                    return Err(MessageReadCode::UnsupportedTransferEncoding);
+                }
+                return Ok(Framing::Chunked);
+            }
+            _ => {}
         }
+
+        let mut length = None;
+        for v in lengths.into_iter().flatten() {
+            for item in v.split(',') {
+                let item = item.trim();
+                if item.is_empty() || !item.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(MessageReadCode::BadContentLength);
+                }
+                let num = match item.parse::<usize>() {
+                    Ok(num) => num,
+                    Err(_) => return Err(MessageReadCode::BadContentLength),
+                };
+                match length {
+                    Some(prev) if prev != num => return Err(MessageReadCode::BadContentLength),
+                    _ => length = Some(num),
+                }
+            }
+        }
+        Ok(Framing::Length(length.unwrap_or(0)))
     }
 
     fn write_raw(&mut self, v: &[u8]) {
@@ -280,21 +679,14 @@ impl Message {
         read_const_length_body_impl!(self, reader, buf, remain_size, write_raw);
     }
 
+    /// read the body by the framing checked in `read_headers`.
     #[inline]
-    pub(crate) async fn read_const_length_body<
-        R: AsyncBufReadExt + Unpin,
-        W: AsyncWriteExt + Unpin,
-    >(
+    pub(crate) async fn read_body<R: AsyncBufReadExt + Unpin, W: AsyncWriteExt + Unpin>(
         &mut self,
         ctx: &mut ConnContext<R, W>,
     ) -> MessageReadCode {
-        match self.get_content_length() {
-            Ok(size) => {
-                self._read_const_length_body(&mut ctx.reader, &mut ctx.buf, size)
-                    .await
-            }
-            Err(_) => MessageReadCode::BadContentLength,
-        }
+        self.read_body_normal(&mut ctx.reader, &mut ctx.buf, &ctx.config.http)
+            .await
     }
 
     pub(crate) async fn read_const_length_body_decompression<R: AsyncBufReadExt + Unpin>(
@@ -311,8 +703,9 @@ impl Message {
         reader: &mut R,
         buf: &mut Vec<u8>,
         max_body_size: usize,
+        lenient: bool,
     ) -> MessageReadCode {
-        read_chunked_body_impl!(self, reader, buf, max_body_size, write_raw);
+        read_chunked_body_impl!(self, reader, buf, max_body_size, lenient, write_raw);
     }
 
     pub(crate) async fn read_chunked_body_decompression<R: AsyncBufReadExt + Unpin>(
@@ -320,8 +713,9 @@ impl Message {
         reader: &mut R,
         buf: &mut Vec<u8>,
         max_body_size: usize,
+        lenient: bool,
     ) -> MessageReadCode {
-        read_chunked_body_impl!(self, reader, buf, max_body_size, write_compression);
+        read_chunked_body_impl!(self, reader, buf, max_body_size, lenient, write_compression);
     }
 
     pub(crate) async fn read_body_normal<R: AsyncBufReadExt + Unpin>(
@@ -333,20 +727,18 @@ impl Message {
         let cap = buf.capacity();
         unsafe { buf.set_len(cap) }; // safety: just bytes array, no ref
 
-        if header::contains(self.headers.getall("transfer-encoding"), "chunked") {
-            return self
-                .read_chunked_body(reader, buf, config.max_body_size.0)
-                .await;
-        }
-
-        match self.get_content_length() {
-            Ok(remain_size) => {
+        match self.framing() {
+            Ok(Framing::Chunked) => {
+                self.read_chunked_body(reader, buf, config.max_body_size.0, config.lenient())
+                    .await
+            }
+            Ok(Framing::Length(remain_size)) => {
                 if remain_size > config.max_body_size.0 {
                     return MessageReadCode::ReachMaxBodySize;
                 }
                 self._read_const_length_body(reader, buf, remain_size).await
             }
-            Err(_) => MessageReadCode::BadContentLength,
+            Err(code) => code,
         }
     }
 
@@ -370,7 +762,21 @@ impl Message {
         }
 
         let max_header_line_size = config.max_header_line_size.u64();
+        // field values may have tabs
+        macro_rules! ensure_value {
+            ($bytes:expr) => {
+                for b in ($bytes) {
+                    if !b.is_ascii_graphic() && *b != b' ' && *b != b'\t' {
+                        return MessageReadCode::BadDatagram;
+                    }
+                }
+            };
+        }
+
         let max_headers_count = config.max_headers_count;
+        let mut headers_count = 0;
+        let lenient = config.lenient();
+        let mut last_key = String::new();
 
         loop {
             match state {
@@ -381,8 +787,13 @@ impl Message {
                             if size < 1 {
                                 return MessageReadCode::ConnReadError;
                             }
+                            if dest[size - 1] != b' ' {
+                                return MessageReadCode::BadDatagram;
+                            }
                             unsafe { dest.set_len(size - 1) }; // safety: trim last space and len check in front
-                            ensure_ascii!(dest);
+                            if dest.is_empty() || !dest.iter().all(|b| header::is_tchar(*b)) {
+                                return MessageReadCode::BadDatagram;
+                            }
                             state = ReadState::FirstLine0;
                             continue;
                         }
@@ -393,16 +804,22 @@ impl Message {
                 }
                 ReadState::FirstLine0 => {
                     let dest = unsafe { (&mut self.firstline.1).as_mut_vec() }; // safety: no double copy
-                    match reader
-                        .take(config.max_url_size.u64())
-                        .read_until(b' ', dest)
-                        .await
-                    {
+                    let max_url_size = config.max_url_size.u64();
+                    match reader.take(max_url_size).read_until(b' ', dest).await {
                         Ok(size) => {
                             if size < 1 {
                                 return MessageReadCode::ConnReadError;
                             }
+                            if dest[size - 1] != b' ' {
+                                if size as u64 >= max_url_size {
+                                    return MessageReadCode::ReachMaxUrlSize;
+                                }
+                                return MessageReadCode::BadDatagram;
+                            }
                             unsafe { dest.set_len(size - 1) }; // safety: trim last space and len check in front
+                            if dest.is_empty() {
+                                return MessageReadCode::BadDatagram;
+                            }
                             ensure_ascii!(dest);
                             state = ReadState::FirstLine1;
                             continue;
@@ -415,12 +832,15 @@ impl Message {
                 ReadState::FirstLine1 => {
                     match reader.take(128).read_line(&mut self.firstline.2).await {
                         Ok(size) => {
-                            if size < 3 {
+                            let bytes = unsafe { (&mut self.firstline.2).as_mut_vec() }; // safety: trim the line end and len check in front
+                            let end = match line_end(bytes, lenient) {
+                                Some(end) if end > 0 && size > 0 => end,
+                                _ => return MessageReadCode::BadDatagram,
+                            };
+                            unsafe { bytes.set_len(end) };
+                            if !is_version(bytes) {
                                 return MessageReadCode::BadDatagram;
                             }
-                            let bytes = unsafe { (&mut self.firstline.2).as_mut_vec() }; // safety: trim `\r\n` and len check in front
-                            unsafe { bytes.set_len(size - 2) };
-                            ensure_ascii!(bytes);
                             state = ReadState::FirstLine2;
                             continue;
                         }
@@ -433,28 +853,57 @@ impl Message {
                     let mut keytmp = [0 as u8; MAX_HEADER_NAME_LENGTH];
                     let mut keyidx: usize;
 
-                    'readlines: loop {
+                    'readline: {
                         buf.clear();
-                        let mut hc = 0;
                         match reader
                             .take(max_header_line_size)
                             .read_until(b'\n', buf)
                             .await
                         {
                             Ok(size) => {
-                                if size < 2 {
-                                    return MessageReadCode::BadDatagram;
+                                if size < 1 {
+                                    return MessageReadCode::ConnReadError;
                                 }
-                                if size == 2 {
+                                let end = match line_end(buf, lenient) {
+                                    Some(end) => end,
+                                    None => {
+                                        if buf[size - 1] != b'\n'
+                                            && size as u64 >= max_header_line_size
+                                        {
+                                            return MessageReadCode::ReachMaxHeaderLineSize;
+                                        }
+                                        return MessageReadCode::BadDatagram;
+                                    }
+                                };
+                                if end == 0 {
                                     state = ReadState::HeadersDone;
-                                    break;
+                                    break 'readline;
+                                }
+                                unsafe { buf.set_len(end) }; // safety: trim the line end and len check in front
+
+                                // obs-fold, RFC 9112 section 5.2
+                                if buf[0] == b' ' || buf[0] == b'\t' {
+                                    if !lenient || last_key.is_empty() {
+                                        return MessageReadCode::BadDatagram;
+                                    }
+                                    ensure_value!(&buf[..]);
+                                    // safety: ascii checked in front
+                                    let value =
+                                        unsafe { std::str::from_utf8_unchecked(&buf[..]) }.trim();
+                                    match self.headers.last_mut(&last_key) {
+                                        Some(prev) => {
+                                            prev.push(' ');
+                                            prev.push_str(value);
+                                        }
+                                        None => return MessageReadCode::BadDatagram,
+                                    }
+                                    break 'readline;
                                 }
-                                unsafe { buf.set_len(size - 2) }; // safety: trim `\r\n` and len check in front
 
                                 keyidx = 0;
                                 for idx in 0..buf.len() {
                                     let c = buf[idx];
-                                    if !c.is_ascii_graphic() && c != b' ' {
+                                    if !c.is_ascii_graphic() && c != b' ' && c != b'\t' {
                                         return MessageReadCode::BadDatagram;
                                     }
 
@@ -464,26 +913,32 @@ impl Message {
                                             std::str::from_utf8_unchecked(&keytmp[..keyidx])
                                         }
                                         .trim();
-
-                                        if idx + 1 >= buf.len() {
+                                        if key.is_empty() {
                                             return MessageReadCode::BadDatagram;
                                         }
 
+                                        // may be empty, RFC 9110 section 5.5
                                         // safety: not calling `std::str::from_utf8`, because i only want ascii chars in the header value
-                                        ensure_ascii!(&buf[(idx + 1)..]);
+                                        ensure_value!(&buf[(idx + 1)..]);
                                         let value: &str = unsafe {
                                             std::str::from_utf8_unchecked(&buf[(idx + 1)..])
                                         }
                                         .trim();
 
                                         self.headers.append(key, value);
-                                        hc += 1;
-                                        if hc > max_headers_count {
+                                        last_key.clear();
+                                        last_key.push_str(key);
+                                        headers_count += 1;
+                                        if headers_count > max_headers_count {
                                             return MessageReadCode::ReachMaxHeadersCount;
                                         }
-                                        break 'readlines;
+                                        break 'readline;
                                     }
 
+                                    // no whitespace in or after a field name, RFC 9112 section 5.1
+                                    if !header::is_tchar(c) && !lenient {
+                                        return MessageReadCode::BadDatagram;
+                                    }
                                     if keyidx >= MAX_HEADER_NAME_LENGTH {
                                         return MessageReadCode::BadDatagram;
                                     }
@@ -494,6 +949,8 @@ impl Message {
                                     }
                                     keyidx += 1;
                                 }
+                                // no colon
+                                return MessageReadCode::BadDatagram;
                             }
                             Err(_) => {
                                 return MessageReadCode::ConnReadError;
@@ -502,7 +959,19 @@ impl Message {
                     }
                 }
                 ReadState::HeadersDone => {
-                    return MessageReadCode::Ok;
+                    // `transfer-encoding` overrides `content-length`, the connection is not
+                    // reused as its framing is in doubt, RFC 9112 section 6.3
+                    if lenient
+                        && self.headers.get("transfer-encoding").is_some()
+                        && self.headers.get("content-length").is_some()
+                    {
+                        self.headers.delete("content-length");
+                        self.headers.set("connection", "close");
+                    }
+                    return match self.framing() {
+                        Ok(_) => MessageReadCode::Ok,
+                        Err(code) => code,
+                    };
                 }
             }
         }
@@ -525,8 +994,11 @@ impl Message {
 
         self.body.end()?;
         let bodysize = self.body.size();
-        self.headers
-            .set("content-length", bodysize.to_string().as_str());
+        // informational responses can not have a body, RFC 9110 section 8.6
+        if !self.firstline.1.starts_with('1') {
+            self.headers
+                .set("content-length", bodysize.to_string().as_str());
+        }
 
         let mut visitor = |k: &str, vs: &Vec<String>| -> bool {
             for v in vs {
@@ -548,3 +1020,231 @@ impl Message {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::{bytes_size::BytesSize, service::ServiceConfig};
+    use crate::ctx::Peer;
+
+    // the result of reading one request, its body and the bytes left for the next one
+    fn read(cfg: &'static ServiceConfig, raw: &[u8]) -> (MessageReadCode, String, String) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let r = tokio::io::BufReader::new(raw);
+            let addr: std::net::SocketAddr = "127.0.0.1:80".parse().unwrap();
+            let mut ctx = ConnContext::new(r, tokio::io::sink(), addr.into(), None, cfg);
+            let mut msg = Message::default();
+            let code = match msg.read_headers(&mut ctx).await {
+                MessageReadCode::Ok => msg.read_body(&mut ctx).await,
+                e => e,
+            };
+            let mut rest = vec![];
+            _ = ctx.reader.read_to_end(&mut rest).await;
+            (
+                code,
+                String::from_utf8_lossy(msg.body.inner()).to_string(),
+                String::from_utf8_lossy(&rest).to_string(),
+            )
+        })
+    }
+
+    fn config(lenient: bool) -> &'static ServiceConfig {
+        let mut cfg = ServiceConfig::default();
+        cfg.http.max_url_size = BytesSize(16);
+        cfg.http.max_header_line_size = BytesSize(48);
+        cfg.http.max_headers_count = 3;
+        cfg.http.max_body_size = BytesSize(8);
+        cfg.http.lenient = Some(lenient);
+        Box::leak(Box::new(cfg))
+    }
+
+    fn status(cfg: &'static ServiceConfig, raw: &str) -> u16 {
+        match read(cfg, raw.as_bytes()).0.status() {
+            Some((code, _)) => code,
+            None => 200,
+        }
+    }
+
+    #[test]
+    fn test_read_codes() {
+        let cfg = config(false);
+        assert_eq!(status(cfg, "GET / HTTP/1.1\r\nhost: a\r\n\r\n"), 200);
+        assert_eq!(status(cfg, "GET / HTTP/1.1\r\nhost a\r\n\r\n"), 400);
+        assert_eq!(
+            status(cfg, "GET /aaaaaaaaaaaaaaaaaaaa HTTP/1.1\r\n\r\n"),
+            414
+        );
+        let long = format!("GET / HTTP/1.1\r\nx: {}\r\n\r\n", "a".repeat(64));
+        assert_eq!(status(cfg, &long), 431);
+        assert_eq!(
+            status(
+                cfg,
+                "GET / HTTP/1.1\r\na: 1\r\nb: 2\r\nc: 3\r\nd: 4\r\n\r\n"
+            ),
+            431
+        );
+        assert_eq!(
+            status(cfg, "POST / HTTP/1.1\r\ncontent-length: 9\r\n\r\n123456789"),
+            413
+        );
+        assert_eq!(
+            status(cfg, "POST / HTTP/1.1\r\ncontent-length: x\r\n\r\n"),
+            400
+        );
+        assert_eq!(
+            status(
+                cfg,
+                "POST / HTTP/1.1\r\ntransfer-encoding: gzip, chunked\r\n\r\n"
+            ),
+            501
+        );
+        // the connection is gone before the body is complete
+        let (code, _, _) = read(cfg, b"POST / HTTP/1.1\r\ncontent-length: 3\r\n\r\n1");
+        assert_eq!(code, MessageReadCode::ConnReadError);
+    }
+
+    #[test]
+    fn test_chunked() {
+        let cfg = config(false);
+        let raw = "POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n\
+            3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\ntrailer: x\r\n\r\nGET";
+        let (code, body, rest) = read(cfg, raw.as_bytes());
+        assert_eq!(code, MessageReadCode::Ok);
+        assert_eq!(body, "abcde");
+        assert_eq!(rest, "GET");
+
+        for raw in [
+            "x\r\nabc\r\n0\r\n\r\n",
+            "3\r\nabcd\r\n0\r\n\r\n",
+            "-3\r\nabc\r\n0\r\n\r\n",
+            "0x3\r\nabc\r\n0\r\n\r\n",
+            "3 \r\nabc\r\n0\r\n\r\n",
+        ] {
+            let raw = format!(
+                "POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n{}",
+                raw
+            );
+            assert_eq!(status(cfg, &raw), 400, "{:?}", raw);
+        }
+        let raw = "POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n\
+            ffffffffffffffffff\r\n";
+        assert_eq!(status(cfg, raw), 400);
+        let raw = "POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n9\r\n";
+        assert_eq!(status(cfg, raw), 413);
+    }
+
+    // known request smuggling payloads, none of them may leave a request behind
+    #[test]
+    fn test_smuggling() {
+        let strict = config(false);
+        let lenient = config(true);
+        let smuggled = "GET /admin HTTP/1.1\r\n\r\n";
+        let payloads = [
+            // CL.CL
+            "content-length: 0\r\ncontent-length: 26",
+            "content-length: 0, 26",
+            "content-length: +26",
+            // CL.TE and TE.CL
+            "content-length: 26\r\ntransfer-encoding: chunked",
+            "transfer-encoding: chunked\r\ncontent-length: 0",
+            // TE obfuscation
+            "transfer-encoding: xchunked",
+            "transfer-encoding: chunked, identity",
+            "transfer-encoding: chunked\r\ntransfer-encoding: identity",
+            "transfer-encoding : chunked",
+            "transfer-encoding:\r\n chunked",
+            "transfer-encoding\t: chunked",
+            " transfer-encoding: chunked",
+        ];
+        for payload in payloads {
+            let raw = format!(
+                "POST / HTTP/1.1\r\n{}\r\n\r\n0\r\n\r\n{}",
+                payload, smuggled
+            );
+            assert_eq!(status(strict, &raw), 400, "{:?}", payload);
+        }
+        // bare LF
+        let raw = format!("POST / HTTP/1.1\ncontent-length: 0\n\n{}", smuggled);
+        assert_eq!(status(strict, &raw), 400);
+        let raw = format!(
+            "POST / HTTP/1.1\r\ncontent-length: 0\nx: 1\r\n\r\n{}",
+            smuggled
+        );
+        assert_eq!(status(strict, &raw), 400);
+        let raw = format!(
+            "POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n0\n\n{}",
+            smuggled
+        );
+        assert_eq!(status(strict, &raw), 400);
+
+        // lenient mode takes the chunked framing and closes after
+        let raw = format!(
+            "POST / HTTP/1.1\r\ncontent-length: 26\r\ntransfer-encoding: chunked\r\n\r\n0\r\n\r\n{}",
+            smuggled
+        );
+        let (code, _, rest) = read(lenient, raw.as_bytes());
+        assert_eq!(code, MessageReadCode::Ok);
+        assert_eq!(rest, smuggled);
+        let raw = "POST / HTTP/1.1\ntransfer-encoding :\n chunked\n\n2\nab\n0\n\n";
+        let (code, body, _) = read(lenient, raw.as_bytes());
+        assert_eq!((code, body.as_str()), (MessageReadCode::Ok, "ab"));
+        // conflicting lengths are never accepted
+        let raw = "POST / HTTP/1.1\r\ncontent-length: 0\r\ncontent-length: 26\r\n\r\n";
+        assert_eq!(status(lenient, raw), 400);
+        let raw = "POST / HTTP/1.1\r\ncontent-length: 2\r\ncontent-length: 2\r\n\r\nab";
+        assert_eq!(status(strict, raw), 200);
+    }
+
+    #[test]
+    fn test_paced_body() {
+        let mut cfg = ServiceConfig::default();
+        cfg.http.autofix(None).unwrap();
+        cfg.tcp.autofix(None).unwrap();
+        cfg.http.body_timeout.0 = std::time::Duration::from_millis(100);
+        cfg.http.min_body_rate = BytesSize(1000);
+        let cfg: &'static ServiceConfig = Box::leak(Box::new(cfg));
+
+        // chunks every 60ms, `sizes[i]` bytes each, then the last chunk
+        let trickle = |sizes: &'static [usize]| {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()
+                .unwrap();
+            rt.block_on(async move {
+                let (client, server) = tokio::io::duplex(4096);
+                let (mut cr, mut cw) = tokio::io::split(client);
+                let (r, w) = tokio::io::split(server);
+                tokio::spawn(async move {
+                    for size in sizes {
+                        let chunk = format!("{:x}\r\n{}\r\n", size, "x".repeat(*size));
+                        _ = cw.write_all(chunk.as_bytes()).await;
+                        tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+                    }
+                    _ = cw.write_all(b"0\r\n\r\n").await;
+                    _ = cr.read_to_end(&mut vec![]).await;
+                });
+                let mut ctx =
+                    ConnContext::new(tokio::io::BufReader::new(r), w, Peer::Unix, None, cfg);
+                let mut msg = Message::default();
+                msg.headers.set("transfer-encoding", "chunked");
+                let mut reader = PacedReader::new(&mut ctx.reader, &cfg.http);
+                let code = msg
+                    .read_body_normal(&mut reader, &mut ctx.buf, &cfg.http)
+                    .await;
+                (code, reader.timed_out(), msg.body.inner().len())
+            })
+        };
+
+        // each chunk buys 60ms or more
+        let (code, timed_out, size) = trickle(&[60, 60, 60, 60]);
+        assert_eq!((code, timed_out, size), (MessageReadCode::Ok, false, 240));
+        // slower than 1000 bytes per second, the deadline passes between chunks
+        let (code, timed_out, _) = trickle(&[1, 1, 1, 1]);
+        assert_eq!(code, MessageReadCode::ConnReadError);
+        assert!(timed_out);
+    }
+}
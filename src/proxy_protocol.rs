@@ -0,0 +1,392 @@
+//! the PROXY protocol header a load balancer sends before the client bytes, see
+//! https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{config::service::ServiceConfig, ctx::Peer, utils::anyhow};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// `PROXY TCP6 <39> <39> 65535 65535\r\n`
+const V1_MAX_LEN: usize = 107;
+
+const PP2_TYPE_ALPN: u8 = 0x01;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_VERSION: u8 = 0x21;
+const PP2_SUBTYPE_SSL_CN: u8 = 0x22;
+const PP2_SUBTYPE_SSL_CIPHER: u8 = 0x23;
+const PP2_SUBTYPE_SSL_SIG_ALG: u8 = 0x24;
+const PP2_SUBTYPE_SSL_KEY_ALG: u8 = 0x25;
+
+const PP2_CLIENT_SSL: u8 = 0x01;
+const PP2_CLIENT_CERT_CONN: u8 = 0x02;
+const PP2_CLIENT_CERT_SESS: u8 = 0x04;
+
+/// the tls connection the load balancer terminated, from the v2 `PP2_TYPE_SSL` tlv.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ProxyTls {
+    pub(crate) version: Option<String>,
+    // common name of the client certificate
+    pub(crate) cn: Option<String>,
+    pub(crate) cipher: Option<String>,
+    pub(crate) sig_alg: Option<String>,
+    pub(crate) key_alg: Option<String>,
+    pub(crate) client_cert: bool,
+    // the client certificate, if any, was verified
+    pub(crate) verified: bool,
+}
+
+/// what the load balancer tells about a connection besides its addresses, v2 only.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ProxyInfo {
+    // the host name the client asked for, usually the sni
+    pub(crate) authority: Option<String>,
+    pub(crate) alpn: Option<Vec<u8>>,
+    pub(crate) unique_id: Option<Vec<u8>>,
+    pub(crate) tls: Option<ProxyTls>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Header {
+    // the client, `None` for a health check of the load balancer or an unknown protocol
+    pub(crate) src: Option<SocketAddr>,
+    pub(crate) dst: Option<SocketAddr>,
+    pub(crate) info: ProxyInfo,
+}
+
+fn parse_v1(line: &[u8]) -> anyhow::Result<Header> {
+    let bad = || anyhow::error(&format!("bad PROXY v1 header `{}`", line.escape_ascii()));
+    let line = match std::str::from_utf8(line) {
+        Ok(v) => v,
+        Err(_) => return bad(),
+    };
+    let line = match line.strip_suffix("\r\n") {
+        Some(v) => v,
+        None => return bad(),
+    };
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(Header::default()),
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let ip = |v: &str| -> Option<IpAddr> {
+                match *proto {
+                    "TCP4" => v.parse::<Ipv4Addr>().ok().map(IpAddr::V4),
+                    _ => v.parse::<Ipv6Addr>().ok().map(IpAddr::V6),
+                }
+            };
+            // no sign, no leading zeros
+            let port = |v: &str| -> Option<u16> {
+                match v.len() > 1 && v.starts_with('0') {
+                    true => None,
+                    false => v.parse::<u16>().ok().filter(|_| !v.starts_with('+')),
+                }
+            };
+            match (ip(src), ip(dst), port(sport), port(dport)) {
+                (Some(src), Some(dst), Some(sport), Some(dport)) => Ok(Header {
+                    src: Some(SocketAddr::new(src, sport)),
+                    dst: Some(SocketAddr::new(dst, dport)),
+                    info: ProxyInfo::default(),
+                }),
+                _ => bad(),
+            }
+        }
+        _ => bad(),
+    }
+}
+
+fn tlvs(mut buf: &[u8]) -> anyhow::Result<Vec<(u8, &[u8])>> {
+    let mut items = vec![];
+    while !buf.is_empty() {
+        if buf.len() < 3 {
+            return anyhow::error("truncated PROXY v2 tlv");
+        }
+        let len = u16::from_be_bytes([buf[1], buf[2]]) as usize;
+        if buf.len() < 3 + len {
+            return anyhow::error("truncated PROXY v2 tlv");
+        }
+        items.push((buf[0], &buf[3..3 + len]));
+        buf = &buf[3 + len..];
+    }
+    Ok(items)
+}
+
+fn text(v: &[u8]) -> Option<String> {
+    String::from_utf8(v.to_vec()).ok()
+}
+
+// `head` is the 4 bytes after the signature, `body` the `len` bytes after them
+fn parse_v2(head: &[u8; 4], body: &[u8]) -> anyhow::Result<Header> {
+    let (version, command) = (head[0] >> 4, head[0] & 0x0f);
+    if version != 2 {
+        return anyhow::error(&format!("unsupported PROXY protocol version {}", version));
+    }
+    let (family, transport) = (head[1] >> 4, head[1] & 0x0f);
+
+    let (src, dst, rest) = match family {
+        // AF_INET
+        0x1 if body.len() >= 12 => {
+            let ip = |v: &[u8]| IpAddr::V4(Ipv4Addr::new(v[0], v[1], v[2], v[3]));
+            let src = SocketAddr::new(ip(&body[0..4]), u16::from_be_bytes([body[8], body[9]]));
+            let dst = SocketAddr::new(ip(&body[4..8]), u16::from_be_bytes([body[10], body[11]]));
+            (Some(src), Some(dst), &body[12..])
+        }
+        // AF_INET6
+        0x2 if body.len() >= 36 => {
+            let ip = |v: &[u8]| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(v);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            let src = SocketAddr::new(ip(&body[0..16]), u16::from_be_bytes([body[32], body[33]]));
+            let dst = SocketAddr::new(ip(&body[16..32]), u16::from_be_bytes([body[34], body[35]]));
+            (Some(src), Some(dst), &body[36..])
+        }
+        // AF_UNIX, the client has no address to use
+        0x3 if body.len() >= 216 => (None, None, &body[216..]),
+        // AF_UNSPEC
+        0x0 => (None, None, body),
+        _ => {
+            return anyhow::error(&format!(
+                "bad PROXY v2 address block, family {}, {} bytes",
+                family,
+                body.len()
+            ));
+        }
+    };
+
+    let mut info = ProxyInfo::default();
+    for (ty, value) in tlvs(rest)? {
+        match ty {
+            PP2_TYPE_ALPN => info.alpn = Some(value.to_vec()),
+            PP2_TYPE_AUTHORITY => info.authority = text(value),
+            PP2_TYPE_UNIQUE_ID => info.unique_id = Some(value.to_vec()),
+            PP2_TYPE_SSL if value.len() >= 5 => {
+                let client = value[0];
+                let verify = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                if client & PP2_CLIENT_SSL == 0 {
+                    continue;
+                }
+                let mut tls = ProxyTls {
+                    client_cert: client & (PP2_CLIENT_CERT_CONN | PP2_CLIENT_CERT_SESS) != 0,
+                    verified: verify == 0,
+                    ..Default::default()
+                };
+                for (ty, value) in tlvs(&value[5..])? {
+                    match ty {
+                        PP2_SUBTYPE_SSL_VERSION => tls.version = text(value),
+                        PP2_SUBTYPE_SSL_CN => tls.cn = text(value),
+                        PP2_SUBTYPE_SSL_CIPHER => tls.cipher = text(value),
+                        PP2_SUBTYPE_SSL_SIG_ALG => tls.sig_alg = text(value),
+                        PP2_SUBTYPE_SSL_KEY_ALG => tls.key_alg = text(value),
+                        _ => {}
+                    }
+                }
+                info.tls = Some(tls);
+            }
+            _ => {}
+        }
+    }
+
+    match (command, transport) {
+        // LOCAL, a health check of the load balancer itself
+        (0x0, _) => Ok(Header {
+            src: None,
+            dst: None,
+            info,
+        }),
+        // PROXY over a stream, anything else is forwarded for an unknown protocol
+        (0x1, 0x1) => Ok(Header { src, dst, info }),
+        (0x1, _) => Ok(Header {
+            src: None,
+            dst: None,
+            info,
+        }),
+        _ => anyhow::error(&format!("unsupported PROXY v2 command {}", command)),
+    }
+}
+
+/// read the header, not a byte more: the tls handshake or the http request follows it.
+pub(crate) async fn read<R: AsyncRead + Unpin>(r: &mut R) -> anyhow::Result<Header> {
+    let mut buf = vec![0u8; 12];
+    anyhow::result(r.read_exact(&mut buf).await)?;
+
+    if buf.as_slice() == V2_SIGNATURE {
+        let mut head = [0u8; 4];
+        anyhow::result(r.read_exact(&mut head).await)?;
+        let len = u16::from_be_bytes([head[2], head[3]]) as usize;
+        let mut body = vec![0u8; len];
+        anyhow::result(r.read_exact(&mut body).await)?;
+        return parse_v2(&head, &body);
+    }
+
+    if buf.starts_with(b"PROXY ") {
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() >= V1_MAX_LEN {
+                return anyhow::error("PROXY v1 header too long");
+            }
+            buf.push(anyhow::result(r.read_u8().await)?);
+        }
+        return parse_v1(&buf);
+    }
+
+    anyhow::error("no PROXY protocol header")
+}
+
+/// the client of a connection accepted from `peer`, read from the PROXY protocol header if
+/// the service expects one. `None` if the connection is to be closed.
+pub(crate) async fn accept<R: AsyncRead + Unpin>(
+    cfg: &ServiceConfig,
+    stream: &mut R,
+    peer: Peer,
+) -> Option<(Peer, Option<ProxyInfo>)> {
+    let pp = match cfg.tcp.proxy_protocol.as_ref() {
+        Some(v) => v,
+        None => return Some((peer, None)),
+    };
+    match peer.ip() {
+        Some(ip) if !pp.trusts(&ip) => {
+            #[cfg(debug_assertions)]
+            {
+                log::trace!("PROXY protocol from untrusted peer, {}", peer);
+            }
+            return None;
+        }
+        _ => {}
+    }
+
+    match tokio::time::timeout(pp.timeout.0, read(stream)).await {
+        Ok(Ok(header)) => match header.src {
+            Some(src) => Some((Peer::Ip(src), Some(header.info))),
+            None => Some((peer, Some(header.info))),
+        },
+        Ok(Err(e)) => {
+            #[cfg(debug_assertions)]
+            {
+                log::trace!("PROXY protocol failed, {}, {}", peer, e);
+            }
+            None
+        }
+        Err(_) => {
+            #[cfg(debug_assertions)]
+            {
+                log::trace!("PROXY protocol timeout, {}", peer);
+            }
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(mut input: &[u8]) -> (anyhow::Result<Header>, Vec<u8>) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let header = read(&mut input).await;
+            (header, input.to_vec())
+        })
+    }
+
+    #[test]
+    fn test_v1() {
+        let (header, rest) = read_all(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /");
+        let header = header.unwrap();
+        assert_eq!(header.src, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(header.dst, Some("198.51.100.1:443".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (header, _) = read_all(b"PROXY TCP6 2001:db8::1 2001:db8::2 1 2\r\n");
+        assert_eq!(
+            header.unwrap().src,
+            Some("[2001:db8::1]:1".parse().unwrap())
+        );
+
+        let (header, rest) = read_all(b"PROXY UNKNOWN\r\nGET");
+        assert_eq!(header.unwrap().src, None);
+        assert_eq!(rest, b"GET");
+
+        for bad in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 198.51.100.1 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 01 2\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 2\r\n",
+            b"PROXY TCP4  192.0.2.1 198.51.100.1 1 2\r\n",
+            b"GET / HTTP/1.1\r\n\r\n",
+        ] {
+            assert!(read_all(bad).0.is_err(), "{}", bad.escape_ascii());
+        }
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(120));
+        assert!(read_all(long.as_bytes()).0.is_err());
+    }
+
+    fn v2(command: u8, family: u8, addrs: &[u8], tlvs: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(0x20 | command);
+        buf.push(family);
+        buf.extend(((addrs.len() + tlvs.len()) as u16).to_be_bytes());
+        buf.extend(addrs);
+        buf.extend(tlvs);
+        buf
+    }
+
+    fn tlv(ty: u8, value: &[u8]) -> Vec<u8> {
+        let mut buf = vec![ty];
+        buf.extend((value.len() as u16).to_be_bytes());
+        buf.extend(value);
+        buf
+    }
+
+    #[test]
+    fn test_v2() {
+        let addrs = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        let mut ssl = vec![PP2_CLIENT_SSL | PP2_CLIENT_CERT_CONN, 0, 0, 0, 0];
+        ssl.extend(tlv(PP2_SUBTYPE_SSL_VERSION, b"TLSv1.3"));
+        ssl.extend(tlv(PP2_SUBTYPE_SSL_CN, b"client"));
+        let mut tlvs = tlv(PP2_TYPE_AUTHORITY, b"example.com");
+        tlvs.extend(tlv(PP2_TYPE_ALPN, b"h2"));
+        tlvs.extend(tlv(PP2_TYPE_SSL, &ssl));
+        let mut input = v2(0x1, 0x11, &addrs, &tlvs);
+        input.extend(b"\x16\x03\x01");
+
+        let (header, rest) = read_all(&input);
+        let header = header.unwrap();
+        assert_eq!(header.src, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(header.dst, Some("198.51.100.1:443".parse().unwrap()));
+        assert_eq!(header.info.authority.as_deref(), Some("example.com"));
+        assert_eq!(header.info.alpn.as_deref(), Some(&b"h2"[..]));
+        let tls = header.info.tls.unwrap();
+        assert_eq!(tls.version.as_deref(), Some("TLSv1.3"));
+        assert_eq!(tls.cn.as_deref(), Some("client"));
+        assert!(tls.client_cert && tls.verified);
+        assert_eq!(rest, b"\x16\x03\x01");
+
+        let mut addrs = vec![0u8; 36];
+        addrs[15] = 1;
+        addrs[32..34].copy_from_slice(&8080u16.to_be_bytes());
+        let (header, _) = read_all(&v2(0x1, 0x21, &addrs, &[]));
+        assert_eq!(header.unwrap().src, Some("[::1]:8080".parse().unwrap()));
+
+        // a health check of the load balancer keeps the peer address
+        let (header, rest) = read_all(&v2(0x0, 0x00, &[], &[]));
+        assert_eq!(header.unwrap().src, None);
+        assert!(rest.is_empty());
+
+        // truncated address block and tlv
+        assert!(read_all(&v2(0x1, 0x11, &[1, 2, 3], &[])).0.is_err());
+        assert!(
+            read_all(&v2(0x1, 0x11, &[0; 12], &[PP2_TYPE_ALPN, 0, 9, 1]))
+                .0
+                .is_err()
+        );
+        // version 1 in a v2 header
+        let mut input = v2(0x1, 0x11, &[0; 12], &[]);
+        input[12] = 0x11;
+        assert!(read_all(&input).0.is_err());
+    }
+}
@@ -1,15 +1,25 @@
+use std::cell::OnceCell;
+
 use crate::{
-    internal::multi_map::MultiMap,
+    config::form::FormConfig,
+    cookie::{self, CookieKeys},
+    form::{self, Form, FormError},
+    internal::{header, multi_map::MultiMap, uri::Uri},
     message::{Message, MessageBody},
 };
 
 pub(crate) struct RequestReader<'a> {
     msg: &'a Message,
+    // parsed on first use
+    cookies: OnceCell<MultiMap>,
 }
 
 impl<'a> From<&'a Message> for RequestReader<'a> {
     fn from(value: &'a Message) -> Self {
-        Self { msg: value }
+        Self {
+            msg: value,
+            cookies: OnceCell::new(),
+        }
     }
 }
 
@@ -24,6 +34,11 @@ impl<'a> RequestReader<'a> {
         &self.msg.firstline.1
     }
 
+    /// the request target parsed, `Err` for a target a server must answer with `400`.
+    pub fn uri(&self) -> Result<Uri, ()> {
+        Uri::parse(&self.msg.firstline.0, &self.msg.firstline.1)
+    }
+
     pub fn version(&self) -> Result<(u8, u8), ()> {
         match (&self.msg.firstline.2).find("/") {
             None => Err(()),
@@ -49,7 +64,7 @@ impl<'a> RequestReader<'a> {
                         }
 
                         let minor;
-                        match versions[..idx].parse::<u8>() {
+                        match versions[idx + 1..].parse::<u8>() {
                             Err(_) => {
                                 return Err(());
                             }
@@ -64,6 +79,84 @@ impl<'a> RequestReader<'a> {
         }
     }
 
+    /// whether the client keeps the connection open, HTTP/1.0 only with
+    /// `connection: keep-alive`, RFC 9112 section 9.3.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.msg.headers.getall("connection");
+        if header::contains(connection, "close") {
+            return false;
+        }
+        match self.version() {
+            Ok((1, 0)) => header::contains(connection, "keep-alive"),
+            Ok((major, _)) => major >= 1,
+            Err(_) => false,
+        }
+    }
+
+    /// whether the client waits for `100 continue` before sending the body, `Err` for an
+    /// expectation other than `100-continue`. HTTP/1.0 clients are ignored, RFC 9110 section
+    /// 10.1.1.
+    pub fn expects_continue(&self) -> Result<bool, ()> {
+        if self.msg.firstline.2 == "HTTP/1.0" {
+            return Ok(false);
+        }
+        let mut expects = false;
+        for v in header::tokens(self.msg.headers.getall("expect")) {
+            if v != "100-continue" {
+                return Err(());
+            }
+            expects = true;
+        }
+        Ok(expects)
+    }
+
+    pub fn cookies(&self) -> &MultiMap {
+        self.cookies
+            .get_or_init(|| cookie::parse(self.msg.headers.getall("cookie")))
+    }
+
+    #[inline]
+    pub fn cookie(&self, name: &str) -> Option<&String> {
+        self.cookies().get(name)
+    }
+
+    /// the value of a cookie set with `SetCookie::signed`, `None` if missing or changed.
+    pub fn signed_cookie(&self, name: &str, keys: &CookieKeys) -> Option<String> {
+        keys.verify(name, self.cookie(name)?)
+    }
+
+    /// the value of a cookie set with `SetCookie::encrypted`, `None` if missing or changed.
+    pub fn encrypted_cookie(&self, name: &str, keys: &CookieKeys) -> Option<String> {
+        keys.decrypt(name, self.cookie(name)?)
+    }
+
+    /// the fields of an `application/x-www-form-urlencoded` body.
+    pub fn form(&self, cfg: &FormConfig) -> Result<MultiMap, FormError> {
+        let (mt, _) = form::media_type(self.content_type());
+        if mt != "application/x-www-form-urlencoded" {
+            return Err(FormError::UnsupportedType);
+        }
+        let mut fields = MultiMap::new();
+        form::parse_urlencoded(self.msg.body.inner(), &mut fields, cfg.max_fields)?;
+        Ok(fields)
+    }
+
+    /// the parts of a `multipart/form-data` body, read by the server as it came for a
+    /// service asking with `Service::multipart`; the body is left empty.
+    pub fn multipart(&self) -> Result<&'a Form, FormError> {
+        match self.msg.form.as_ref() {
+            Some(form) => Ok(form),
+            None => Err(FormError::UnsupportedType),
+        }
+    }
+
+    fn content_type(&self) -> &str {
+        match self.msg.headers.get("content-type") {
+            Some(v) => v.as_str(),
+            None => "",
+        }
+    }
+
     pub fn headers(&self) -> &MultiMap {
         &self.msg.headers
     }
@@ -72,3 +165,62 @@ impl<'a> RequestReader<'a> {
         &self.msg.body
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(version: &str, expect: &[&str]) -> Message {
+        let mut msg = Message::default();
+        msg.firstline.2.push_str(version);
+        for v in expect {
+            msg.headers.append("expect", v);
+        }
+        msg
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let mut msg = request("HTTP/1.0", &[]);
+        assert_eq!(RequestReader::from(&msg).version(), Ok((1, 0)));
+        assert!(!RequestReader::from(&msg).keep_alive());
+        msg.headers.append("connection", "Keep-Alive");
+        assert!(RequestReader::from(&msg).keep_alive());
+
+        let mut msg = request("HTTP/1.1", &[]);
+        assert_eq!(RequestReader::from(&msg).version(), Ok((1, 1)));
+        assert!(RequestReader::from(&msg).keep_alive());
+        msg.headers.append("connection", "te, close");
+        assert!(!RequestReader::from(&msg).keep_alive());
+    }
+
+    #[test]
+    fn test_cookies() {
+        let keys = CookieKeys::derive(&[7; 32]);
+        let mut msg = request("HTTP/1.1", &[]);
+        let sealed = keys.encrypt("b", "2");
+        msg.headers.append(
+            "cookie",
+            &format!("a={}; b={}", keys.sign("a", "1"), sealed),
+        );
+        msg.headers.append("cookie", "c=3");
+        let req = RequestReader::from(&msg);
+        assert_eq!(req.cookie("c").unwrap(), "3");
+        assert_eq!(req.signed_cookie("a", &keys).unwrap(), "1");
+        assert_eq!(req.encrypted_cookie("b", &keys).unwrap(), "2");
+        assert!(req.signed_cookie("c", &keys).is_none());
+        assert!(req.encrypted_cookie("a", &keys).is_none());
+    }
+
+    #[test]
+    fn test_expects_continue() {
+        let msg = request("HTTP/1.1", &["100-Continue"]);
+        assert_eq!(RequestReader::from(&msg).expects_continue(), Ok(true));
+        let msg = request("HTTP/1.1", &[]);
+        assert_eq!(RequestReader::from(&msg).expects_continue(), Ok(false));
+        let msg = request("HTTP/1.1", &["100-continue", "x"]);
+        assert_eq!(RequestReader::from(&msg).expects_continue(), Err(()));
+        let msg = request("HTTP/1.0", &["x"]);
+        assert_eq!(RequestReader::from(&msg).expects_continue(), Ok(false));
+    }
+}
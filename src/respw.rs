@@ -1,4 +1,4 @@
-use crate::message::Message;
+use crate::{cookie::SetCookie, message::Message, utils::anyhow};
 
 pub(crate) struct ResponseWriter<'a> {
     msg: &'a mut Message,
@@ -13,6 +13,7 @@ impl<'a> From<&'a mut Message> for ResponseWriter<'a> {
 impl<'a> ResponseWriter<'a> {
     #[inline]
     pub fn version(&mut self, major: u8, minor: u8) -> &mut Self {
+        self.msg.firstline.0.clear();
         self.msg
             .firstline
             .0
@@ -22,6 +23,8 @@ impl<'a> ResponseWriter<'a> {
 
     #[inline]
     pub fn code(&mut self, code: u16, reason: &str) -> &mut Self {
+        self.msg.firstline.1.clear();
+        self.msg.firstline.2.clear();
         self.msg.firstline.1.push_str(code.to_string().as_str());
         self.msg.firstline.2.push_str(reason);
         self
@@ -45,6 +48,12 @@ impl<'a> ResponseWriter<'a> {
         self
     }
 
+    /// append a `set-cookie` header, `Err` for a cookie that can not be sent.
+    pub fn setcookie(&mut self, cookie: &SetCookie) -> anyhow::Result<&mut Self> {
+        self.msg.headers.append("set-cookie", &cookie.to_header()?);
+        Ok(self)
+    }
+
     #[inline]
     pub fn end(self) -> &'a mut Message {
         self.msg
@@ -1,44 +1,361 @@
 use std::sync::Arc;
 
 use crate::{
-    ctx::ConnContext,
-    http2,
-    message::{Message, MessageReadCode},
+    config::service::ServiceConfig,
+    ctx::{ConnContext, Peer},
+    form, http2,
+    internal::header,
+    message::{ChunkedReader, Framing, Message, MessageReadCode, PacedReader},
     protocols::Protocol,
+    proxy_protocol::ProxyInfo,
+    reqr::RequestReader,
+    respw::ResponseWriter,
     services::common::Service,
+    shutdown,
+    tls::{acme::challenge, TlsInfo},
     ws,
 };
 
+#[cfg(feature = "http3")]
+fn advertise_http3(cfg: &ServiceConfig, resp: &mut Message) {
+    match cfg.quic.as_ref() {
+        Some(quic) => {
+            if resp.headers.get("alt-svc").is_none() {
+                resp.headers.set("alt-svc", &quic.alt_svc);
+            }
+        }
+        None => {}
+    }
+}
+
+// answer a request that is not read to the end, the connection is closed after
+async fn reply_and_close<R, W>(
+    ctx: &mut ConnContext<R, W>,
+    resp: &mut Message,
+    code: u16,
+    reason: &str,
+    headers: &[(&str, &str)],
+) where
+    R: tokio::io::AsyncBufReadExt + Unpin,
+    W: tokio::io::AsyncWriteExt + Unpin,
+{
+    resp.clear();
+    error_response(ctx.config, resp, code, reason);
+    let mut w = ResponseWriter::from(&mut *resp);
+    for (k, v) in headers {
+        w.setheader(k, v);
+    }
+    send_and_close(ctx, resp).await;
+}
+
+/// a response of the server itself, with the error page of the service for `code`.
+pub(crate) fn error_response(cfg: &ServiceConfig, resp: &mut Message, code: u16, reason: &str) {
+    let mut w = ResponseWriter::from(&mut *resp);
+    w.version(1, 1).code(code, reason);
+    match cfg.http.error_page(code) {
+        Some(page) => {
+            w.setheader("content-type", &page.content_type);
+            resp.body.write_all_to_internal(&page.body);
+        }
+        None => {}
+    }
+}
+
+async fn send_and_close<R, W>(ctx: &mut ConnContext<R, W>, resp: &mut Message)
+where
+    R: tokio::io::AsyncBufReadExt + Unpin,
+    W: tokio::io::AsyncWriteExt + Unpin,
+{
+    ResponseWriter::from(&mut *resp).setheader("connection", "close");
+    match tokio::time::timeout(ctx.config.http.write_timeout.0, resp.write_to(ctx)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => log::debug!("send response failed, {}", e),
+        Err(_) => log::debug!("send response timed out"),
+    }
+}
+
+// whether the connection is kept after this response, its `connection` header tells the
+// client. RFC 9112 section 9.3
+fn reuse(
+    cfg: &ServiceConfig,
+    req: &Message,
+    resp: &mut Message,
+    served: usize,
+    keep_alive: bool,
+) -> bool {
+    let reuse = keep_alive
+        && cfg.http.keep_alive()
+        && (cfg.http.max_requests < 1 || served < cfg.http.max_requests)
+        && !shutdown::triggered()
+        && RequestReader::from(req).keep_alive()
+        && !header::contains(resp.headers.getall("connection"), "close");
+    let mut w = ResponseWriter::from(resp);
+    if !reuse {
+        w.setheader("connection", "close");
+    } else if req.firstline.2 == "HTTP/1.0" {
+        w.setheader("connection", "keep-alive");
+    }
+    reuse
+}
+
+// whether the body of a request is to be read, it is not if the request is answered here.
+// `100 continue` is sent only when the body will be read, RFC 9110 section 10.1.1
+async fn before_body<R, W>(
+    service: &impl Service,
+    ctx: &mut ConnContext<R, W>,
+    req: &Message,
+    resp: &mut Message,
+) -> bool
+where
+    R: tokio::io::AsyncBufReadExt + Unpin + Send,
+    W: tokio::io::AsyncWriteExt + Unpin + Send,
+{
+    let expects = match RequestReader::from(req).expects_continue() {
+        Ok(v) => v,
+        Err(_) => {
+            reply_and_close(ctx, resp, 417, "Expectation Failed", &[]).await;
+            return false;
+        }
+    };
+    match req.framing() {
+        Ok(Framing::Length(0)) => return true,
+        Ok(Framing::Length(size)) if size > ctx.config.http.max_body_size.0 => {
+            reply_and_close(ctx, resp, 413, "Content Too Large", &[]).await;
+            return false;
+        }
+        _ => {}
+    }
+    if !service.accept_body(ctx, req, resp) {
+        #[cfg(debug_assertions)]
+        {
+            log::trace!("request body rejected, {}", ctx.addr);
+        }
+        send_and_close(ctx, resp).await;
+        return false;
+    }
+    if !expects {
+        return true;
+    }
+    let result = tokio::time::timeout(ctx.config.http.write_timeout.0, async {
+        ctx.writer
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await?;
+        ctx.writer.flush().await
+    })
+    .await;
+    match result {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            log::debug!("send 100 continue failed, {}", e);
+            false
+        }
+        Err(_) => {
+            log::debug!("send 100 continue timed out");
+            false
+        }
+    }
+}
+
+// the body of a request, `Err` with the response if it comes slower than `min_body_rate`
+// allows or is a form that can not be read. a form goes to `Message::form` as it arrives
+// if the service asks for it, see `Service::multipart`
+async fn read_body<R, W>(
+    service: &impl Service,
+    ctx: &mut ConnContext<R, W>,
+    req: &mut Message,
+) -> Result<MessageReadCode, (u16, &'static str)>
+where
+    R: tokio::io::AsyncBufReadExt + Unpin,
+    W: tokio::io::AsyncWriteExt + Unpin,
+{
+    let cfg = &ctx.config.http;
+    let mut reader = PacedReader::new(&mut ctx.reader, cfg);
+    let result = if service.multipart() && form::is_multipart(req) {
+        let read = match req.framing() {
+            Ok(Framing::Length(size)) => {
+                let body = tokio::io::AsyncReadExt::take(&mut reader, size as u64);
+                form::read_multipart(req, body, &cfg.forms).await
+            }
+            Ok(Framing::Chunked) => {
+                let body = ChunkedReader::new(&mut reader, cfg);
+                form::read_multipart(req, body, &cfg.forms).await
+            }
+            Err(code) => return Ok(code),
+        };
+        match read {
+            Ok(_) => Ok(MessageReadCode::Ok),
+            Err(e) => {
+                #[cfg(debug_assertions)]
+                {
+                    log::trace!("read request form failed, {:?}", e);
+                }
+                Err(e.status())
+            }
+        }
+    } else {
+        Ok(req.read_body_normal(&mut reader, &mut ctx.buf, cfg).await)
+    };
+    if reader.timed_out() {
+        return Err((408, "Request Timeout"));
+    }
+    result
+}
+
+// how long a rejected client may take to send its request
+const REJECT_READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// answer `503` to a connection over a limit, see `limits`.
+pub(crate) async fn reject<
+    R: tokio::io::AsyncRead + Unpin + Send,
+    W: tokio::io::AsyncWrite + Unpin + Send,
+>(
+    cfg: &'static ServiceConfig,
+    r: R,
+    w: W,
+    addr: Peer,
+) {
+    #[cfg(debug_assertions)]
+    {
+        log::trace!(service = cfg.name.as_str(); "connection rejected by limits, {}", addr);
+    }
+
+    let _guard = shutdown::ConnGuard::new();
+    let r = tokio::io::BufReader::with_capacity(cfg.tcp.read_stream_buf_size.0, r);
+    let w = tokio::io::BufWriter::with_capacity(cfg.tcp.read_stream_buf_size.0, w);
+    let mut ctx = ConnContext::new(r, w, addr, None, cfg);
+    let mut reqmsg = Message::default();
+    let mut respmsg = Message::default();
+
+    // a response sent before the request is read may be lost to a reset
+    let timeout = std::cmp::min(cfg.http.header_timeout.0, REJECT_READ_TIMEOUT);
+    _ = tokio::time::timeout(timeout, reqmsg.read_headers(&mut ctx)).await;
+    let retry_after = cfg.tcp.limits.retry_after_secs().to_string();
+    reply_and_close(
+        &mut ctx,
+        &mut respmsg,
+        503,
+        "Service Unavailable",
+        &[("retry-after", retry_after.as_str())],
+    )
+    .await;
+}
+
 pub(crate) async fn serve<
     R: tokio::io::AsyncRead + Unpin + Send,
     W: tokio::io::AsyncWrite + Unpin + Send,
 >(
-    service: Arc<impl Service>,
+    service: Arc<impl Service + Send + Sync + 'static>,
     r: R,
     w: W,
-    addr: std::net::SocketAddr,
-    over_tls: bool,
+    addr: Peer,
+    tls: Option<TlsInfo>,
+    proxy: Option<ProxyInfo>,
 ) {
     #[cfg(debug_assertions)]
     {
         log::trace!(service = service.config().name.as_str(); "connection made, {}", addr);
     }
 
+    let _guard = shutdown::ConnGuard::new();
     let cfg = service.config();
+    let over_tls = tls.is_some();
+    let h2c = !over_tls && cfg.http.h2c.unwrap_or(false);
+    // the bytes read to tell http2 from http1 are read again in front of the rest
+    let mut r = r;
+    let mut head = vec![];
+    if h2c && http2::read_preface(cfg, &mut r, &mut head).await.is_err() {
+        return;
+    }
+    let r = tokio::io::AsyncReadExt::chain(std::io::Cursor::new(head), r);
     let r = tokio::io::BufReader::with_capacity(cfg.tcp.read_stream_buf_size.0, r);
     let w = tokio::io::BufWriter::with_capacity(cfg.tcp.read_stream_buf_size.0, w);
-    let mut ctx = ConnContext::new(r, w, addr, over_tls, service.config());
+    let mut ctx = ConnContext::new(r, w, addr, tls, service.config());
+    ctx.proxy = proxy;
+    if h2c && ctx.reader.get_ref().get_ref().0.get_ref() == http2::PREFACE {
+        return http2::serve(service, ctx, None).await;
+    }
 
     let mut reqmsg = Message::default();
     let mut respmsg = Message::default();
+    // pipelined requests wait in `ctx.reader` and are answered one by one, in order
+    let mut served = 0;
 
     loop {
-        match reqmsg.read_headers(&mut ctx).await {
-            MessageReadCode::Ok => match reqmsg.read_const_length_body(&mut ctx).await {
-                MessageReadCode::Ok => {
-                    match service.http(&ctx, &mut reqmsg, &mut respmsg).await {
-                        Ok(next_protocol) => match (&mut respmsg).write_to(&mut ctx).await {
-                            Ok(_) => match next_protocol {
+        // an idle keep-alive connection is closed at once on shutdown
+        tokio::select! {
+            result = tokio::time::timeout(cfg.http.idle_timeout.0, tokio::io::AsyncBufReadExt::fill_buf(&mut ctx.reader)) => {
+                match result {
+                    Ok(Ok(bytes)) if !bytes.is_empty() => {}
+                    _ => break,
+                }
+            },
+            _ = shutdown::wait() => {
+                break;
+            }
+        }
+
+        let code =
+            match tokio::time::timeout(cfg.http.header_timeout.0, reqmsg.read_headers(&mut ctx))
+                .await
+            {
+                Ok(code) => code,
+                Err(_) => {
+                    #[cfg(debug_assertions)]
+                    {
+                        log::trace!("read request header timed out, {}", ctx.addr);
+                    }
+                    reply_and_close(&mut ctx, &mut respmsg, 408, "Request Timeout", &[]).await;
+                    break;
+                }
+            };
+        match code {
+            MessageReadCode::Ok
+                if !before_body(service.as_ref(), &mut ctx, &reqmsg, &mut respmsg).await =>
+            {
+                break;
+            }
+            MessageReadCode::Ok => match read_body(service.as_ref(), &mut ctx, &mut reqmsg).await {
+                Err((code, reason)) => {
+                    #[cfg(debug_assertions)]
+                    {
+                        log::trace!("read request body failed, {}, {}", ctx.addr, code);
+                    }
+                    reply_and_close(&mut ctx, &mut respmsg, code, reason, &[]).await;
+                    break;
+                }
+                Ok(MessageReadCode::Ok) => {
+                    let result = if h2c
+                        && http2::upgrade(
+                            &RequestReader::from(&reqmsg),
+                            &mut ResponseWriter::from(&mut respmsg),
+                        ) {
+                        Ok(Protocol::Http2)
+                    } else if challenge::respond_http01(&reqmsg, &mut respmsg) {
+                        Ok(Protocol::Current { keep_alive: true })
+                    } else {
+                        service.http(&ctx, &mut reqmsg, &mut respmsg).await
+                    };
+                    served += 1;
+                    let result = match result {
+                        Ok(Protocol::Current { keep_alive }) => Ok(Protocol::Current {
+                            keep_alive: reuse(cfg, &reqmsg, &mut respmsg, served, keep_alive),
+                        }),
+                        v => v,
+                    };
+                    #[cfg(feature = "http3")]
+                    {
+                        if over_tls && result.is_ok() {
+                            advertise_http3(cfg, &mut respmsg);
+                        }
+                    }
+                    match result {
+                        Ok(next_protocol) => match tokio::time::timeout(
+                            cfg.http.write_timeout.0,
+                            (&mut respmsg).write_to(&mut ctx),
+                        )
+                        .await
+                        {
+                            Ok(Ok(_)) => match next_protocol {
                                 Protocol::Current { keep_alive } => {
                                     if !keep_alive {
                                         break;
@@ -51,13 +368,17 @@ pub(crate) async fn serve<
                                     return ws::serve(ctx, reqmsg).await;
                                 }
                                 Protocol::Http2 => {
-                                    return http2::serve(ctx, reqmsg).await;
+                                    return http2::serve(service, ctx, Some(reqmsg)).await;
                                 }
                             },
-                            Err(e) => {
+                            Ok(Err(e)) => {
                                 log::debug!("send response failed, {}", e);
                                 break;
                             }
+                            Err(_) => {
+                                log::debug!("send response timed out");
+                                break;
+                            }
                         },
                         Err(e) => {
                             log::error!(service=cfg.name.as_str(); "handle failed, {}", e);
@@ -65,14 +386,20 @@ pub(crate) async fn serve<
                         }
                     };
                 }
-                MessageReadCode::ConnReadError => {
+                Ok(MessageReadCode::ConnReadError) => {
                     break;
                 }
-                e => {
+                Ok(e) => {
                     #[cfg(debug_assertions)]
                     {
                         log::trace!("read request body failed, {:?}", e);
                     }
+                    match e.status() {
+                        Some((code, reason)) => {
+                            reply_and_close(&mut ctx, &mut respmsg, code, reason, &[]).await;
+                        }
+                        None => {}
+                    }
                     break;
                 }
             },
@@ -84,6 +411,12 @@ pub(crate) async fn serve<
                 {
                     log::trace!("read request header failed, {:?}", e);
                 }
+                match e.status() {
+                    Some((code, reason)) => {
+                        reply_and_close(&mut ctx, &mut respmsg, code, reason, &[]).await;
+                    }
+                    None => {}
+                }
                 break;
             }
         }
@@ -94,3 +427,217 @@ pub(crate) async fn serve<
         log::trace!(service = service.config().name.as_str(); "connection lost, {}", addr);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::utils::anyhow;
+
+    // answers with the path of the request, and the form read for it if `.1`
+    struct Echo(&'static ServiceConfig, bool);
+
+    impl Service for Echo {
+        fn config(&self) -> &'static ServiceConfig {
+            self.0
+        }
+
+        async fn init(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn http<
+            R: tokio::io::AsyncBufReadExt + Unpin + Send,
+            W: tokio::io::AsyncWriteExt + Unpin + Send,
+        >(
+            &self,
+            _ctx: &ConnContext<R, W>,
+            req: &mut Message,
+            resp: &mut Message,
+        ) -> impl std::future::Future<Output = anyhow::Result<Protocol>> + Send {
+            let reader = RequestReader::from(&*req);
+            let mut out = reader.rawuri().clone();
+            match reader.multipart() {
+                Ok(form) => {
+                    let sizes: Vec<String> = form
+                        .files
+                        .iter()
+                        .map(|v| match &v.data {
+                            form::PartData::Memory(data) => format!("{}", data.len()),
+                            form::PartData::File(tmp) => format!("{}(file)", tmp.size()),
+                        })
+                        .collect();
+                    out.push_str(&format!(
+                        " a={} files={} body={}",
+                        form.fields.get("a").map_or("", |v| v.as_str()),
+                        sizes.join(","),
+                        req.body.inner().len()
+                    ));
+                }
+                Err(_) => {}
+            }
+            ResponseWriter::from(&mut *resp)
+                .version(1, 1)
+                .code(200, "OK");
+            resp.body.write_all_to_internal(out.as_bytes());
+            async { Ok(Protocol::Current { keep_alive: true }) }
+        }
+
+        fn multipart(&self) -> bool {
+            self.1
+        }
+    }
+
+    fn config() -> ServiceConfig {
+        let mut cfg = ServiceConfig::default();
+        cfg.http.autofix(None).unwrap();
+        cfg.tcp.autofix(None).unwrap();
+        cfg
+    }
+
+    // the responses to `raw` sent at once, until the server closes the connection
+    fn exchange(max_requests: usize, raw: &str) -> String {
+        let mut cfg = config();
+        cfg.http.max_requests = max_requests;
+        talk(Echo(Box::leak(Box::new(cfg)), false), raw)
+    }
+
+    fn talk(service: Echo, raw: &str) -> String {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let (r, w) = tokio::io::split(server);
+            let addr: std::net::SocketAddr = "127.0.0.1:80".parse().unwrap();
+            let task = tokio::spawn(serve(Arc::new(service), r, w, addr.into(), None, None));
+            let (mut cr, mut cw) = tokio::io::split(client);
+            cw.write_all(raw.as_bytes()).await.unwrap();
+            let mut out = String::new();
+            _ = cr.read_to_string(&mut out).await;
+            task.await.unwrap();
+            out
+        })
+    }
+
+    fn paths(out: &str) -> Vec<(&str, bool)> {
+        out.split("HTTP/1.1 200 OK\r\n")
+            .skip(1)
+            .map(|v| {
+                let (head, body) = v.split_once("\r\n\r\n").unwrap();
+                (body, head.contains("connection: close"))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pipelining() {
+        let raw = "GET /a HTTP/1.1\r\n\r\n\
+            POST /b HTTP/1.1\r\ncontent-length: 3\r\n\r\nGET\
+            GET /c HTTP/1.1\r\nconnection: close\r\n\r\n\
+            GET /d HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            paths(&exchange(0, raw)),
+            vec![("/a", false), ("/b", false), ("/c", true)]
+        );
+        assert_eq!(paths(&exchange(2, raw)), vec![("/a", false), ("/b", true)]);
+
+        let out = exchange(0, "GET /a HTTP/1.0\r\nconnection: keep-alive\r\n\r\nGET /b HTTP/1.0\r\n\r\nGET /c HTTP/1.0\r\n\r\n");
+        assert!(out.contains("connection: keep-alive"));
+        assert_eq!(paths(&out), vec![("/a", false), ("/b", true)]);
+    }
+
+    #[test]
+    fn test_slow_body() {
+        let mut cfg = config();
+        cfg.http.body_timeout.0 = std::time::Duration::from_millis(100);
+        cfg.http.min_body_rate = crate::config::bytes_size::BytesSize(1000);
+        let service = Arc::new(Echo(Box::leak(Box::new(cfg)), false));
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let out = rt.block_on(async {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let (r, w) = tokio::io::split(server);
+            let addr: std::net::SocketAddr = "127.0.0.1:80".parse().unwrap();
+            tokio::spawn(serve(service, r, w, addr.into(), None, None));
+            let (mut cr, mut cw) = tokio::io::split(client);
+            cw.write_all(b"POST /a HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n")
+                .await
+                .unwrap();
+            // a byte a chunk, far below the rate
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    if cw.write_all(b"1\r\nx\r\n").await.is_err() {
+                        break;
+                    }
+                }
+            });
+            let mut out = String::new();
+            _ = cr.read_to_string(&mut out).await;
+            out
+        });
+        assert!(
+            out.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn test_multipart() {
+        let mut cfg = config();
+        cfg.http.forms.spool_size = crate::config::bytes_size::BytesSize(4);
+        cfg.http.forms.max_file_size = crate::config::bytes_size::BytesSize(16);
+        let cfg: &'static ServiceConfig = Box::leak(Box::new(cfg));
+        let body = "--xY\r\ncontent-disposition: form-data; name=\"a\"\r\n\r\n1\r\n\
+            --xY\r\ncontent-disposition: form-data; name=\"f\"; filename=\"f\"\r\n\r\n12345678\r\n\
+            --xY--\r\nepilogue";
+        let head = "POST /f HTTP/1.1\r\ncontent-type: multipart/form-data; boundary=xY\r\n";
+        let chunked: String = body
+            .as_bytes()
+            .chunks(7)
+            .map(|v| format!("{:x}\r\n{}\r\n", v.len(), std::str::from_utf8(v).unwrap()))
+            .collect();
+
+        // read off the connection, the next request follows the epilogue
+        let raw = format!(
+            "{}content-length: {}\r\n\r\n{}{}transfer-encoding: chunked\r\n\r\n{}0\r\nx: 1\r\n\r\n\
+            GET /next HTTP/1.1\r\nconnection: close\r\n\r\n",
+            head,
+            body.len(),
+            body,
+            head,
+            chunked
+        );
+        let out = talk(Echo(cfg, true), &raw);
+        assert_eq!(
+            paths(&out),
+            vec![
+                ("/f a=1 files=8(file) body=0", false),
+                ("/f a=1 files=8(file) body=0", false),
+                ("/next", true)
+            ]
+        );
+        // a service not asking gets the body
+        let out = talk(Echo(cfg, false), &raw);
+        assert_eq!(paths(&out)[0], ("/f", false));
+
+        let large = body.replace("12345678", &"x".repeat(17));
+        let raw = format!("{}content-length: {}\r\n\r\n{}", head, large.len(), large);
+        let out = talk(Echo(cfg, true), &raw);
+        assert!(
+            out.starts_with("HTTP/1.1 413 Content Too Large\r\n"),
+            "{}",
+            out
+        );
+        let raw = format!("{}transfer-encoding: chunked\r\n\r\nzz\r\n", head);
+        let out = talk(Echo(cfg, true), &raw);
+        assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", out);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::utils::anyhow;
+
+use crate::{
+    config::service::ServiceConfig, ctx::ConnContext, limits, message::Message,
+    protocols::Protocol, reqr::RequestReader, respw::ResponseWriter, supervisor,
+};
+
+use super::common::Service;
+
+pub struct AdminService(&'static ServiceConfig);
+
+impl AdminService {
+    pub fn new(cfg: &'static ServiceConfig) -> Self {
+        Self(cfg)
+    }
+}
+
+impl Service for AdminService {
+    fn config(&self) -> &'static ServiceConfig {
+        self.0
+    }
+
+    async fn init(&mut self) -> crate::utils::anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn http<
+        R: tokio::io::AsyncBufReadExt + Unpin + Send,
+        W: tokio::io::AsyncWriteExt + Unpin + Send,
+    >(
+        &self,
+        ctx: &ConnContext<R, W>,
+        req: &mut Message,
+        resp: &mut Message,
+    ) -> impl std::future::Future<Output = anyhow::Result<Protocol>> + Send {
+        let (method, path) = {
+            let req = RequestReader::from(&*req);
+            let path = match req.uri() {
+                Ok(uri) => uri.path,
+                Err(_) => String::new(),
+            };
+            (req.method().clone(), path)
+        };
+        let idx = self.config().idx();
+        let addr = ctx.addr;
+
+        async move {
+            let (code, reason, body) = match (method.as_str(), path.as_str()) {
+                ("POST", "/reload") => {
+                    // reading and validating the files blocks
+                    let result = match tokio::task::spawn_blocking(supervisor::reload).await {
+                        Ok(v) => v,
+                        Err(e) => anyhow::error(&format!("reload task failed, {}", e)),
+                    };
+                    match result {
+                        Ok(_) => {
+                            log::info!(service = idx; "configuration reloaded by {}", addr);
+                            (200, "OK", "reloaded\n".to_string())
+                        }
+                        Err(e) => {
+                            log::error!(service = idx; "reload by {} failed, {}", addr, e);
+                            (500, "Internal Server Error", format!("{}\n", e))
+                        }
+                    }
+                }
+                (_, "/reload") => (405, "Method Not Allowed", "\n".to_string()),
+                ("GET", "/metrics") => (200, "OK", limits::metrics()),
+                (_, "/metrics") => (405, "Method Not Allowed", "\n".to_string()),
+                (_, "") => (400, "Bad Request", "\n".to_string()),
+                _ => (404, "Not Found", "\n".to_string()),
+            };
+
+            let resp = {
+                let mut w = ResponseWriter::from(resp);
+                w.version(1, 1)
+                    .code(code, reason)
+                    .header("server", "httpd.rs")
+                    .header("content-type", "text/plain; charset=utf-8");
+                w.end()
+            };
+            resp.body.write_all_to_internal(body.as_bytes());
+
+            Ok(Protocol::Current { keep_alive: true })
+        }
+    }
+
+    // no endpoint takes a body
+    fn accept_body<
+        R: tokio::io::AsyncBufReadExt + Unpin + Send,
+        W: tokio::io::AsyncWriteExt + Unpin + Send,
+    >(
+        &self,
+        _ctx: &ConnContext<R, W>,
+        _req: &Message,
+        resp: &mut Message,
+    ) -> bool {
+        let resp = {
+            let mut w = ResponseWriter::from(resp);
+            w.version(1, 1)
+                .code(413, "Content Too Large")
+                .header("server", "httpd.rs")
+                .header("content-type", "text/plain; charset=utf-8");
+            w.end()
+        };
+        resp.body.write_all_to_internal("\n".as_bytes());
+        false
+    }
+}
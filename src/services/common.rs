@@ -20,4 +20,25 @@ pub trait Service {
         req: &mut Message,
         resp: &mut Message,
     ) -> impl Future<Output = anyhow::Result<Protocol>> + Send;
+
+    /// called once the headers of a request with a body are read. a service rejecting the
+    /// body, `false`, writes its response to `resp` and the connection is closed after it;
+    /// a client waiting on `expect: 100-continue` never sends the body then.
+    fn accept_body<
+        R: tokio::io::AsyncBufReadExt + Unpin + Send,
+        W: tokio::io::AsyncWriteExt + Unpin + Send,
+    >(
+        &self,
+        _ctx: &ConnContext<R, W>,
+        _req: &Message,
+        _resp: &mut Message,
+    ) -> bool {
+        true
+    }
+
+    /// whether a `multipart/form-data` body is read by the server into `Message::form`, part
+    /// by part as it arrives and file parts to temporary files, instead of into the body.
+    fn multipart(&self) -> bool {
+        false
+    }
 }
@@ -37,6 +37,12 @@ impl Service for HelloWorldService {
 
         {
             log::trace!(service = self.config().idx(); "Request From {}", ctx.addr);
+            match ctx.tls.as_ref().and_then(|v| v.client_cert.as_ref()) {
+                Some(cert) => {
+                    log::trace!(service = self.config().idx(); "Client Certificate {} {:?}", cert.subject, cert.sans);
+                }
+                None => {}
+            }
             let req = RequestReader::from(&*req);
             log::trace!("{} {} {:?}", req.method(), req.rawuri(), req.version());
             req.headers().each(&mut |k, vs| {
@@ -63,8 +69,6 @@ impl Service for HelloWorldService {
         }
 
         async move {
-            let mut keep_alive = true;
-
             let resp = {
                 let mut w = ResponseWriter::from(resp);
                 w.version(1, 1).code(200, "OK").header("server", "httpd.rs");
@@ -72,7 +76,6 @@ impl Service for HelloWorldService {
                 match reqversion {
                     Ok((major, minor)) => {
                         w.version(major, minor);
-                        keep_alive = major >= 1 && minor >= 1;
                     }
                     Err(_) => {}
                 }
@@ -81,7 +84,7 @@ impl Service for HelloWorldService {
             };
             resp.body.write_all_to_internal("Hello world!".as_bytes());
 
-            Ok(Protocol::Current { keep_alive })
+            Ok(Protocol::Current { keep_alive: true })
         }
     }
 }
@@ -1,3 +1,4 @@
+pub mod admin;
 pub mod common;
 pub mod forward;
 pub mod fs;
@@ -0,0 +1,262 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+// shared by every runtime of the process, `per_core` runs one runtime per thread
+struct State {
+    tx: tokio::sync::watch::Sender<bool>,
+    conns: AtomicUsize,
+    drained: tokio::sync::Notify,
+}
+
+fn state() -> &'static State {
+    static STATE: OnceLock<State> = OnceLock::new();
+    STATE.get_or_init(|| State {
+        tx: tokio::sync::watch::channel(false).0,
+        conns: AtomicUsize::new(0),
+        drained: tokio::sync::Notify::new(),
+    })
+}
+
+/// start the shutdown sequence, listeners stop accepting and connections drain.
+pub(crate) fn trigger() {
+    state().tx.send_replace(true);
+}
+
+/// whether the process is shutting down or the scope of the current connection is closed.
+#[inline]
+pub(crate) fn triggered() -> bool {
+    *state().tx.borrow() || SCOPE.try_with(|v| v.closed()).unwrap_or(false)
+}
+
+/// resolves once the shutdown sequence has started or the scope of the current connection is closed.
+pub(crate) async fn wait() {
+    let mut rx = state().tx.subscribe();
+    match SCOPE.try_with(|v| v.inner.tx.subscribe()) {
+        Ok(mut scope) => {
+            tokio::select! {
+                _ = rx.wait_for(|v| *v) => {},
+                _ = scope.wait_for(|v| *v != SCOPE_RUNNING) => {},
+            }
+        }
+        Err(_) => {
+            _ = rx.wait_for(|v| *v).await;
+        }
+    }
+}
+
+const SCOPE_RUNNING: u8 = 0;
+const SCOPE_CLOSED: u8 = 1;
+const SCOPE_KILLED: u8 = 2;
+
+struct ScopeState {
+    tx: tokio::sync::watch::Sender<u8>,
+    conns: AtomicUsize,
+    drained: tokio::sync::Notify,
+}
+
+tokio::task_local! {
+    static SCOPE: Scope;
+}
+
+/// the connections accepted by one generation of a service. a config reload closes the
+/// scope of the replaced generation, its connections finish their current request like on
+/// shutdown, while the listener goes on with the new generation.
+#[derive(Clone)]
+pub(crate) struct Scope {
+    inner: Arc<ScopeState>,
+}
+
+struct ScopeGuard(Arc<ScopeState>);
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        if self.0.conns.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.drained.notify_waiters();
+        }
+    }
+}
+
+impl Scope {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Arc::new(ScopeState {
+                tx: tokio::sync::watch::channel(SCOPE_RUNNING).0,
+                conns: AtomicUsize::new(0),
+                drained: tokio::sync::Notify::new(),
+            }),
+        }
+    }
+
+    /// run a connection in this scope, it is dropped once the scope is killed.
+    pub(crate) fn spawn<F>(&self, fut: F) -> tokio::task::JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.inner.conns.fetch_add(1, Ordering::SeqCst);
+        let guard = ScopeGuard(self.inner.clone());
+        let mut rx = self.inner.tx.subscribe();
+        tokio::spawn(SCOPE.scope(self.clone(), async move {
+            let _guard = guard;
+            tokio::select! {
+                _ = fut => {},
+                _ = rx.wait_for(|v| *v == SCOPE_KILLED) => {},
+            }
+        }))
+    }
+
+    #[inline]
+    pub(crate) fn closed(&self) -> bool {
+        *self.inner.tx.borrow() != SCOPE_RUNNING
+    }
+
+    /// ask the connections to finish, they are not waited for.
+    pub(crate) fn close(&self) {
+        self.inner.tx.send_if_modified(|v| {
+            if *v == SCOPE_RUNNING {
+                *v = SCOPE_CLOSED;
+                return true;
+            }
+            false
+        });
+    }
+
+    /// close, give the connections `grace` to finish, then drop the rest.
+    /// returns false if some were dropped.
+    pub(crate) async fn retire(&self, grace: Duration) -> bool {
+        self.close();
+        let drained = async {
+            loop {
+                let notified = self.inner.drained.notified();
+                if self.inner.conns.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        let ok = tokio::time::timeout(grace, drained).await.is_ok();
+        self.inner.tx.send_replace(SCOPE_KILLED);
+        ok
+    }
+}
+
+/// spawn into the scope of the current connection if any, so sub tasks are retired with it.
+pub(crate) fn spawn<F>(fut: F) -> tokio::task::JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    match SCOPE.try_with(|v| v.clone()) {
+        Ok(scope) => scope.spawn(fut),
+        Err(_) => tokio::spawn(fut),
+    }
+}
+
+/// counts a live connection until dropped.
+pub(crate) struct ConnGuard;
+
+impl ConnGuard {
+    pub(crate) fn new() -> Self {
+        state().conns.fetch_add(1, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        if state().conns.fetch_sub(1, Ordering::SeqCst) == 1 {
+            state().drained.notify_waiters();
+        }
+    }
+}
+
+#[inline]
+pub(crate) fn conns() -> usize {
+    state().conns.load(Ordering::SeqCst)
+}
+
+/// wait for every connection to close, at most `grace`. returns false on timeout,
+/// the remaining connections are dropped with their runtime.
+pub(crate) async fn drain(grace: Duration) -> bool {
+    let drained = async {
+        loop {
+            let notified = state().drained.notified();
+            if conns() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    };
+    tokio::time::timeout(grace, drained).await.is_ok()
+}
+
+/// trigger on ctrl-c or SIGTERM, reload the config on SIGHUP and upgrade the binary on
+/// SIGUSR2. runs on its own thread so every run mode is covered.
+pub(crate) fn listen_signals() -> std::io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    std::thread::Builder::new()
+        .name("httpd.signal".to_string())
+        .spawn(move || {
+            runtime.block_on(async {
+                #[cfg(unix)]
+                {
+                    use tokio::signal::unix::{signal, SignalKind};
+
+                    let (mut term, mut hangup, mut usr2) = match (
+                        signal(SignalKind::terminate()),
+                        signal(SignalKind::hangup()),
+                        signal(SignalKind::user_defined2()),
+                    ) {
+                        (Ok(term), Ok(hangup), Ok(usr2)) => (term, hangup, usr2),
+                        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                                log::error!("listen SIGTERM/SIGHUP/SIGUSR2 failed, {}", e);
+                                _ = tokio::signal::ctrl_c().await;
+                                crate::systemd::stopping();
+                                trigger();
+                                return;
+                            }
+                        };
+                    loop {
+                        tokio::select! {
+                            _ = tokio::signal::ctrl_c() => break,
+                            _ = term.recv() => break,
+                            _ = hangup.recv() => {
+                                match crate::supervisor::reload() {
+                                    Ok(_) => println!("httpd: configuration reloaded"),
+                                    Err(e) => {
+                                        log::error!("reload failed, keep the running configuration, {}", e);
+                                        println!("httpd: reload failed, {}", e);
+                                    }
+                                }
+                            },
+                            _ = usr2.recv() => {
+                                match crate::upgrade::start() {
+                                    Ok(_) => {}
+                                    Err(e) => {
+                                        log::error!("upgrade failed, {}", e);
+                                        println!("httpd: upgrade failed, {}", e);
+                                    }
+                                }
+                            },
+                        }
+                    }
+                }
+                #[cfg(not(unix))]
+                {
+                    _ = tokio::signal::ctrl_c().await;
+                }
+                log::info!("shutting down, {} connections to drain", conns());
+                println!("httpd: shutting down");
+                // not in `trigger`, the old process of an upgrade is not stopping the unit
+                crate::systemd::stopping();
+                trigger();
+            });
+        })?;
+    Ok(())
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use crate::{
+    config::{service::ServiceConfig, Config},
+    limits,
+    listener::StdListener,
+    shutdown, systemd, upgrade,
+    utils::anyhow,
+};
+
+/// the config of a running service, `None` once the service is removed.
+pub(crate) type Updates = tokio::sync::watch::Receiver<Option<&'static ServiceConfig>>;
+
+/// the sockets of a service, bound before it is started.
+pub(crate) struct Listeners {
+    // tcp or unix, one per runtime, see `RuntimeConfig::runtimes`
+    pub(crate) tcp: Vec<StdListener>,
+    pub(crate) udp: Option<std::net::UdpSocket>,
+}
+
+/// start a service on the runtime(s) of the current run mode.
+pub(crate) type Spawn =
+    Box<dyn Fn(&'static ServiceConfig, Listeners, Updates) -> anyhow::Result<()> + Send>;
+
+struct Running {
+    cfg: &'static ServiceConfig,
+    tx: tokio::sync::watch::Sender<Option<&'static ServiceConfig>>,
+}
+
+// services are identified by their listen address, a renamed service keeps its listener
+struct Supervisor {
+    config: &'static Config,
+    running: HashMap<String, Running>,
+    spawn: Spawn,
+}
+
+fn supervisor() -> std::sync::MutexGuard<'static, Option<Supervisor>> {
+    static SUPERVISOR: OnceLock<Mutex<Option<Supervisor>>> = OnceLock::new();
+    match SUPERVISOR.get_or_init(Default::default).lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// a validated reload, only the spawning of new services is left to fail
+struct Plan {
+    config: Config,
+    adds: Vec<(String, Listeners)>,
+    swaps: Vec<String>,
+    // swapped services with new unix socket permissions
+    sockets: Vec<String>,
+    removes: Vec<String>,
+    fallbacks: Vec<String>,
+}
+
+fn bind(cfg: &ServiceConfig, runtimes: usize) -> anyhow::Result<Listeners> {
+    // only linux spreads the connections over the sockets of a port, elsewhere and for unix
+    // sockets the runtimes share one
+    let reuse_port = runtimes > 1 && cfg!(target_os = "linux");
+    let mut tcp: Vec<StdListener> = vec![];
+    for _ in 0..runtimes {
+        // every runtime claims a passed socket while there are any, the old binary may have
+        // had one per runtime too
+        let listener = match tcp.first() {
+            Some(first)
+                if (!reuse_port || !first.reuses_port()) && !StdListener::inherited(cfg) =>
+            {
+                anyhow::result(first.try_clone())
+            }
+            _ => StdListener::bind(cfg, reuse_port),
+        };
+        match listener {
+            Ok(v) => tcp.push(v),
+            Err(e) => {
+                return anyhow::error(&format!(
+                    "service `{}` bind `{}` failed, {}",
+                    cfg.name, cfg.tcp.addr, e
+                ));
+            }
+        }
+    }
+
+    let mut udp = None;
+    #[cfg(feature = "http3")]
+    {
+        match cfg.quic.as_ref() {
+            Some(quic) if cfg.tcp.tls.enabled() => match upgrade::udp_socket(&cfg.name, &quic.addr)
+            {
+                Ok(v) => udp = Some(v),
+                Err(e) => {
+                    return anyhow::error(&format!(
+                        "service `{}` bind `{}`(udp) failed, {}",
+                        cfg.name, quic.addr, e
+                    ));
+                }
+            },
+            _ => {}
+        }
+    }
+    Ok(Listeners { tcp, udp })
+}
+
+// everything that may fail before a service is started
+fn check(cfg: &ServiceConfig) -> anyhow::Result<()> {
+    match cfg.tcp.tls.load() {
+        Ok(_) => Ok(()),
+        Err(e) => anyhow::error(&format!("service `{}` tls, {}", cfg.name, e)),
+    }
+}
+
+// a running service keeps its sockets over a reload, the address is its identity and the
+// rest of the config is swapped in. a unix socket file keeps its path, a change of its
+// permissions is applied to it in place
+fn socket_changed(a: &ServiceConfig, b: &ServiceConfig) -> bool {
+    a.tcp.unix_path().is_some()
+        && (a.tcp.unix.mode != b.tcp.unix.mode
+            || a.tcp.unix.owner != b.tcp.unix.owner
+            || a.tcp.unix.group != b.tcp.unix.group)
+}
+
+/// bind and start every service of `config`, nothing is started if one of them fails.
+pub(crate) fn start(config: &'static Config, spawn: Spawn) -> anyhow::Result<()> {
+    limits::set_max_conns(config.runtime.max_conns);
+    let mut listeners = vec![];
+    for cfg in config.services.values() {
+        check(cfg)?;
+        listeners.push((cfg, bind(cfg, config.runtime.runtimes())?));
+    }
+
+    let mut running = HashMap::new();
+    for (cfg, listener) in listeners {
+        let (tx, rx) = tokio::sync::watch::channel(Some(cfg));
+        spawn(cfg, listener, rx)?;
+        running.insert(cfg.tcp.addr.clone(), Running { cfg, tx });
+    }
+
+    *supervisor() = Some(Supervisor {
+        config,
+        running,
+        spawn,
+    });
+    Ok(())
+}
+
+/// how long a removed service may take to finish its connections.
+pub(crate) fn grace() -> Duration {
+    match supervisor().as_ref() {
+        Some(v) => v.config.runtime.shutdown_timeout.0,
+        None => Duration::from_secs(30),
+    }
+}
+
+/// the working directory the config file was loaded from.
+pub(crate) fn basedir() -> String {
+    match supervisor().as_ref() {
+        Some(v) => v.config.basedir.clone(),
+        None => String::new(),
+    }
+}
+
+/// resolves with the next config of the service, `None` if it is removed.
+pub(crate) async fn next(updates: &mut Updates) -> Option<&'static ServiceConfig> {
+    match updates.changed().await {
+        Ok(_) => *updates.borrow_and_update(),
+        Err(_) => None,
+    }
+}
+
+fn plan(sup: &Supervisor) -> anyhow::Result<Plan> {
+    let mut config = Config::read(&sup.config.src, Path::new(&sup.config.basedir))?;
+    let fallbacks = config.inherit_logging(sup.config)?;
+
+    let mut adds = vec![];
+    let mut swaps = vec![];
+    let mut sockets = vec![];
+    for cfg in config.services.values() {
+        if config
+            .services
+            .values()
+            .filter(|v| v.tcp.addr == cfg.tcp.addr)
+            .count()
+            > 1
+        {
+            return anyhow::error(&format!("more than one service listen @ {}", cfg.tcp.addr));
+        }
+        match sup.running.get(&cfg.tcp.addr) {
+            Some(running) => {
+                check(cfg)?;
+                swaps.push(cfg.tcp.addr.clone());
+                if socket_changed(running.cfg, cfg) {
+                    sockets.push(cfg.tcp.addr.clone());
+                }
+            }
+            None => {
+                check(cfg)?;
+                adds.push((
+                    cfg.tcp.addr.clone(),
+                    bind(cfg, sup.config.runtime.runtimes())?,
+                ));
+            }
+        }
+    }
+    let removes = sup
+        .running
+        .keys()
+        .filter(|addr| !config.services.values().any(|v| &v.tcp.addr == *addr))
+        .cloned()
+        .collect();
+
+    Ok(Plan {
+        config,
+        adds,
+        swaps,
+        sockets,
+        removes,
+        fallbacks,
+    })
+}
+
+fn find<'a>(config: &'a Config, addr: &str) -> &'a ServiceConfig {
+    config
+        .services
+        .values()
+        .find(|v| v.tcp.addr == addr)
+        .unwrap()
+}
+
+/// read the config file again and apply it: new services are started, removed ones drain
+/// and stop, the others switch to the new config for new connections, which loads their
+/// certificates again even if nothing of them changed. any error rejects
+/// the whole reload and the running services are left untouched.
+///
+/// the runtime and the logging appenders are set up once, changes to them need a restart.
+pub(crate) fn reload() -> anyhow::Result<()> {
+    if shutdown::triggered() {
+        return anyhow::error("shutting down");
+    }
+    systemd::reloading();
+    let result = apply();
+    // systemd waits for the end of a reload, failed or not
+    systemd::ready();
+    result
+}
+
+fn apply() -> anyhow::Result<()> {
+    let mut guard = supervisor();
+    let sup = match guard.as_mut() {
+        Some(v) => v,
+        None => return anyhow::error("services are not started"),
+    };
+    if sup.config.src.is_empty() {
+        return anyhow::error("not started from a config file");
+    }
+
+    let plan = plan(sup)?;
+
+    if format!("{:?}{:?}", sup.config.runtime, sup.config.logging)
+        != format!("{:?}{:?}", plan.config.runtime, plan.config.logging)
+    {
+        log::warn!("runtime and logging changes take effect after a restart");
+    }
+    if plan.config.workdir() != sup.config.workdir() {
+        log::warn!("workdir changes take effect after a restart");
+    }
+    for name in plan.fallbacks {
+        log::info!(
+            "new service `{}` logs through the root appenders until a restart",
+            name
+        );
+    }
+
+    // a config lives as long as the connections served by it, reloads are rare enough to leak it
+    let config: &'static Config = Box::leak(Box::new(plan.config));
+
+    for (addr, listener) in plan.adds {
+        let cfg = find(config, &addr);
+        let (tx, rx) = tokio::sync::watch::channel(Some(cfg));
+        match (sup.spawn)(cfg, listener, rx) {
+            Ok(_) => {
+                log::info!("service `{}` added, {}", cfg.name, addr);
+                sup.running.insert(addr, Running { cfg, tx });
+            }
+            Err(e) => {
+                log::error!("service `{}` start failed, {}", cfg.name, e);
+            }
+        }
+    }
+    for addr in plan.swaps {
+        let cfg = find(config, &addr);
+        match sup.running.get_mut(&addr) {
+            Some(running) => {
+                log::info!("service `{}` reloaded, {}", cfg.name, addr);
+                running.cfg = cfg;
+                running.tx.send_replace(Some(cfg));
+            }
+            None => {}
+        }
+    }
+    #[cfg(unix)]
+    for addr in plan.sockets {
+        let cfg = find(config, &addr);
+        match cfg.tcp.unix_path() {
+            Some(path) => match crate::listener::set_unix_permissions(path, &cfg.tcp.unix) {
+                Ok(_) => {}
+                Err(e) => log::error!("service `{}` socket permissions, {}", cfg.name, e),
+            },
+            None => {}
+        }
+    }
+    for addr in plan.removes {
+        match sup.running.remove(&addr) {
+            Some(running) => {
+                log::info!("service `{}` removed, {}", running.cfg.name, addr);
+                running.tx.send_replace(None);
+            }
+            None => {}
+        }
+    }
+    sup.config = config;
+    Ok(())
+}
@@ -0,0 +1,254 @@
+//! socket activation and the service notification protocol of systemd, see `sd_listen_fds(3)`
+//! and `sd_notify(3)`. nothing here needs systemd: without the environment variables every
+//! function is a no-op.
+
+use crate::utils::anyhow;
+
+// the first fd passed by systemd, the rest follow it
+const LISTEN_FDS_START: i32 = 3;
+
+// the fds passed to process `me` and their names, the name is empty if systemd did not know it
+fn listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    me: u32,
+) -> Vec<(i32, String)> {
+    match pid.and_then(|v| v.parse::<u32>().ok()) {
+        Some(pid) if pid == me => {}
+        _ => return vec![],
+    }
+    let count = match fds.and_then(|v| v.parse::<i32>().ok()) {
+        Some(v) if v > 0 => v,
+        _ => return vec![],
+    };
+    let mut names: Vec<&str> = match names {
+        Some(v) => v.split(':').collect(),
+        None => vec![],
+    };
+    if names.len() != count as usize {
+        names.clear();
+    }
+
+    (0..count)
+        .map(|i| {
+            let name = match names.get(i as usize) {
+                Some(&"unknown") | None => "",
+                Some(v) => *v,
+            };
+            (LISTEN_FDS_START + i, name.to_string())
+        })
+        .collect()
+}
+
+/// hand the sockets of socket activation to `upgrade`, a service takes the one named after
+/// it (`FileDescriptorName=`) or the one bound to its address. must run before any other
+/// thread is started.
+pub(crate) fn inherit() -> anyhow::Result<()> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let names = std::env::var("LISTEN_FDNAMES").ok();
+    // the sockets are not for the processes started by this one
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    #[cfg(unix)]
+    {
+        use std::os::fd::{FromRawFd, OwnedFd};
+
+        use crate::upgrade::{self, Kind};
+
+        let sockets = listen_fds(
+            pid.as_deref(),
+            fds.as_deref(),
+            names.as_deref(),
+            std::process::id(),
+        );
+        for (fd, name) in sockets {
+            // SAFETY: systemd passed the fd to this process alone
+            let socket = socket2::Socket::from(unsafe { OwnedFd::from_raw_fd(fd) });
+            anyhow::result(upgrade::set_cloexec(&socket, true))?;
+
+            let adopted = match (socket.r#type(), socket.local_addr()) {
+                (Ok(ty), Ok(addr)) => match (ty, addr.as_socket(), addr.as_pathname()) {
+                    (socket2::Type::STREAM, Some(addr), _) => Some((Kind::Tcp, addr.to_string())),
+                    (socket2::Type::DGRAM, Some(addr), _) => Some((Kind::Udp, addr.to_string())),
+                    (socket2::Type::STREAM, None, Some(path)) => {
+                        Some((Kind::Unix, path.to_string_lossy().to_string()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            match adopted {
+                Some((kind, addr)) => upgrade::adopt(kind, &name, addr, socket.into()),
+                None => {
+                    return anyhow::error(&format!(
+                        "socket `{}`(fd {}) from systemd is not supported",
+                        name, fd
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn send(path: &std::ffi::OsStr, msg: &str) -> std::io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(msg.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(std::io::Error::from(std::io::ErrorKind::Unsupported)),
+        None => {
+            socket.send_to(msg.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+// `NOTIFY_SOCKET` is kept, a process started by `upgrade` notifies as the new main process
+fn notify(msg: &str) {
+    #[cfg(unix)]
+    {
+        let path = match std::env::var_os("NOTIFY_SOCKET") {
+            Some(v) => v,
+            None => return,
+        };
+        match send(&path, msg) {
+            Ok(_) => {}
+            Err(e) => log::warn!("notify systemd `{}` failed, {}", msg.replace('\n', " "), e),
+        }
+    }
+}
+
+/// the services are started. also sent by the new process of an upgrade, which becomes the
+/// main process of the unit, this needs `NotifyAccess=all`.
+pub(crate) fn ready() {
+    notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+}
+
+/// a reload is started, `ready` tells it is done.
+pub(crate) fn reloading() {
+    #[cfg(unix)]
+    {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+        let usec = ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000;
+        notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", usec));
+    }
+}
+
+/// the process is draining and exits after.
+pub(crate) fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// ping the watchdog at half of `WatchdogSec=`, if it is enabled for this process. a ping is sent
+/// only while every runtime of `handles` makes progress, a stuck runtime lets systemd restart us.
+pub(crate) fn watchdog(handles: &[tokio::runtime::Handle]) {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    let usec = match std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+    {
+        Some(v) if v > 0 => v,
+        _ => return,
+    };
+    match std::env::var("WATCHDOG_PID") {
+        Ok(pid) if pid != std::process::id().to_string() => return,
+        _ => {}
+    }
+
+    let interval = std::time::Duration::from_micros(usec / 2);
+    // every runtime beats twice an interval, a ping needs a beat of each since the last one
+    let beats: Arc<Vec<AtomicU64>> = Arc::new(handles.iter().map(|_| AtomicU64::new(0)).collect());
+    for (i, handle) in handles.iter().enumerate() {
+        let beats = beats.clone();
+        handle.spawn(async move {
+            let mut ticker = tokio::time::interval(interval / 2);
+            loop {
+                ticker.tick().await;
+                beats[i].fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+    handles[0].spawn(async move {
+        let mut seen = vec![0; beats.len()];
+        let mut ticker = tokio::time::interval(interval);
+        // the first tick is immediate, before any beat
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let mut alive = true;
+            for (i, beat) in beats.iter().enumerate() {
+                let beat = beat.load(Ordering::Relaxed);
+                if beat == seen[i] {
+                    alive = false;
+                }
+                seen[i] = beat;
+            }
+            if alive {
+                notify("WATCHDOG=1");
+            } else {
+                log::warn!("a runtime made no progress, skip the watchdog ping");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds() {
+        let me = std::process::id();
+        let pid = me.to_string();
+        assert_eq!(
+            listen_fds(Some(&pid), Some("2"), Some("web:unknown"), me),
+            vec![(3, "web".to_string()), (4, "".to_string())]
+        );
+        // names not matching the count are ignored
+        assert_eq!(
+            listen_fds(Some(&pid), Some("2"), Some("web"), me),
+            vec![(3, "".to_string()), (4, "".to_string())]
+        );
+        assert!(listen_fds(Some("1"), Some("2"), None, me).is_empty());
+        assert!(listen_fds(None, Some("2"), None, me).is_empty());
+        assert!(listen_fds(Some(&pid), Some("0"), None, me).is_empty());
+        assert!(listen_fds(Some(&pid), Some("x"), None, me).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_notify_socket() {
+        let path = std::env::temp_dir().join(format!("httpd.notify.{}", std::process::id()));
+        _ = std::fs::remove_file(&path);
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+
+        send(path.as_os_str(), "READY=1\nMAINPID=1").unwrap();
+        let mut buf = [0u8; 64];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\nMAINPID=1");
+
+        _ = std::fs::remove_file(&path);
+    }
+}
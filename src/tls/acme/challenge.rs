@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::{
+    crypto::CryptoProvider,
+    pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    server::{Acceptor, ServerConfig},
+    sign::CertifiedKey,
+};
+
+use crate::{message::Message, reqr::RequestReader, respw::ResponseWriter, utils::anyhow};
+
+use super::client::sha256;
+
+pub(crate) const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// accepts the tls of a service. a client offering `acme-tls/1` gets a config answering only
+/// that protocol, the others the served config, which ignores alpn.
+#[derive(Clone)]
+pub(crate) struct TlsAcceptor {
+    served: Arc<ServerConfig>,
+    validation: Option<Arc<ServerConfig>>,
+}
+
+impl TlsAcceptor {
+    pub(crate) fn new(served: ServerConfig, tls_alpn01: bool) -> Self {
+        let validation = match tls_alpn01 {
+            true => {
+                let mut cfg = served.clone();
+                cfg.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+                Some(Arc::new(cfg))
+            }
+            false => None,
+        };
+        Self {
+            served: Arc::new(served),
+            validation,
+        }
+    }
+
+    pub(crate) async fn accept<IO: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: IO,
+    ) -> std::io::Result<tokio_rustls::server::TlsStream<IO>> {
+        let validation = match self.validation.as_ref() {
+            Some(v) => v,
+            None => {
+                return tokio_rustls::TlsAcceptor::from(self.served.clone())
+                    .accept(stream)
+                    .await
+            }
+        };
+        let start = tokio_rustls::LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+        let offered = start
+            .client_hello()
+            .alpn()
+            .map(|mut v| v.any(|p| p == ACME_TLS_ALPN))
+            .unwrap_or(false);
+        match offered {
+            true => start.into_stream(validation.clone()).await,
+            false => start.into_stream(self.served.clone()).await,
+        }
+    }
+}
+
+const HTTP01_PREFIX: &str = "/.well-known/acme-challenge/";
+
+// pending challenges are shared by every listener of the process
+fn http01_tokens() -> &'static Mutex<HashMap<String, String>> {
+    static TOKENS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+    TOKENS.get_or_init(Default::default)
+}
+
+fn tls_alpn01_certs() -> &'static Mutex<HashMap<String, Arc<CertifiedKey>>> {
+    static CERTS: OnceLock<Mutex<HashMap<String, Arc<CertifiedKey>>>> = OnceLock::new();
+    CERTS.get_or_init(Default::default)
+}
+
+fn lock<T>(v: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match v.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+pub(crate) fn add_http01(token: &str, key_authorization: &str) {
+    lock(http01_tokens()).insert(token.to_string(), key_authorization.to_string());
+}
+
+pub(crate) fn remove_http01(token: &str) {
+    lock(http01_tokens()).remove(token);
+}
+
+/// the validation certificate of RFC 8737, carrying the digest of the key authorization.
+pub(crate) fn add_tls_alpn01(
+    provider: &CryptoProvider,
+    domain: &str,
+    key_authorization: &str,
+) -> anyhow::Result<()> {
+    let mut params = anyhow::result(rcgen::CertificateParams::new(vec![domain.to_string()]))?;
+    params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(&sha256(
+        key_authorization.as_bytes(),
+    ))];
+    let keypair = anyhow::result(rcgen::KeyPair::generate())?;
+    let cert = anyhow::result(params.self_signed(&keypair))?;
+
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(keypair.serialize_der()));
+    let key = anyhow::result(provider.key_provider.load_private_key(key))?;
+    lock(tls_alpn01_certs()).insert(
+        domain.to_ascii_lowercase(),
+        Arc::new(CertifiedKey::new(vec![cert.der().clone()], key)),
+    );
+    Ok(())
+}
+
+pub(crate) fn remove_tls_alpn01(domain: &str) {
+    lock(tls_alpn01_certs()).remove(&domain.to_ascii_lowercase());
+}
+
+pub(crate) fn tls_alpn01(sni: Option<&str>) -> Option<Arc<CertifiedKey>> {
+    lock(tls_alpn01_certs())
+        .get(&sni?.to_ascii_lowercase())
+        .cloned()
+}
+
+/// answer a pending http-01 challenge, returns false if `req` is not one.
+pub(crate) fn respond_http01(req: &Message, resp: &mut Message) -> bool {
+    let req = RequestReader::from(req);
+    if req.method() != "GET" {
+        return false;
+    }
+    let token = match req.rawuri().strip_prefix(HTTP01_PREFIX) {
+        Some(v) => v,
+        None => return false,
+    };
+    let key_authorization = match lock(http01_tokens()).get(token) {
+        Some(v) => v.clone(),
+        None => return false,
+    };
+
+    let mut w = ResponseWriter::from(resp);
+    w.version(1, 1)
+        .code(200, "OK")
+        .header("content-type", "application/octet-stream");
+    let resp = w.end();
+    resp.body
+        .write_all_to_internal(key_authorization.as_bytes());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_rustls::rustls::{
+        pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+        ClientConfig, RootCertStore, ServerConfig,
+    };
+
+    use super::{TlsAcceptor, ACME_TLS_ALPN};
+
+    // the alpn agreed with a client offering `offered`
+    async fn handshake(
+        acceptor: &TlsAcceptor,
+        roots: RootCertStore,
+        offered: &[&[u8]],
+    ) -> Option<Vec<u8>> {
+        let mut cfg = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        cfg.alpn_protocols = offered.iter().map(|v| v.to_vec()).collect();
+        let connector = tokio_rustls::TlsConnector::from(std::sync::Arc::new(cfg));
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let name = ServerName::try_from("localhost").unwrap();
+        let (client, server) =
+            tokio::join!(connector.connect(name, client), acceptor.accept(server));
+        client.unwrap();
+        server
+            .unwrap()
+            .get_ref()
+            .1
+            .alpn_protocol()
+            .map(|v| v.to_vec())
+    }
+
+    #[test]
+    fn test_accept() {
+        let generated = crate::tls::selfsigned::generate(&[]).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(generated.cert.der().clone()).unwrap();
+        let key =
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der()));
+        let served = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![generated.cert.der().clone()], key)
+            .unwrap();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let acceptor = TlsAcceptor::new(served.clone(), true);
+            // other protocols are not refused, alpn is ignored as without acme
+            assert_eq!(handshake(&acceptor, roots.clone(), &[b"h2"]).await, None);
+            assert_eq!(handshake(&acceptor, roots.clone(), &[]).await, None);
+            assert_eq!(
+                handshake(&acceptor, roots.clone(), &[ACME_TLS_ALPN]).await,
+                Some(ACME_TLS_ALPN.to_vec())
+            );
+
+            let acceptor = TlsAcceptor::new(served, false);
+            assert_eq!(
+                handshake(&acceptor, roots.clone(), &[b"h2", b"http/1.1"]).await,
+                None
+            );
+        });
+    }
+}
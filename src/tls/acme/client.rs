@@ -0,0 +1,363 @@
+use std::{sync::Arc, time::Duration};
+
+use base64::Engine;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_rustls::rustls::{
+    crypto::CryptoProvider,
+    pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+    sign::SigningKey,
+    SignatureScheme,
+};
+
+use crate::{tls::load_certs, utils::anyhow};
+
+// each step of a request, a hung ca must not stall issuance and renewals
+const STEP_TIMEOUT: Duration = Duration::from_secs(30);
+// directories, orders and certificate chains are a few KB
+const MAX_RESPONSE_SIZE: u64 = 1024 * 1024;
+
+async fn step<T, E: std::fmt::Debug>(
+    what: &str,
+    url: &str,
+    fut: impl std::future::Future<Output = Result<T, E>>,
+) -> anyhow::Result<T> {
+    match tokio::time::timeout(STEP_TIMEOUT, fut).await {
+        Ok(Ok(v)) => Ok(v),
+        Ok(Err(e)) => anyhow::error(&format!("{} {} failed, {:?}", what, url, e)),
+        Err(_) => anyhow::error(&format!("{} {} timed out", what, url)),
+    }
+}
+
+pub(crate) fn b64(v: &[u8]) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(v)
+}
+
+pub(crate) fn sha256(v: &[u8]) -> Vec<u8> {
+    use sha2::Digest;
+    sha2::Sha256::digest(v).to_vec()
+}
+
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Response {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub(crate) fn json(&self) -> anyhow::Result<serde_json::Value> {
+        anyhow::result(serde_json::from_slice(&self.body))
+    }
+
+    fn parse(raw: &[u8]) -> anyhow::Result<Self> {
+        let end = anyhow::option(
+            raw.windows(4).position(|v| v == b"\r\n\r\n"),
+            "incomplete http response",
+        )?;
+        let head = anyhow::result(std::str::from_utf8(&raw[..end]))?;
+        let mut lines = head.split("\r\n");
+        let status = anyhow::option(
+            lines
+                .next()
+                .and_then(|v| v.split(' ').nth(1))
+                .and_then(|v| v.parse::<u16>().ok()),
+            "bad http status line",
+        )?;
+
+        let mut headers = vec![];
+        for line in lines {
+            match line.split_once(':') {
+                Some((k, v)) => headers.push((k.trim().to_string(), v.trim().to_string())),
+                None => {}
+            }
+        }
+
+        let mut resp = Self {
+            status,
+            headers,
+            body: vec![],
+        };
+        let body = &raw[end + 4..];
+        let chunked = resp
+            .header("transfer-encoding")
+            .map(|v| v.eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false);
+        if !chunked {
+            resp.body = body.to_vec();
+            return Ok(resp);
+        }
+
+        let mut pos = 0;
+        loop {
+            let rest = anyhow::option(body.get(pos..), "truncated chunked body")?;
+            let line_end = anyhow::option(
+                rest.windows(2).position(|v| v == b"\r\n"),
+                "bad chunked body",
+            )?;
+            let size = anyhow::result(std::str::from_utf8(&rest[..line_end]))?;
+            let size = size.split(';').next().unwrap_or("").trim();
+            let size = anyhow::result(usize::from_str_radix(size, 16))?;
+            pos += line_end + 2;
+            if size == 0 {
+                break;
+            }
+            let data = match pos.checked_add(size) {
+                Some(end) => body.get(pos..end),
+                None => None,
+            };
+            resp.body
+                .extend_from_slice(anyhow::option(data, "truncated chunked body")?);
+            pos += size;
+            if body.get(pos..pos + 2) != Some(b"\r\n".as_slice()) {
+                return anyhow::error("bad chunked body");
+            }
+            pos += 2;
+        }
+        Ok(resp)
+    }
+}
+
+/// a one-request-per-connection http client, enough for the few calls of an acme flow.
+pub(crate) struct HttpClient {
+    connector: tokio_rustls::TlsConnector,
+}
+
+impl HttpClient {
+    pub(crate) fn new(ca: &str) -> anyhow::Result<Self> {
+        let mut roots = tokio_rustls::rustls::RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        if !ca.is_empty() {
+            for cert in load_certs(ca)? {
+                anyhow::result(roots.add(cert))?;
+            }
+        }
+        let tlscfg = tokio_rustls::rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self {
+            connector: tokio_rustls::TlsConnector::from(Arc::new(tlscfg)),
+        })
+    }
+
+    pub(crate) async fn request(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<&[u8]>,
+    ) -> anyhow::Result<Response> {
+        let (https, rest) = match url.split_once("://") {
+            Some(("https", rest)) => (true, rest),
+            Some(("http", rest)) => (false, rest),
+            _ => return anyhow::error(&format!("unsupported url `{}`", url)),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, anyhow::result(port.parse::<u16>())?)
+            }
+            _ => (authority, if https { 443 } else { 80 }),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let mut raw = format!(
+            "{} {} HTTP/1.1\r\nhost: {}\r\nuser-agent: httpd.rs\r\naccept: */*\r\nconnection: close\r\n",
+            method, path, authority
+        );
+        match body {
+            Some(body) => {
+                raw.push_str(&format!(
+                    "content-type: application/jose+json\r\ncontent-length: {}\r\n\r\n",
+                    body.len()
+                ));
+            }
+            None => raw.push_str("\r\n"),
+        }
+        let mut raw = raw.into_bytes();
+        match body {
+            Some(body) => raw.extend_from_slice(body),
+            None => {}
+        }
+
+        let stream = step("connect", url, tokio::net::TcpStream::connect((host, port))).await?;
+        let mut buf = vec![];
+        if https {
+            let name = anyhow::result(ServerName::try_from(host.to_string()))?;
+            let mut stream =
+                step("tls handshake", url, self.connector.connect(name, stream)).await?;
+            step("write", url, async {
+                stream.write_all(&raw).await?;
+                stream.flush().await
+            })
+            .await?;
+            // some servers close without close_notify
+            let mut limited = (&mut stream).take(MAX_RESPONSE_SIZE + 1);
+            let read = limited.read_to_end(&mut buf);
+            match tokio::time::timeout(STEP_TIMEOUT, read).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof && !buf.is_empty() => {}
+                Ok(Err(e)) => return anyhow::error(&format!("read {} failed, {}", url, e)),
+                Err(_) => return anyhow::error(&format!("read {} timed out", url)),
+            }
+        } else {
+            let mut stream = stream;
+            step("write", url, stream.write_all(&raw)).await?;
+            let mut limited = (&mut stream).take(MAX_RESPONSE_SIZE + 1);
+            let read = limited.read_to_end(&mut buf);
+            step("read", url, read).await?;
+        }
+        if buf.len() as u64 > MAX_RESPONSE_SIZE {
+            return anyhow::error(&format!("response of {} is too large", url));
+        }
+        Response::parse(&buf)
+    }
+}
+
+// asn.1 `SEQUENCE { INTEGER r, INTEGER s }` to the fixed width `r || s` of jws
+fn ecdsa_der_to_raw(der: &[u8]) -> anyhow::Result<Vec<u8>> {
+    fn read_int(der: &[u8], pos: &mut usize) -> anyhow::Result<Vec<u8>> {
+        if der.get(*pos) != Some(&0x02) {
+            return anyhow::error("bad ecdsa signature");
+        }
+        let len = *anyhow::option(der.get(*pos + 1), "bad ecdsa signature")? as usize;
+        *pos += 2;
+        let v = anyhow::option(der.get(*pos..*pos + len), "bad ecdsa signature")?;
+        *pos += len;
+        let v: Vec<u8> = v.iter().skip_while(|b| **b == 0).copied().collect();
+        if v.len() > 32 {
+            return anyhow::error("bad ecdsa signature");
+        }
+        let mut out = vec![0u8; 32 - v.len()];
+        out.extend_from_slice(&v);
+        Ok(out)
+    }
+
+    if der.first() != Some(&0x30) || der.len() < 2 {
+        return anyhow::error("bad ecdsa signature");
+    }
+    let mut pos = 2;
+    let mut raw = read_int(der, &mut pos)?;
+    raw.extend(read_int(der, &mut pos)?);
+    Ok(raw)
+}
+
+/// an ES256 account key.
+pub(crate) struct AccountKey {
+    signer: Arc<dyn SigningKey>,
+    jwk: serde_json::Value,
+    thumbprint: String,
+}
+
+impl AccountKey {
+    pub(crate) fn new(provider: &CryptoProvider, key: &rcgen::KeyPair) -> anyhow::Result<Self> {
+        let point = key.public_key_raw();
+        if point.len() != 65 || point[0] != 0x04 {
+            return anyhow::error("acme account key must be an ecdsa p-256 key");
+        }
+        let (x, y) = (b64(&point[1..33]), b64(&point[33..]));
+        // members in lexicographic order, RFC 7638
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+
+        let der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+        Ok(Self {
+            signer: anyhow::result(provider.key_provider.load_private_key(der))?,
+            jwk: serde_json::json!({"crv": "P-256", "kty": "EC", "x": x, "y": y}),
+            thumbprint: b64(&sha256(canonical.as_bytes())),
+        })
+    }
+
+    pub(crate) fn key_authorization(&self, token: &str) -> String {
+        format!("{}.{}", token, self.thumbprint)
+    }
+
+    /// a flattened jws, signed with `kid` or with the embedded jwk before the account exists.
+    pub(crate) fn sign(
+        &self,
+        url: &str,
+        nonce: &str,
+        kid: Option<&str>,
+        payload: Option<&serde_json::Value>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut protected = serde_json::json!({"alg": "ES256", "nonce": nonce, "url": url});
+        match kid {
+            Some(kid) => protected["kid"] = serde_json::json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = b64(protected.to_string().as_bytes());
+        // an empty payload makes a POST-as-GET request
+        let payload = match payload {
+            Some(v) => b64(v.to_string().as_bytes()),
+            None => String::new(),
+        };
+
+        let signer = anyhow::option(
+            self.signer
+                .choose_scheme(&[SignatureScheme::ECDSA_NISTP256_SHA256]),
+            "acme account key can not sign ES256",
+        )?;
+        let der = anyhow::result(signer.sign(format!("{}.{}", protected, payload).as_bytes()))?;
+        let body = serde_json::json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(&ecdsa_der_to_raw(&der)?),
+        });
+        Ok(body.to_string().into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ecdsa_der_to_raw, Response};
+
+    #[test]
+    fn test_ecdsa_der_to_raw() {
+        let mut der = vec![0x30, 0x45, 0x02, 0x21, 0x00];
+        der.extend([0x80u8; 32]);
+        der.extend([0x02, 0x20]);
+        der.extend([0x01u8; 32]);
+        let raw = ecdsa_der_to_raw(&der).unwrap();
+        assert_eq!(&raw[..32], &[0x80u8; 32]);
+        assert_eq!(&raw[32..], &[0x01u8; 32]);
+
+        let raw = ecdsa_der_to_raw(&[0x30, 0x06, 0x02, 0x01, 0x05, 0x02, 0x01, 0x07]).unwrap();
+        assert_eq!(raw[31], 5);
+        assert_eq!(raw[63], 7);
+        assert_eq!(raw.len(), 64);
+    }
+
+    #[test]
+    fn test_parse_chunked_response() {
+        let resp = Response::parse(
+            b"HTTP/1.1 201 Created\r\nReplay-Nonce: abc\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n{\"a\r\n4\r\n\":1}\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(resp.status, 201);
+        assert_eq!(resp.header("replay-nonce"), Some("abc"));
+        assert_eq!(resp.json().unwrap()["a"], 1);
+    }
+
+    #[test]
+    fn test_parse_truncated_chunked_response() {
+        let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+        for body in [
+            "3\r\n{}",
+            "2\r\n{}",
+            "2\r\n{}\r",
+            "2\r\n{}xx1\r\n}\r\n0\r\n\r\n",
+            "2\r\n{}\r\n",
+            "ffffffffffffffff\r\n{}\r\n",
+        ] {
+            let raw = format!("{}{}", head, body);
+            assert!(Response::parse(raw.as_bytes()).is_err(), "{:?}", body);
+        }
+    }
+}
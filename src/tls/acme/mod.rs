@@ -0,0 +1,352 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio_rustls::rustls::crypto::CryptoProvider;
+
+use crate::{
+    config::{
+        acme::{AcmeChallenge, AcmeConfig},
+        service::ServiceConfig,
+    },
+    utils::anyhow,
+};
+
+use self::client::{b64, AccountKey, HttpClient, Response};
+
+use super::resolver::SniResolver;
+
+pub(crate) mod challenge;
+mod client;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_TIMES: usize = 60;
+
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+struct AcmeClient<'a> {
+    cfg: &'a AcmeConfig,
+    provider: &'a CryptoProvider,
+    http: HttpClient,
+    directory: Directory,
+    key: AccountKey,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+fn url_of(v: &serde_json::Value, name: &str) -> anyhow::Result<String> {
+    match v[name].as_str() {
+        Some(v) => Ok(v.to_string()),
+        None => anyhow::error(&format!("acme response has no `{}`, {}", name, v)),
+    }
+}
+
+fn load_or_create_account_key(cfg: &AcmeConfig) -> anyhow::Result<rcgen::KeyPair> {
+    let fp = cfg.account_key();
+    match std::fs::read_to_string(&fp) {
+        Ok(pem) => anyhow::result(rcgen::KeyPair::from_pem(&pem)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key = anyhow::result(rcgen::KeyPair::generate())?;
+            write_private(&fp, key.serialize_pem().as_bytes())?;
+            log::info!("acme account key created, {}", fp);
+            Ok(key)
+        }
+        Err(e) => anyhow::error(&format!("read acme account key `{}` failed, {}", fp, e)),
+    }
+}
+
+fn write_private(fp: &str, content: &[u8]) -> anyhow::Result<()> {
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    }
+    let mut file = anyhow::result(opts.open(fp))?;
+    anyhow::result(std::io::Write::write_all(&mut file, content))
+}
+
+impl<'a> AcmeClient<'a> {
+    async fn new(cfg: &'a AcmeConfig, provider: &'a CryptoProvider) -> anyhow::Result<Self> {
+        anyhow::result(std::fs::create_dir_all(&cfg.dir))?;
+        let key = AccountKey::new(provider, &load_or_create_account_key(cfg)?)?;
+
+        let http = HttpClient::new(&cfg.ca)?;
+        let resp = http.request("GET", &cfg.directory, None).await?;
+        if resp.status != 200 {
+            return anyhow::error(&format!(
+                "fetch acme directory `{}` failed, status {}",
+                cfg.directory, resp.status
+            ));
+        }
+        let v = resp.json()?;
+        let directory = Directory {
+            new_nonce: url_of(&v, "newNonce")?,
+            new_account: url_of(&v, "newAccount")?,
+            new_order: url_of(&v, "newOrder")?,
+        };
+
+        Ok(Self {
+            cfg,
+            provider,
+            http,
+            directory,
+            key,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    async fn nonce(&mut self) -> anyhow::Result<String> {
+        match self.nonce.take() {
+            Some(v) => Ok(v),
+            None => {
+                let resp = self
+                    .http
+                    .request("HEAD", &self.directory.new_nonce, None)
+                    .await?;
+                Ok(
+                    anyhow::option(resp.header("replay-nonce"), "acme server sent no nonce")?
+                        .to_string(),
+                )
+            }
+        }
+    }
+
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&serde_json::Value>,
+    ) -> anyhow::Result<Response> {
+        // a nonce may be rejected once, RFC 8555 section 6.5
+        for _ in 0..2 {
+            let nonce = self.nonce().await?;
+            let body = self.key.sign(url, &nonce, self.kid.as_deref(), payload)?;
+            let resp = self.http.request("POST", url, Some(&body)).await?;
+            self.nonce = resp.header("replay-nonce").map(|v| v.to_string());
+            if resp.status < 400 {
+                return Ok(resp);
+            }
+
+            let problem = resp.json().unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                continue;
+            }
+            return anyhow::error(&format!(
+                "acme request `{}` failed, status {}, {}",
+                url,
+                resp.status,
+                String::from_utf8_lossy(&resp.body)
+            ));
+        }
+        anyhow::error(&format!("acme request `{}` failed, bad nonce", url))
+    }
+
+    async fn account(&mut self) -> anyhow::Result<()> {
+        let contacts: Vec<String> = self
+            .cfg
+            .contacts
+            .iter()
+            .map(|v| {
+                if v.contains(':') {
+                    v.clone()
+                } else {
+                    format!("mailto:{}", v)
+                }
+            })
+            .collect();
+        let url = self.directory.new_account.clone();
+        // an existing account of the same key is returned as is
+        let resp = self
+            .post(
+                &url,
+                Some(&serde_json::json!({
+                    "termsOfServiceAgreed": self.cfg.agree_tos,
+                    "contact": contacts,
+                })),
+            )
+            .await?;
+        self.kid = Some(
+            anyhow::option(resp.header("location"), "acme account has no location")?.to_string(),
+        );
+        Ok(())
+    }
+
+    async fn poll(&mut self, url: &str, pending: &[&str]) -> anyhow::Result<serde_json::Value> {
+        for _ in 0..POLL_TIMES {
+            let v = self.post(url, None).await?.json()?;
+            match v["status"].as_str() {
+                Some(status) if pending.contains(&status) => {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+                _ => return Ok(v),
+            }
+        }
+        anyhow::error(&format!("acme object `{}` is still pending", url))
+    }
+
+    async fn authorize(&mut self, url: &str) -> anyhow::Result<()> {
+        let authz = self.post(url, None).await?.json()?;
+        if authz["status"] == "valid" {
+            return Ok(());
+        }
+        let domain = anyhow::option(
+            authz["identifier"]["value"].as_str(),
+            "acme authorization has no identifier",
+        )?
+        .to_string();
+
+        let kind = match self.cfg.challenge {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        };
+        let challenge = match authz["challenges"]
+            .as_array()
+            .and_then(|v| v.iter().find(|c| c["type"] == kind))
+        {
+            Some(v) => v.clone(),
+            None => {
+                return anyhow::error(&format!(
+                    "acme offers no {} challenge for `{}`",
+                    kind, domain
+                ));
+            }
+        };
+        let token = url_of(&challenge, "token")?;
+        let key_authorization = self.key.key_authorization(&token);
+        match self.cfg.challenge {
+            AcmeChallenge::Http01 => challenge::add_http01(&token, &key_authorization),
+            AcmeChallenge::TlsAlpn01 => {
+                challenge::add_tls_alpn01(self.provider, &domain, &key_authorization)?
+            }
+        }
+
+        let result = async {
+            self.post(&url_of(&challenge, "url")?, Some(&serde_json::json!({})))
+                .await?;
+            self.poll(url, &["pending", "processing"]).await
+        }
+        .await;
+
+        match self.cfg.challenge {
+            AcmeChallenge::Http01 => challenge::remove_http01(&token),
+            AcmeChallenge::TlsAlpn01 => challenge::remove_tls_alpn01(&domain),
+        }
+
+        let authz = result?;
+        if authz["status"] != "valid" {
+            return anyhow::error(&format!(
+                "acme authorization for `{}` failed, {}",
+                domain, authz
+            ));
+        }
+        Ok(())
+    }
+
+    async fn issue(&mut self) -> anyhow::Result<()> {
+        self.account().await?;
+
+        let identifiers: Vec<serde_json::Value> = self
+            .cfg
+            .domains
+            .iter()
+            .map(|v| serde_json::json!({"type": "dns", "value": v}))
+            .collect();
+        let url = self.directory.new_order.clone();
+        let resp = self
+            .post(&url, Some(&serde_json::json!({"identifiers": identifiers})))
+            .await?;
+        let order_url =
+            anyhow::option(resp.header("location"), "acme order has no location")?.to_string();
+        let order = resp.json()?;
+
+        let authorizations: Vec<String> = order["authorizations"]
+            .as_array()
+            .map(|v| {
+                v.iter()
+                    .filter_map(|v| v.as_str().map(|v| v.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        for authz in authorizations.iter() {
+            self.authorize(authz).await?;
+        }
+
+        let keypair = anyhow::result(rcgen::KeyPair::generate())?;
+        let params = anyhow::result(rcgen::CertificateParams::new(self.cfg.domains.clone()))?;
+        let csr = anyhow::result(params.serialize_request(&keypair))?;
+        self.post(
+            &url_of(&order, "finalize")?,
+            Some(&serde_json::json!({"csr": b64(csr.der())})),
+        )
+        .await?;
+
+        let order = self
+            .poll(&order_url, &["pending", "ready", "processing"])
+            .await?;
+        if order["status"] != "valid" {
+            return anyhow::error(&format!("acme order failed, {}", order));
+        }
+        let chain = self.post(&url_of(&order, "certificate")?, None).await?.body;
+
+        // the key first, the reload watcher waits for both files to settle
+        write_private(&self.cfg.key, keypair.serialize_pem().as_bytes())?;
+        anyhow::result(std::fs::write(&self.cfg.cert, chain))?;
+        Ok(())
+    }
+}
+
+/// whether the stored certificate is missing or expires within `renew_before`.
+fn due(cfg: &AcmeConfig) -> bool {
+    let pem = match std::fs::read(&cfg.cert) {
+        Ok(v) => v,
+        Err(_) => return true,
+    };
+    let not_after = match x509_parser::pem::parse_x509_pem(&pem) {
+        Ok((_, pem)) => match pem.parse_x509() {
+            Ok(cert) => cert.validity().not_after.timestamp(),
+            Err(_) => return true,
+        },
+        Err(_) => return true,
+    };
+    let now = chrono::Utc::now().timestamp();
+    not_after - now < cfg.renew_before.as_secs() as i64
+}
+
+/// obtain the certificate if needed, then check for renewal every `check_interval`.
+pub(crate) async fn run(service: &'static ServiceConfig, resolver: Arc<SniResolver>) {
+    let cfg = match service.tcp.tls.acme.as_ref() {
+        Some(v) => v,
+        None => return,
+    };
+
+    loop {
+        let mut wait = cfg.check_interval.0;
+        if due(cfg) {
+            let provider = match CryptoProvider::get_default() {
+                Some(v) => v,
+                None => {
+                    log::error!(service = service.idx(); "acme certificate for {:?} is not issued, no tls crypto provider", cfg.domains);
+                    return;
+                }
+            };
+            let result = match AcmeClient::new(cfg, provider).await {
+                Ok(mut client) => client.issue().await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(_) => {
+                    log::info!(service = service.idx(); "acme certificate issued for {:?}", cfg.domains);
+                    super::reload::reload(service, &resolver);
+                }
+                Err(e) => {
+                    log::error!(service = service.idx(); "acme certificate for {:?} failed, {}", cfg.domains, e);
+                    wait = wait.min(Duration::from_secs(60 * 60));
+                }
+            }
+        }
+        tokio::time::sleep(wait).await;
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use tokio_rustls::rustls::{crypto::CryptoProvider, sign::CertifiedKey};
+
+use crate::utils::anyhow;
+
+pub(crate) mod acme;
+pub(crate) mod reload;
+pub(crate) mod resolver;
+pub(crate) mod selfsigned;
+
+pub(crate) fn load_certs(
+    fp: &str,
+) -> anyhow::Result<Vec<tokio_rustls::rustls::pki_types::CertificateDer<'static>>> {
+    let mut certs = vec![];
+    for v in rustls_pemfile::certs(&mut std::io::BufReader::new(anyhow::result(
+        std::fs::File::open(fp),
+    )?)) {
+        certs.push(anyhow::result(v)?);
+    }
+    if certs.is_empty() {
+        return anyhow::error("no certificate found");
+    }
+    Ok(certs)
+}
+
+pub(crate) fn load_key(
+    fp: &str,
+) -> anyhow::Result<tokio_rustls::rustls::pki_types::PrivateKeyDer<'static>> {
+    let key = anyhow::result(rustls_pemfile::private_key(&mut std::io::BufReader::new(
+        anyhow::result(std::fs::File::open(fp))?,
+    )))?;
+    anyhow::option(key, "none key")
+}
+
+/// load a cert chain and its private key, errors name the failing file and `domains`.
+pub(crate) fn load_certified_key(
+    provider: &CryptoProvider,
+    cert: &str,
+    key: &str,
+    domains: &[String],
+) -> anyhow::Result<Arc<CertifiedKey>> {
+    let certs = match load_certs(cert) {
+        Ok(certs) => certs,
+        Err(e) => {
+            return anyhow::error(&format!(
+                "load certificate `{}` for {:?} failed, {}",
+                cert, domains, e
+            ));
+        }
+    };
+    let key = match load_key(key)
+        .and_then(|v| anyhow::result(provider.key_provider.load_private_key(v)))
+    {
+        Ok(v) => v,
+        Err(e) => {
+            return anyhow::error(&format!(
+                "load private key `{}` for {:?} failed, {}",
+                key, domains, e
+            ));
+        }
+    };
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+/// the verified certificate a client presented in the handshake.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClientCert {
+    pub(crate) subject: String,
+    pub(crate) sans: Vec<String>,
+}
+
+impl ClientCert {
+    pub(crate) fn parse(
+        certs: Option<&[tokio_rustls::rustls::pki_types::CertificateDer]>,
+    ) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(certs?.first()?.as_ref()).ok()?;
+
+        let mut sans = vec![];
+        match cert.subject_alternative_name() {
+            Ok(Some(ext)) => {
+                for name in ext.value.general_names.iter() {
+                    match name {
+                        x509_parser::extensions::GeneralName::DNSName(v)
+                        | x509_parser::extensions::GeneralName::RFC822Name(v)
+                        | x509_parser::extensions::GeneralName::URI(v) => {
+                            sans.push(v.to_string());
+                        }
+                        x509_parser::extensions::GeneralName::IPAddress(v) => match v.len() {
+                            4 => {
+                                let v: [u8; 4] = (*v).try_into().unwrap();
+                                sans.push(std::net::Ipv4Addr::from(v).to_string());
+                            }
+                            16 => {
+                                let v: [u8; 16] = (*v).try_into().unwrap();
+                                sans.push(std::net::Ipv6Addr::from(v).to_string());
+                            }
+                            _ => {}
+                        },
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Some(Self {
+            subject: cert.subject().to_string(),
+            sans,
+        })
+    }
+}
+
+/// what the tls layer knows about a connection.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TlsInfo {
+    pub(crate) sni: Option<String>,
+    pub(crate) client_cert: Option<ClientCert>,
+}
+
+impl TlsInfo {
+    pub(crate) fn from_conn(conn: &tokio_rustls::rustls::ServerConnection) -> Self {
+        Self {
+            sni: conn.server_name().map(|v| v.to_string()),
+            client_cert: ClientCert::parse(conn.peer_certificates()),
+        }
+    }
+}
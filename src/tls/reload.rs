@@ -0,0 +1,52 @@
+use std::{collections::HashMap, sync::Arc, time::SystemTime};
+
+use tokio_rustls::rustls::crypto::CryptoProvider;
+
+use crate::config::{service::ServiceConfig, tls::TlsConfig};
+
+use super::resolver::SniResolver;
+
+fn mtimes(cfg: &TlsConfig) -> HashMap<String, Option<SystemTime>> {
+    let mut map = HashMap::new();
+    for fp in cfg.files() {
+        let mtime = std::fs::metadata(fp).and_then(|v| v.modified()).ok();
+        map.insert(fp.to_string(), mtime);
+    }
+    map
+}
+
+pub(crate) fn reload(service: &ServiceConfig, resolver: &SniResolver) {
+    let provider = match CryptoProvider::get_default() {
+        Some(v) => v,
+        None => return,
+    };
+    match service.tcp.tls.load_store(provider) {
+        Ok(store) => {
+            resolver.swap(store);
+            log::info!(service = service.idx(); "tls certificates reloaded, {}", service.name);
+        }
+        Err(e) => {
+            log::error!(service = service.idx(); "reload tls certificates failed, keep the old ones, {}", e);
+        }
+    }
+}
+
+/// poll the cert/key files and reload on change, new handshakes get the new certificates.
+/// SIGHUP reloads them with the config, see `supervisor::reload`.
+pub(crate) async fn watch(service: &'static ServiceConfig, resolver: Arc<SniResolver>) {
+    let cfg = &service.tcp.tls;
+    let mut prev = mtimes(cfg);
+    let mut interval = tokio::time::interval(cfg.watch_interval.0);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        let current = mtimes(cfg);
+        if current != prev {
+            // a renewal job may write the cert and the key separately, wait for it to settle
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            prev = mtimes(cfg);
+            reload(service, &resolver);
+        }
+    }
+}
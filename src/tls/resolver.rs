@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use tokio_rustls::rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+use crate::utils::anyhow;
+
+use super::acme::challenge;
+
+/// certificates of one listener, selected by the SNI of the client hello.
+#[derive(Debug, Default)]
+pub(crate) struct CertStore {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    // `*.example.com` is saved as `example.com`
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl CertStore {
+    pub(crate) fn insert(&mut self, domain: &str, key: Arc<CertifiedKey>) -> anyhow::Result<()> {
+        let domain = normalize(domain);
+        let (map, name) = match domain.strip_prefix("*.") {
+            Some(suffix) => (&mut self.wildcard, suffix.to_string()),
+            None => (&mut self.exact, domain.clone()),
+        };
+        if name.is_empty() || name.contains('*') {
+            return anyhow::error(&format!("bad tls domain, `{}`", domain));
+        }
+        if map.contains_key(&name) {
+            return anyhow::error(&format!("duplicate tls domain, `{}`", domain));
+        }
+        map.insert(name, key);
+        Ok(())
+    }
+
+    pub(crate) fn set_default(&mut self, key: Arc<CertifiedKey>) -> anyhow::Result<()> {
+        if self.default.is_some() {
+            return anyhow::error("more than one default tls certificate");
+        }
+        self.default = Some(key);
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn has_default(&self) -> bool {
+        self.default.is_some()
+    }
+
+    pub(crate) fn get(&self, sni: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        match sni {
+            Some(sni) => {
+                let sni = normalize(sni);
+                match self.exact.get(&sni) {
+                    Some(key) => return Some(key.clone()),
+                    None => {}
+                }
+                // a wildcard only covers one label
+                match sni.split_once('.') {
+                    Some((_, parent)) => match self.wildcard.get(parent) {
+                        Some(key) => return Some(key.clone()),
+                        None => {}
+                    },
+                    None => {}
+                }
+                self.default.clone()
+            }
+            None => self.default.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SniResolver {
+    store: RwLock<Arc<CertStore>>,
+}
+
+impl SniResolver {
+    pub(crate) fn new(store: CertStore) -> Self {
+        Self {
+            store: RwLock::new(Arc::new(store)),
+        }
+    }
+
+    /// replace all certificates, handshakes in progress keep the store they already got.
+    pub(crate) fn swap(&self, store: CertStore) {
+        let store = Arc::new(store);
+        match self.store.write() {
+            Ok(mut g) => *g = store,
+            Err(poisoned) => *poisoned.into_inner() = store,
+        }
+    }
+
+    fn current(&self) -> Arc<CertStore> {
+        match self.store.read() {
+            Ok(g) => g.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let validation = client_hello
+            .alpn()
+            .map(|mut v| v.any(|p| p == challenge::ACME_TLS_ALPN))
+            .unwrap_or(false);
+        if validation {
+            return challenge::tls_alpn01(client_hello.server_name());
+        }
+        self.current().get(client_hello.server_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio_rustls::rustls::{
+        pki_types::CertificateDer,
+        sign::{CertifiedKey, Signer, SigningKey},
+        SignatureAlgorithm, SignatureScheme,
+    };
+
+    use super::CertStore;
+
+    #[derive(Debug)]
+    struct NoKey;
+
+    impl SigningKey for NoKey {
+        fn choose_scheme(&self, _: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+            None
+        }
+
+        fn algorithm(&self) -> SignatureAlgorithm {
+            SignatureAlgorithm::ECDSA
+        }
+    }
+
+    fn key(name: &str) -> Arc<CertifiedKey> {
+        Arc::new(CertifiedKey::new(
+            vec![CertificateDer::from(name.as_bytes().to_vec())],
+            Arc::new(NoKey),
+        ))
+    }
+
+    fn name(v: Option<Arc<CertifiedKey>>) -> String {
+        match v {
+            Some(v) => String::from_utf8(v.cert[0].to_vec()).unwrap(),
+            None => "".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_sni_select() {
+        let mut store = CertStore::default();
+        store.insert("example.com", key("a")).unwrap();
+        store.insert("*.example.com", key("b")).unwrap();
+        store.insert("API.example.com.", key("c")).unwrap();
+        assert!(store.insert("*.example.com", key("d")).is_err());
+        assert!(store.insert("a.*.com", key("d")).is_err());
+
+        assert_eq!(name(store.get(Some("example.com"))), "a");
+        assert_eq!(name(store.get(Some("www.Example.com"))), "b");
+        assert_eq!(name(store.get(Some("api.example.com"))), "c");
+        assert_eq!(name(store.get(Some("a.b.example.com"))), "");
+        assert_eq!(name(store.get(None)), "");
+
+        store.set_default(key("default")).unwrap();
+        assert!(store.set_default(key("default")).is_err());
+        assert_eq!(name(store.get(Some("a.b.example.com"))), "default");
+        assert_eq!(name(store.get(None)), "default");
+    }
+}
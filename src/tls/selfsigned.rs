@@ -0,0 +1,134 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use tokio_rustls::rustls::{
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    sign::CertifiedKey,
+};
+
+use crate::utils::anyhow;
+
+fn names(domains: &[String]) -> Vec<String> {
+    let mut names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    for domain in domains.iter() {
+        if !names.contains(domain) {
+            names.push(domain.clone());
+        }
+    }
+    names
+}
+
+/// a self-signed certificate for `domains`, `localhost` and the loopback addresses.
+pub(crate) fn generate(domains: &[String]) -> anyhow::Result<rcgen::CertifiedKey> {
+    match rcgen::generate_simple_self_signed(names(domains)) {
+        Ok(v) => Ok(v),
+        Err(e) => anyhow::error(&format!(
+            "generate self-signed certificate for {:?} failed, {}",
+            domains, e
+        )),
+    }
+}
+
+// an in-memory certificate and its pkcs8 key by the sorted names, reloads keep them
+type Generated = HashMap<Vec<String>, (CertificateDer<'static>, Vec<u8>)>;
+
+fn generated() -> &'static Mutex<Generated> {
+    static GENERATED: OnceLock<Mutex<Generated>> = OnceLock::new();
+    GENERATED.get_or_init(Default::default)
+}
+
+/// an in-memory certificate, generated once per set of domains, it changes on every start.
+pub(crate) fn certified_key(
+    provider: &CryptoProvider,
+    domains: &[String],
+) -> anyhow::Result<Arc<CertifiedKey>> {
+    let mut names = names(domains);
+    names.sort();
+    let (cert, key) = {
+        let mut cache = match generated().lock() {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match cache.get(&names) {
+            Some(v) => v.clone(),
+            None => {
+                let generated = generate(domains)?;
+                log::warn!("using a generated self-signed certificate for {:?}", names);
+                let v = (
+                    generated.cert.der().clone(),
+                    generated.key_pair.serialize_der(),
+                );
+                cache.insert(names, v.clone());
+                v
+            }
+        }
+    };
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key));
+    let key = anyhow::result(provider.key_provider.load_private_key(key))?;
+    Ok(Arc::new(CertifiedKey::new(vec![cert], key)))
+}
+
+/// write a certificate and key as pem files.
+pub(crate) fn write(cert: &str, key: &str, domains: &[String]) -> anyhow::Result<()> {
+    let generated = generate(domains)?;
+    anyhow::result(std::fs::write(cert, generated.cert.pem()))?;
+
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    }
+    let mut file = anyhow::result(opts.open(key))?;
+    anyhow::result(std::io::Write::write_all(
+        &mut file,
+        generated.key_pair.serialize_pem().as_bytes(),
+    ))?;
+    Ok(())
+}
+
+/// generate `cert` and `key` unless both exist, so restarts keep the same certificate.
+pub(crate) fn persist(cert: &str, key: &str, domains: &[String]) -> anyhow::Result<()> {
+    if std::path::Path::new(cert).exists() && std::path::Path::new(key).exists() {
+        return Ok(());
+    }
+    match write(cert, key, domains) {
+        Ok(_) => {
+            log::warn!(
+                "generated a self-signed certificate `{}` for {:?}",
+                cert,
+                names(domains)
+            );
+            Ok(())
+        }
+        Err(e) => anyhow::error(&format!(
+            "write self-signed certificate `{}` failed, {}",
+            cert, e
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_certified_key() {
+        let builder = tokio_rustls::rustls::ServerConfig::builder();
+        let provider = builder.crypto_provider();
+        let domains = |v: &[&str]| v.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+
+        let a = certified_key(provider, &domains(&["a.test", "b.test"])).unwrap();
+        let b = certified_key(provider, &domains(&["b.test", "a.test", "localhost"])).unwrap();
+        assert_eq!(a.cert, b.cert);
+        let c = certified_key(provider, &domains(&["c.test"])).unwrap();
+        assert_ne!(a.cert, c.cert);
+    }
+}
@@ -0,0 +1,579 @@
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{Mutex, OnceLock},
+};
+
+use crate::{config::unix::UnixConfig, shutdown, utils::anyhow};
+
+// `tcp@127.0.0.1:8080@name=3;udp@127.0.0.1:8443@name=4;unix@/run/httpd.sock@name=5`, the
+// listening sockets of the services handed to a new binary
+const FDS_ENV: &str = "HTTPD_UPGRADE_FDS";
+// the write end of a pipe, the new binary writes a byte to it once its services are started
+const READY_ENV: &str = "HTTPD_UPGRADE_READY";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Kind {
+    Tcp,
+    Udp,
+    Unix,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Tcp => "tcp",
+            Kind::Udp => "udp",
+            Kind::Unix => "unix",
+        }
+    }
+}
+
+struct Socket {
+    kind: Kind,
+    // a `SocketAddr`, or the path of a unix socket
+    addr: String,
+    // the service name, empty if unknown
+    name: String,
+    #[cfg(unix)]
+    fd: std::os::fd::OwnedFd,
+}
+
+#[derive(Default)]
+struct State {
+    // sockets inherited from the old binary or systemd, not claimed by a service yet
+    inherited: Vec<Socket>,
+    // a duplicate of every listening socket, handed to the new binary
+    live: Vec<Socket>,
+    // the pipe to the old binary
+    #[cfg(unix)]
+    ready: Option<std::os::fd::OwnedFd>,
+    upgrading: bool,
+    // listeners spawned on a runtime and not listening yet
+    pending: usize,
+    // the services are started, see `ready`
+    started: bool,
+}
+
+fn state() -> std::sync::MutexGuard<'static, State> {
+    static STATE: OnceLock<Mutex<State>> = OnceLock::new();
+    let state = STATE.get_or_init(|| Mutex::new(State::default()));
+    match state.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn resolve(addr: &str) -> anyhow::Result<Vec<SocketAddr>> {
+    Ok(anyhow::result(addr.to_socket_addrs())?.collect())
+}
+
+fn texts(addrs: &[SocketAddr]) -> Vec<String> {
+    addrs.iter().map(|v| v.to_string()).collect()
+}
+
+// a socket named after the service first, then one bound to its address
+fn find_inherited(state: &State, kind: Kind, name: &str, addrs: &[String]) -> Option<usize> {
+    match state
+        .inherited
+        .iter()
+        .position(|v| v.kind == kind && !v.name.is_empty() && v.name == name)
+    {
+        Some(idx) => Some(idx),
+        None => state
+            .inherited
+            .iter()
+            .position(|v| v.kind == kind && addrs.contains(&v.addr)),
+    }
+}
+
+#[cfg(unix)]
+fn take_inherited(kind: Kind, name: &str, addrs: &[String]) -> Option<std::os::fd::OwnedFd> {
+    let mut state = state();
+    let idx = find_inherited(&state, kind, name, addrs)?;
+    Some(state.inherited.remove(idx).fd)
+}
+
+/// whether a socket passed for the listener of service `name` at `addr` is not claimed yet.
+/// the runtimes of per_core mode claim one each, the old binary may have had as many.
+pub(crate) fn inherits(kind: Kind, name: &str, addr: &str) -> bool {
+    let addrs = match kind {
+        Kind::Unix => vec![addr.to_string()],
+        _ => match resolve(addr) {
+            Ok(v) => texts(&v),
+            Err(_) => return false,
+        },
+    };
+    find_inherited(&state(), kind, name, &addrs).is_some()
+}
+
+/// a socket passed by systemd, see `take_inherited`.
+#[cfg(unix)]
+pub(crate) fn adopt(kind: Kind, name: &str, addr: String, fd: std::os::fd::OwnedFd) {
+    state().inherited.push(Socket {
+        kind,
+        addr,
+        name: name.to_string(),
+        fd,
+    });
+}
+
+/// the listener of service `name`, inherited from the old binary or systemd if they passed
+/// one, bound to `addr` otherwise. with `reuse_port` every runtime of per_core mode binds
+/// `addr` and the kernel spreads the connections over them.
+pub(crate) fn tcp_listener(
+    name: &str,
+    addr: &str,
+    reuse_port: bool,
+) -> anyhow::Result<std::net::TcpListener> {
+    let addrs = resolve(addr)?;
+    #[cfg(unix)]
+    let listener = match take_inherited(Kind::Tcp, name, &texts(&addrs)) {
+        Some(fd) => std::net::TcpListener::from(fd),
+        None => anyhow::result(bind_tcp(&addrs, reuse_port))?,
+    };
+    #[cfg(not(unix))]
+    let listener = anyhow::result(bind_tcp(&addrs, reuse_port))?;
+    anyhow::result(listener.set_nonblocking(true))?;
+    Ok(listener)
+}
+
+fn bind_tcp(addrs: &[SocketAddr], reuse_port: bool) -> std::io::Result<std::net::TcpListener> {
+    if !reuse_port {
+        return std::net::TcpListener::bind(addrs);
+    }
+
+    let mut last = None;
+    for addr in addrs {
+        match bind_reuse_port(addr) {
+            Ok(v) => return Ok(v),
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(last.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to bind")
+    }))
+}
+
+fn bind_reuse_port(addr: &SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    // as `TcpListener::bind` does
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// whether more listeners may bind the address of `listener`, false for a socket passed by
+/// systemd without `ReusePort=`.
+pub(crate) fn reuses_port(listener: &std::net::TcpListener) -> bool {
+    #[cfg(unix)]
+    {
+        socket2::SockRef::from(listener)
+            .reuse_port()
+            .unwrap_or(false)
+    }
+    #[cfg(not(unix))]
+    false
+}
+
+/// a udp socket for `addr`, see `tcp_listener`.
+pub(crate) fn udp_socket(name: &str, addr: &str) -> anyhow::Result<std::net::UdpSocket> {
+    let addrs = resolve(addr)?;
+    #[cfg(unix)]
+    let socket = match take_inherited(Kind::Udp, name, &texts(&addrs)) {
+        Some(fd) => std::net::UdpSocket::from(fd),
+        None => anyhow::result(std::net::UdpSocket::bind(addrs.as_slice()))?,
+    };
+    #[cfg(not(unix))]
+    let socket = anyhow::result(std::net::UdpSocket::bind(addrs.as_slice()))?;
+    anyhow::result(socket.set_nonblocking(true))?;
+    Ok(socket)
+}
+
+/// the unix socket listener of service `name` at `path`, see `tcp_listener`.
+#[cfg(unix)]
+pub(crate) fn unix_listener(
+    name: &str,
+    path: &str,
+    cfg: &UnixConfig,
+) -> anyhow::Result<std::os::unix::net::UnixListener> {
+    let listener = match take_inherited(Kind::Unix, name, &[path.to_string()]) {
+        Some(fd) => std::os::unix::net::UnixListener::from(fd),
+        None => crate::listener::bind_unix(path, cfg)?,
+    };
+    anyhow::result(listener.set_nonblocking(true))?;
+    Ok(listener)
+}
+
+/// keeps a duplicate of a listening socket to hand to the new binary, until dropped.
+pub(crate) struct Handover {
+    // the duplicate, the runtimes of per_core mode have a socket each for the same address
+    #[cfg(unix)]
+    fd: std::os::fd::RawFd,
+}
+
+impl Drop for Handover {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsRawFd;
+            state().live.retain(|v| v.fd.as_raw_fd() != self.fd);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn hand_over(
+    kind: Kind,
+    name: &str,
+    addr: String,
+    fd: std::os::fd::BorrowedFd,
+) -> anyhow::Result<Handover> {
+    use std::os::fd::AsRawFd;
+
+    let fd = anyhow::result(fd.try_clone_to_owned())?;
+    let handover = Handover { fd: fd.as_raw_fd() };
+    state().live.push(Socket {
+        kind,
+        addr,
+        name: name.to_string(),
+        fd,
+    });
+    Ok(handover)
+}
+
+pub(crate) fn hand_over_tcp(
+    name: &str,
+    listener: &std::net::TcpListener,
+) -> anyhow::Result<Handover> {
+    let addr = anyhow::result(listener.local_addr())?;
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsFd;
+        hand_over(Kind::Tcp, name, addr.to_string(), listener.as_fd())
+    }
+    #[cfg(not(unix))]
+    Ok(Handover {})
+}
+
+pub(crate) fn hand_over_udp(name: &str, socket: &std::net::UdpSocket) -> anyhow::Result<Handover> {
+    let addr = anyhow::result(socket.local_addr())?;
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsFd;
+        hand_over(Kind::Udp, name, addr.to_string(), socket.as_fd())
+    }
+    #[cfg(not(unix))]
+    Ok(Handover {})
+}
+
+#[cfg(unix)]
+pub(crate) fn hand_over_unix(
+    name: &str,
+    path: &str,
+    listener: &std::os::unix::net::UnixListener,
+) -> anyhow::Result<Handover> {
+    use std::os::fd::AsFd;
+    hand_over(Kind::Unix, name, path.to_string(), listener.as_fd())
+}
+
+/// take over the sockets listed by the old binary, before any other thread is started.
+pub(crate) fn inherit() -> anyhow::Result<()> {
+    let fds = std::env::var(FDS_ENV);
+    let ready = std::env::var(READY_ENV);
+    std::env::remove_var(FDS_ENV);
+    std::env::remove_var(READY_ENV);
+
+    #[cfg(unix)]
+    {
+        use std::os::fd::{FromRawFd, OwnedFd};
+
+        let mut state = state();
+        for item in fds.unwrap_or_default().split(';').filter(|v| !v.is_empty()) {
+            let bad = || anyhow::error(&format!("bad {} item `{}`", FDS_ENV, item));
+            let (kind, rest) = match item.split_once('@') {
+                Some(("tcp", rest)) => (Kind::Tcp, rest),
+                Some(("udp", rest)) => (Kind::Udp, rest),
+                Some(("unix", rest)) => (Kind::Unix, rest),
+                _ => return bad(),
+            };
+            // a unix socket path may have a `@` in it
+            let (addr, name, fd) = match rest.rsplit_once('=') {
+                Some((rest, fd)) => match rest.rsplit_once('@') {
+                    Some((addr, name)) => match fd.parse::<i32>() {
+                        Ok(fd) if fd > 2 && !addr.is_empty() => (addr, name, fd),
+                        _ => return bad(),
+                    },
+                    None => return bad(),
+                },
+                None => return bad(),
+            };
+            // SAFETY: the old binary passed the fd for us alone
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            anyhow::result(set_cloexec(&fd, true))?;
+            state.inherited.push(Socket {
+                kind,
+                addr: addr.to_string(),
+                name: name.to_string(),
+                fd,
+            });
+        }
+
+        match ready {
+            Ok(fd) => match fd.parse::<i32>() {
+                Ok(fd) if fd > 2 => {
+                    // SAFETY: the write end of the pipe is ours alone
+                    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                    anyhow::result(set_cloexec(&fd, true))?;
+                    state.ready = Some(fd);
+                }
+                _ => return anyhow::error(&format!("bad {} `{}`", READY_ENV, fd)),
+            },
+            Err(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// a listener spawned on a runtime, `ready` waits until every one is dropped, i.e. is
+/// listening or failed.
+pub(crate) struct Pending(());
+
+impl Pending {
+    pub(crate) fn new() -> Self {
+        state().pending += 1;
+        Pending(())
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let mut state = state();
+        state.pending -= 1;
+        if state.started && state.pending == 0 {
+            finish(&mut state);
+        }
+    }
+}
+
+/// the services are started, once their listeners are up close the inherited sockets no
+/// service claimed and tell the old binary to drain.
+pub(crate) fn ready() {
+    let mut state = state();
+    state.started = true;
+    if state.pending == 0 {
+        finish(&mut state);
+    }
+}
+
+fn finish(state: &mut State) {
+    for socket in std::mem::take(&mut state.inherited) {
+        log::info!(
+            "inherited {} socket @ {} is not used by any service, closed",
+            socket.kind.name(),
+            socket.addr
+        );
+    }
+
+    #[cfg(unix)]
+    {
+        match state.ready.take() {
+            Some(fd) => {
+                let mut pipe = std::fs::File::from(fd);
+                match std::io::Write::write_all(&mut pipe, b"1") {
+                    Ok(_) => println!("httpd: took over from the old process"),
+                    Err(e) => log::error!("notify the old process failed, {}", e),
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn set_cloexec(fd: &impl std::os::fd::AsRawFd, on: bool) -> std::io::Result<()> {
+    let fd = fd.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let flags = if on {
+        flags | libc::FD_CLOEXEC
+    } else {
+        flags & !libc::FD_CLOEXEC
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// the binary of the running process, a replaced file reads as `path (deleted)`
+#[cfg(unix)]
+fn executable() -> anyhow::Result<std::path::PathBuf> {
+    let exe = anyhow::result(std::env::current_exe())?;
+    let path = exe.to_string_lossy();
+    match path.strip_suffix(" (deleted)") {
+        Some(v) => Ok(std::path::PathBuf::from(v)),
+        None => Ok(exe),
+    }
+}
+
+/// start the binary at the path of the running one with the listening sockets, once it is
+/// ready this process stops accepting and drains like on shutdown. a new binary failing to
+/// start leaves this process serving.
+#[cfg(unix)]
+pub(crate) fn start() -> anyhow::Result<()> {
+    use std::os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::process::CommandExt,
+    };
+
+    if shutdown::triggered() {
+        return anyhow::error("shutting down");
+    }
+
+    let mut guard = state();
+    if guard.upgrading {
+        return anyhow::error("an upgrade is in progress");
+    }
+
+    let mut fds = vec![];
+    let mut inodes = vec![];
+    let mut env = String::new();
+    for socket in guard.live.iter() {
+        let fd = socket.fd.as_raw_fd();
+        // the runtimes sharing one socket have a duplicate each, it is passed once
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } == 0 {
+            if inodes.contains(&stat.st_ino) {
+                continue;
+            }
+            inodes.push(stat.st_ino);
+        }
+        fds.push(fd);
+        env.push_str(&format!(
+            "{}@{}@{}={};",
+            socket.kind.name(),
+            socket.addr,
+            socket.name,
+            fd
+        ));
+    }
+
+    let mut pipe = [0; 2];
+    if unsafe { libc::pipe(pipe.as_mut_ptr()) } < 0 {
+        return anyhow::error(&format!(
+            "create pipe failed, {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    // SAFETY: both ends were just created
+    let (reader, writer) =
+        unsafe { (OwnedFd::from_raw_fd(pipe[0]), OwnedFd::from_raw_fd(pipe[1])) };
+    anyhow::result(set_cloexec(&reader, true))?;
+    anyhow::result(set_cloexec(&writer, true))?;
+    fds.push(writer.as_raw_fd());
+
+    let exe = executable()?;
+    let basedir = crate::supervisor::basedir();
+    let mut cmd = std::process::Command::new(&exe);
+    cmd.args(std::env::args_os().skip(1))
+        .env(FDS_ENV, env)
+        .env(READY_ENV, writer.as_raw_fd().to_string())
+        // the new process pings the watchdog once it is the main process
+        .env_remove("WATCHDOG_PID");
+    if !basedir.is_empty() {
+        cmd.current_dir(basedir);
+    }
+    // SAFETY: only fcntl runs between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            for fd in fds.iter() {
+                set_cloexec(fd, false)?;
+            }
+            Ok(())
+        });
+    }
+    let mut child = match cmd.spawn() {
+        Ok(v) => v,
+        Err(e) => return anyhow::error(&format!("start `{}` failed, {}", exe.display(), e)),
+    };
+    drop(writer);
+    guard.upgrading = true;
+    println!("httpd: upgrading, new process pid: {}", child.id());
+
+    let result = std::thread::Builder::new()
+        .name("httpd.upgrade".to_string())
+        .spawn(move || {
+            let mut reader = std::fs::File::from(reader);
+            let mut buf = [0u8; 1];
+            // eof if the new process exits before it is ready
+            let ready = matches!(std::io::Read::read(&mut reader, &mut buf), Ok(1));
+            if ready {
+                log::info!("new process {} is ready, draining", child.id());
+                println!("httpd: new process is ready, shutting down");
+                shutdown::trigger();
+                return;
+            }
+            let status = child.wait();
+            log::error!("upgrade failed, new process exited, {:?}", status);
+            println!("httpd: upgrade failed, keep serving");
+            state().upgrading = false;
+        });
+    anyhow::result(result)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn start() -> anyhow::Result<()> {
+    anyhow::error("binary upgrade is only supported on unix")
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim() {
+        let first = bind_reuse_port(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind_reuse_port(&addr).unwrap();
+        let other = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = addr.to_string();
+        adopt(Kind::Tcp, "", addr.clone(), first.into());
+        adopt(Kind::Tcp, "", addr.clone(), second.into());
+        adopt(
+            Kind::Tcp,
+            "",
+            other.local_addr().unwrap().to_string(),
+            other.into(),
+        );
+
+        // one socket of the address per runtime
+        let mut claimed = vec![];
+        while inherits(Kind::Tcp, "web", &addr) {
+            claimed.push(tcp_listener("web", &addr, true).unwrap());
+        }
+        assert_eq!(claimed.len(), 2);
+        assert!(claimed
+            .iter()
+            .all(|v| v.local_addr().unwrap().to_string() == addr));
+
+        // the unused socket is closed once the listeners are up
+        let pending = Pending::new();
+        ready();
+        assert_eq!(state().inherited.len(), 1);
+        drop(pending);
+        assert!(state().inherited.is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+use std::net::IpAddr;
+
+use super::anyhow;
+
+/// an address block such as `10.0.0.0/8` or `fd00::/8`, a bare address is a block of one.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(v: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match v.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (v, None),
+        };
+        let addr = match addr.trim().parse::<IpAddr>() {
+            Ok(v) => v,
+            Err(_) => return anyhow::error(&format!("bad cidr `{}`", v)),
+        };
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix.map(|v| v.trim().parse::<u8>()) {
+            Some(Ok(v)) if v <= max => v,
+            None => max,
+            _ => return anyhow::error(&format!("bad cidr `{}`", v)),
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // an ipv4 client of a dual stack listener shows up as `::ffff:a.b.c.d`
+        let ip = match ip {
+            IpAddr::V6(v) => match v.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => *ip,
+            },
+            _ => *ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cidr;
+
+    #[test]
+    fn test_cidr() {
+        let ip = |v: &str| v.parse().unwrap();
+
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(&ip("10.1.2.3")));
+        assert!(net.contains(&ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(&ip("10.2.0.1")));
+        assert!(!net.contains(&ip("fd00::1")));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&ip("8.8.8.8")));
+        assert!(Cidr::parse("127.0.0.1").unwrap().contains(&ip("127.0.0.1")));
+        assert!(!Cidr::parse("127.0.0.1").unwrap().contains(&ip("127.0.0.2")));
+        assert!(Cidr::parse("fd00::/8").unwrap().contains(&ip("fd12::1")));
+        assert!(!Cidr::parse("fd00::/8").unwrap().contains(&ip("fe80::1")));
+
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("10.0.0/8").is_err());
+    }
+}
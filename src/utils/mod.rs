@@ -1,3 +1,5 @@
 pub mod anyhow;
+pub mod cidr;
+pub mod cpus;
 pub mod luxon;
 pub mod paths;
@@ -1,10 +1,93 @@
-use crate::{ctx::ConnContext, message::Message};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+use crate::{
+    ctx::ConnContext,
+    message::Message,
+    shutdown,
+    ws_impl::{self, WsOpCode},
+};
+
+// the peer has this long to answer our close frame
+const CLOSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+async fn send<W: tokio::io::AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    out: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    writer.write_all(out).await?;
+    out.clear();
+    writer.flush().await
+}
+
+async fn close<R: tokio::io::AsyncBufReadExt + Unpin, W: tokio::io::AsyncWriteExt + Unpin>(
+    ctx: &mut ConnContext<R, W>,
+    out: &mut Vec<u8>,
+    code: u16,
+    reason: &str,
+) {
+    ws_impl::write_close(out, code, reason);
+    if send(&mut ctx.writer, out).await.is_err() {
+        return;
+    }
+    let max = ctx.config.http.max_body_size.0;
+    _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        while let Ok(frame) = ws_impl::read_frame(&mut ctx.reader, max).await {
+            if frame.is_close() {
+                break;
+            }
+        }
+    })
+    .await;
+}
 
 pub(crate) async fn serve<
     R: tokio::io::AsyncBufReadExt + Unpin,
     W: tokio::io::AsyncWriteExt + Unpin,
 >(
-    _ctx: ConnContext<R, W>,
+    mut ctx: ConnContext<R, W>,
     _req: Message,
 ) {
+    let max = ctx.config.http.max_body_size.0;
+    let mut out = vec![];
+    loop {
+        tokio::select! {
+            result = ctx.reader.fill_buf() => {
+                match result {
+                    Ok(bytes) if !bytes.is_empty() => {}
+                    _ => return,
+                }
+            },
+            _ = shutdown::wait() => {
+                return close(&mut ctx, &mut out, ws_impl::CLOSE_GOING_AWAY, "shutdown").await;
+            }
+        }
+
+        let frame = match ws_impl::read_frame(&mut ctx.reader, max).await {
+            Ok(v) => v,
+            Err(e) => {
+                match e.close_code() {
+                    Some(code) => close(&mut ctx, &mut out, code, "").await,
+                    None => {}
+                }
+                return;
+            }
+        };
+
+        if frame.is_close() {
+            let code = match frame.payload.get(..2) {
+                Some(v) => u16::from_be_bytes([v[0], v[1]]),
+                None => ws_impl::CLOSE_NORMAL,
+            };
+            ws_impl::write_close(&mut out, code, "");
+            _ = send(&mut ctx.writer, &mut out).await;
+            return;
+        }
+        if frame.is(WsOpCode::Ping) {
+            ws_impl::write_frame(&mut out, WsOpCode::Pong as u8, &frame.payload);
+            if send(&mut ctx.writer, &mut out).await.is_err() {
+                return;
+            }
+        }
+        // services have no websocket handler yet, data frames are dropped
+    }
 }
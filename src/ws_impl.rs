@@ -47,3 +47,156 @@ pub(crate) fn upgrade(req: &mut RequestReader, resp: &mut ResponseWriter) -> Res
         .header("connection", "Upgrade");
     Ok(true)
 }
+
+pub(crate) const CLOSE_NORMAL: u16 = 1000;
+pub(crate) const CLOSE_GOING_AWAY: u16 = 1001;
+pub(crate) const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub(crate) const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug)]
+pub(crate) enum WsError {
+    Io(std::io::Error),
+    Protocol,
+    TooBig,
+}
+
+impl From<std::io::Error> for WsError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl WsError {
+    pub(crate) fn close_code(&self) -> Option<u16> {
+        match self {
+            Self::Io(_) => None,
+            Self::Protocol => Some(CLOSE_PROTOCOL_ERROR),
+            Self::TooBig => Some(CLOSE_TOO_BIG),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct WsFrame {
+    pub(crate) fin: bool,
+    pub(crate) opcode: u8,
+    pub(crate) payload: Vec<u8>,
+}
+
+impl WsFrame {
+    #[inline]
+    pub(crate) fn is(&self, opcode: WsOpCode) -> bool {
+        self.opcode == opcode as u8
+    }
+
+    #[inline]
+    pub(crate) fn is_close(&self) -> bool {
+        self.opcode == ExtOpCode::Close as u8
+    }
+}
+
+/// read one client frame, RFC 6455 section 5.2. client frames must be masked.
+pub(crate) async fn read_frame<R: tokio::io::AsyncBufReadExt + Unpin>(
+    reader: &mut R,
+    max_payload: usize,
+) -> Result<WsFrame, WsError> {
+    use tokio::io::AsyncReadExt;
+
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await?;
+    let fin = head[0] & 0x80 != 0;
+    // no extension is negotiated, so the rsv bits must be unset
+    if head[0] & 0x70 != 0 || head[1] & 0x80 == 0 {
+        return Err(WsError::Protocol);
+    }
+    let opcode = head[0] & 0x0f;
+    match opcode {
+        0x0..=0x2 => {}
+        0x8..=0xA => {
+            if !fin || (head[1] & 0x7f) > 125 {
+                return Err(WsError::Protocol);
+            }
+        }
+        _ => return Err(WsError::Protocol),
+    }
+
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut v = [0u8; 2];
+            reader.read_exact(&mut v).await?;
+            u16::from_be_bytes(v) as u64
+        }
+        127 => {
+            let mut v = [0u8; 8];
+            reader.read_exact(&mut v).await?;
+            let v = u64::from_be_bytes(v);
+            if v >> 63 != 0 {
+                return Err(WsError::Protocol);
+            }
+            v
+        }
+        v => v as u64,
+    };
+    if len > max_payload as u64 {
+        return Err(WsError::TooBig);
+    }
+
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok(WsFrame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+/// append an unmasked server frame.
+pub(crate) fn write_frame(dest: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    dest.push(0x80 | opcode);
+    let len = payload.len();
+    if len < 126 {
+        dest.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        dest.push(126);
+        dest.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        dest.push(127);
+        dest.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    dest.extend_from_slice(payload);
+}
+
+/// a close frame, `reason` is cut to the 123 bytes a control frame leaves it, at a char boundary
+/// so it stays utf-8.
+pub(crate) fn write_close(dest: &mut Vec<u8>, code: u16, reason: &str) {
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    write_frame(dest, ExtOpCode::Close as u8, &payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_close() {
+        let mut dest = vec![];
+        write_close(&mut dest, 1000, &"é".repeat(100));
+        // 2 + 122 bytes, the 62nd `é` does not fit
+        assert_eq!(dest[1], 124);
+        assert!(std::str::from_utf8(&dest[4..]).is_ok());
+
+        let mut dest = vec![];
+        write_close(&mut dest, 1000, "bye");
+        assert_eq!(dest, [0x88, 5, 0x03, 0xe8, b'b', b'y', b'e']);
+    }
+}